@@ -0,0 +1,265 @@
+use rodio::buffer::SamplesBuffer;
+use rodio::{Decoder, OutputStreamHandle, PlayError, Sample, Sink, Source};
+use std::f32::consts::TAU;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::player::Playback;
+use crate::scheduler::Change;
+use crate::utils::MAX_OUTPUT_LATENCY;
+
+/// How many clicks a calibration plays, one every `CLICK_INTERVAL`.
+pub const CLICK_COUNT: usize = 4;
+const CLICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A click is a short burst of a high tone, which is easy to pick out of a recording.
+const CLICK_LENGTH: Duration = Duration::from_millis(10);
+const CLICK_FREQUENCY: f32 = 2000.0;
+const CLICK_SAMPLE_RATE: u32 = 48_000;
+
+/// How far the gaps between the recorded clicks may deviate from `CLICK_INTERVAL`.
+const CLICK_TOLERANCE: Duration = Duration::from_millis(20);
+
+/// Recordings whose peak stays below this level hold no clicks.
+const SILENCE: f32 = 0.01;
+
+/// Builds the calibration click track: `CLICK_COUNT` clicks, each at the start of a
+/// `CLICK_INTERVAL`.
+pub fn click_track() -> SamplesBuffer<f32> {
+    let samples_per =
+        |duration: Duration| (duration.as_secs_f64() * CLICK_SAMPLE_RATE as f64).round() as usize;
+    let interval = samples_per(CLICK_INTERVAL);
+    let click = samples_per(CLICK_LENGTH);
+
+    let mut samples = vec![0.0; interval * CLICK_COUNT];
+    for start in (0..CLICK_COUNT).map(|index| index * interval) {
+        for (i, sample) in samples[start..start + click].iter_mut().enumerate() {
+            let phase = TAU * CLICK_FREQUENCY * i as f32 / CLICK_SAMPLE_RATE as f32;
+            *sample = 0.8 * phase.sin();
+        }
+    }
+    SamplesBuffer::new(1, CLICK_SAMPLE_RATE, samples)
+}
+
+/// Schedules the click track for `start_time` on the leader's clock, on a sink of its own, and
+/// passes the local time at which it started to `on_start`.
+///
+/// Unlike actions, the clicks are not played ahead by the output latency of this device, since
+/// that latency is what a recording of them measures. The scheduler runs every job that far ahead,
+/// so the clicks are scheduled that much later.
+pub fn schedule_clicks(
+    stream_handle: &OutputStreamHandle,
+    start_time: u64,
+    playback: &Playback,
+    on_start: impl FnOnce(SystemTime) + Send + 'static,
+) -> Result<(), PlayError> {
+    let sink = Sink::try_new(stream_handle)?;
+    sink.pause();
+    sink.append(click_track());
+
+    let output_latency = *playback.output_latency.lock().unwrap();
+    let target_time = start_time + output_latency.as_millis() as u64;
+    playback
+        .scheduler
+        .schedule(Change::Clicks, target_time, move |_| {
+            let played_at = SystemTime::now();
+            sink.play();
+            // The clicks play on once the sink is dropped
+            sink.detach();
+            on_start(played_at);
+        });
+    Ok(())
+}
+
+/// A mono recording of the calibration clicks.
+pub struct Capture {
+    samples: Vec<f32>,
+    sample_rate: u32,
+    /// Local time at which the first sample was recorded.
+    started_at: SystemTime,
+}
+
+impl Capture {
+    /// Reads a WAV recording, e.g. of a microphone in front of the speakers, or a loopback capture
+    /// of the audio output.
+    ///
+    /// Recorders keep writing to the file until the recording stops, so its last sample is taken to
+    /// be recorded at the file's modification time, and its first sample the length of the
+    /// recording before that. Channels are mixed down to mono.
+    pub fn from_wav(path: &Path) -> io::Result<Capture> {
+        let file = File::open(path)?;
+        let ended_at = file.metadata()?.modified()?;
+        let decoder = Decoder::new_wav(BufReader::new(file))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let channels = decoder.channels().max(1) as usize;
+        let sample_rate = decoder.sample_rate();
+        let interleaved: Vec<i16> = decoder.collect();
+        let samples: Vec<f32> = interleaved
+            .chunks(channels)
+            .map(|frame| frame.iter().map(|sample| sample.to_f32()).sum::<f32>() / channels as f32)
+            .collect();
+
+        let length = Duration::from_secs_f64(samples.len() as f64 / sample_rate as f64);
+        let started_at = ended_at.checked_sub(length).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "recording starts before 1970")
+        })?;
+        Ok(Capture {
+            samples,
+            sample_rate,
+            started_at,
+        })
+    }
+
+    /// Works out the output latency of this device from the recorded clicks, given the local time
+    /// at which the click track was played.
+    ///
+    /// The clicks are told apart from other noise by their regular gaps, and the latency is the
+    /// average delay of the clicks.
+    pub fn measure_latency(&self, played_at: SystemTime) -> Result<Duration, String> {
+        let onsets = find_clicks(&self.samples, self.sample_rate);
+        let clicks = onsets
+            .windows(CLICK_COUNT)
+            .find(|clicks| {
+                clicks.windows(2).all(|pair| {
+                    let gap = pair[1] - pair[0];
+                    gap.abs_diff(CLICK_INTERVAL) <= CLICK_TOLERANCE
+                })
+            })
+            .ok_or_else(|| {
+                format!(
+                    "Could not find the {} calibration clicks in the recording",
+                    CLICK_COUNT
+                )
+            })?;
+
+        let seconds_since =
+            |earlier: SystemTime, later: SystemTime| match later.duration_since(earlier) {
+                Ok(duration) => duration.as_secs_f64(),
+                Err(e) => -e.duration().as_secs_f64(),
+            };
+        let delay = clicks
+            .iter()
+            .enumerate()
+            .map(|(index, onset)| {
+                let heard_at = self.started_at + *onset;
+                seconds_since(played_at + CLICK_INTERVAL * index as u32, heard_at)
+            })
+            .sum::<f64>()
+            / CLICK_COUNT as f64;
+
+        if delay < -CLICK_TOLERANCE.as_secs_f64() {
+            return Err(
+                "The clicks were recorded before they were played; is the recording's time right?"
+                    .to_string(),
+            );
+        }
+        let latency = Duration::from_secs_f64(delay.max(0.0));
+        if latency > MAX_OUTPUT_LATENCY {
+            return Err(format!(
+                "Measured an output latency of {} ms, more than the {} ms that can be compensated",
+                latency.as_millis(),
+                MAX_OUTPUT_LATENCY.as_millis()
+            ));
+        }
+        Ok(latency)
+    }
+}
+
+/// Returns where clicks start in a recording, relative to its start.
+///
+/// A click starts where the signal first reaches half of the recording's peak. The half interval
+/// after it is skipped, so echoes and the ringing of the speaker do not count as further clicks.
+fn find_clicks(samples: &[f32], sample_rate: u32) -> Vec<Duration> {
+    let peak = samples
+        .iter()
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    if peak < SILENCE {
+        return Vec::new();
+    }
+
+    let dead_time = (CLICK_INTERVAL.as_secs_f64() / 2.0 * sample_rate as f64) as usize;
+    let mut clicks = Vec::new();
+    let mut next = 0;
+    for (i, sample) in samples.iter().enumerate() {
+        if i >= next && sample.abs() >= peak / 2.0 {
+            clicks.push(Duration::from_secs_f64(i as f64 / sample_rate as f64));
+            next = i + dead_time;
+        }
+    }
+    clicks
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Builds a mono 16-bit PCM WAV file holding `samples`.
+    fn wav_bytes(sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // mono
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_measures_latency_from_injected_capture() {
+        let dir =
+            std::env::temp_dir().join(format!("syncstream-calibration-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.wav");
+
+        // The recorder starts a second before the clicks are played, which are heard 120 ms late,
+        // and picks up a pop before them
+        let played_at = SystemTime::now() - Duration::from_secs(60);
+        let rate = CLICK_SAMPLE_RATE as usize;
+        let mut recording = vec![0i16; rate * 7];
+        recording[rate / 10] = i16::MAX / 2;
+        let heard_from = rate * 1120 / 1000;
+        for (i, sample) in click_track().enumerate() {
+            recording[heard_from + i] = (sample * 0.5 * i16::MAX as f32) as i16;
+        }
+        fs::write(&path, wav_bytes(CLICK_SAMPLE_RATE, &recording)).unwrap();
+        let ended_at = played_at - Duration::from_secs(1) + Duration::from_secs(7);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(ended_at)
+            .unwrap();
+
+        let capture = Capture::from_wav(&path).unwrap();
+        let latency = capture.measure_latency(played_at).unwrap();
+        assert!(
+            latency.abs_diff(Duration::from_millis(120)) < Duration::from_millis(1),
+            "latency {:?}",
+            latency
+        );
+
+        // A recording that missed some of the clicks is rejected
+        recording.truncate(heard_from + rate * 2);
+        fs::write(&path, wav_bytes(CLICK_SAMPLE_RATE, &recording)).unwrap();
+        let capture = Capture::from_wav(&path).unwrap();
+        assert!(capture.measure_latency(played_at).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
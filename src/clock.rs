@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::{self, Message};
+
+/// Number of samples kept by `ClockSync`; older samples are discarded.
+const SAMPLE_WINDOW: usize = 16;
+
+/// Number of requests sent right after joining, so an estimate is available quickly.
+const INITIAL_BURST: usize = 8;
+
+/// Spacing between the requests of the initial burst.
+const BURST_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Interval between requests once the initial burst is done.
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Returns the local system time in milliseconds since the UNIX epoch.
+pub fn system_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_millis() as u64
+}
+
+/// Returns the local system time in microseconds since the UNIX epoch, for timing that has to be
+/// finer than a millisecond.
+pub fn system_time_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Failed to get current time")
+        .as_micros() as u64
+}
+
+/// One request/response exchange with the leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    /// Estimated leader clock minus local clock, in milliseconds.
+    pub offset_ms: i64,
+    /// Time spent on the network, excluding the leader's processing time.
+    pub round_trip_ms: u64,
+}
+
+impl ClockSample {
+    /// Computes a sample from the four timestamps of an NTP-style exchange.
+    ///
+    /// `origin` and `destination` are taken from the member's clock when the request was sent and
+    /// the response received; `receive` and `transmit` are taken from the leader's clock when the
+    /// request arrived and the response left.
+    pub fn from_exchange(origin: u64, receive: u64, transmit: u64, destination: u64) -> Self {
+        let (origin, receive) = (origin as i64, receive as i64);
+        let (transmit, destination) = (transmit as i64, destination as i64);
+
+        ClockSample {
+            offset_ms: ((receive - origin) + (transmit - destination)) / 2,
+            round_trip_ms: ((destination - origin) - (transmit - receive)).max(0) as u64,
+        }
+    }
+}
+
+/// Estimates the offset between the local clock and the leader's clock.
+///
+/// Samples are kept in a sliding window. Samples with a long round trip are the ones most likely
+/// to be skewed by queuing delays, so only the faster half of the window is considered and the
+/// median of their offsets is used as the estimate.
+///
+/// Without any samples the offset is zero, which is how the leader uses it for its own clock.
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockSync {
+    /// Adds a sample, discarding the oldest one if the window is full.
+    pub fn add_sample(&mut self, sample: ClockSample) {
+        if self.samples.len() == SAMPLE_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Returns the number of samples currently in the window.
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Returns whether enough samples were collected for a reliable estimate.
+    pub fn is_ready(&self) -> bool {
+        self.samples.len() >= INITIAL_BURST
+    }
+
+    /// Returns the estimated leader clock minus local clock, in milliseconds.
+    pub fn offset_ms(&self) -> i64 {
+        if self.samples.is_empty() {
+            return 0;
+        }
+
+        let mut by_round_trip: Vec<&ClockSample> = self.samples.iter().collect();
+        by_round_trip.sort_by_key(|sample| sample.round_trip_ms);
+        let fastest = &by_round_trip[..by_round_trip.len().div_ceil(2)];
+
+        let mut offsets: Vec<i64> = fastest.iter().map(|sample| sample.offset_ms).collect();
+        offsets.sort_unstable();
+        let middle = offsets.len() / 2;
+        if offsets.len().is_multiple_of(2) {
+            (offsets[middle - 1] + offsets[middle]) / 2
+        } else {
+            offsets[middle]
+        }
+    }
+
+    /// Returns the current time on the leader's clock, in milliseconds since the UNIX epoch.
+    pub fn leader_time_ms(&self) -> u64 {
+        system_time_ms().saturating_add_signed(self.offset_ms())
+    }
+}
+
+/// Answers a time request, which comes from a member synchronizing its clock, or from the leader
+/// measuring the round trip to a member.
+///
+/// `receive_ms` should be taken as soon as the request was received, so the processing time is
+/// excluded from the round trip.
+pub fn respond_to_time_request(
+    socket: &UdpSocket,
+    origin: u64,
+    receive_ms: u64,
+    addr: SocketAddr,
+) -> std::io::Result<()> {
+    let response = Message::TimeResponse {
+        origin,
+        receive: receive_ms,
+        transmit: system_time_ms(),
+    };
+    protocol::send_message(socket, &response, addr)
+}
+
+/// Records the leader's answer to a time request as a new sample.
+pub fn handle_time_response(
+    clock: &Arc<Mutex<ClockSync>>,
+    origin: u64,
+    receive: u64,
+    transmit: u64,
+) {
+    let sample = ClockSample::from_exchange(origin, receive, transmit, system_time_ms());
+    let mut clock = clock.lock().unwrap();
+    let first_estimate = clock.sample_count() + 1 == INITIAL_BURST;
+    clock.add_sample(sample);
+    if first_estimate {
+        println!(
+            "Clock offset to leader: {} ms (round trip {} ms)",
+            clock.offset_ms(),
+            sample.round_trip_ms
+        );
+    }
+}
+
+/// Starts a background thread that keeps sending time requests to the leader.
+///
+/// The responses are received by whichever loop currently reads the member's socket, which passes
+/// them on to `handle_time_response`.
+pub fn start_sync_thread(socket: UdpSocket, leader_addr: SocketAddr) {
+    thread::spawn(move || {
+        let mut sent = 0;
+        loop {
+            let request = Message::TimeRequest {
+                origin: system_time_ms(),
+            };
+            if let Err(e) = protocol::send_message(&socket, &request, leader_addr) {
+                eprintln!("Failed to send time request: {}", e);
+            }
+
+            sent += 1;
+            thread::sleep(if sent < INITIAL_BURST {
+                BURST_INTERVAL
+            } else {
+                SYNC_INTERVAL
+            });
+        }
+    });
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a time server on loopback whose clock is `skew_ms` ahead of the local clock.
+    fn spawn_time_server(skew_ms: i64, requests: usize) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
+            for _ in 0..requests {
+                let (size, src) = socket.recv_from(&mut buf).unwrap();
+                let receive = system_time_ms().saturating_add_signed(skew_ms);
+                if let Ok(packet) = protocol::decode(&buf[..size]) {
+                    if let Message::TimeRequest { origin } = packet.message {
+                        let response = Message::TimeResponse {
+                            origin,
+                            receive,
+                            transmit: system_time_ms().saturating_add_signed(skew_ms),
+                        };
+                        protocol::send_message(&socket, &response, src).unwrap();
+                    }
+                }
+            }
+        });
+        addr
+    }
+
+    /// Performs `samples` exchanges with the server at `server` and returns the estimator.
+    fn measure(server: SocketAddr, samples: usize) -> ClockSync {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let clock = Arc::new(Mutex::new(ClockSync::default()));
+        let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
+
+        for _ in 0..samples {
+            let request = Message::TimeRequest {
+                origin: system_time_ms(),
+            };
+            protocol::send_message(&socket, &request, server).unwrap();
+            let (size, _) = socket.recv_from(&mut buf).unwrap();
+            if let Message::TimeResponse {
+                origin,
+                receive,
+                transmit,
+            } = protocol::decode(&buf[..size]).unwrap().message
+            {
+                handle_time_response(&clock, origin, receive, transmit);
+            }
+        }
+
+        Arc::try_unwrap(clock).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_sample_from_symmetric_exchange() {
+        // 10 ms each way, leader 500 ms ahead, 3 ms processing on the leader
+        let sample = ClockSample::from_exchange(1_000, 1_510, 1_513, 1_023);
+
+        assert_eq!(sample.offset_ms, 500);
+        assert_eq!(sample.round_trip_ms, 20);
+    }
+
+    #[test]
+    fn test_sample_with_leader_behind() {
+        let sample = ClockSample::from_exchange(10_000, 7_002, 7_002, 10_004);
+
+        assert_eq!(sample.offset_ms, -3_000);
+        assert_eq!(sample.round_trip_ms, 4);
+    }
+
+    #[test]
+    fn test_empty_estimator_uses_local_clock() {
+        let clock = ClockSync::default();
+
+        assert_eq!(clock.offset_ms(), 0);
+        assert!(clock.leader_time_ms().abs_diff(system_time_ms()) <= 1);
+    }
+
+    #[test]
+    fn test_slow_samples_are_filtered_out() {
+        let mut clock = ClockSync::default();
+        for offset in [100, 101, 99, 100] {
+            clock.add_sample(ClockSample {
+                offset_ms: offset,
+                round_trip_ms: 2,
+            });
+        }
+        for offset in [400, 450, 380, 500] {
+            clock.add_sample(ClockSample {
+                offset_ms: offset,
+                round_trip_ms: 300,
+            });
+        }
+
+        assert_eq!(clock.offset_ms(), 100);
+    }
+
+    #[test]
+    fn test_window_discards_old_samples() {
+        let mut clock = ClockSync::default();
+        for _ in 0..SAMPLE_WINDOW {
+            clock.add_sample(ClockSample {
+                offset_ms: -50,
+                round_trip_ms: 1,
+            });
+        }
+        for _ in 0..SAMPLE_WINDOW {
+            clock.add_sample(ClockSample {
+                offset_ms: 75,
+                round_trip_ms: 1,
+            });
+        }
+
+        assert_eq!(clock.sample_count(), SAMPLE_WINDOW);
+        assert_eq!(clock.offset_ms(), 75);
+    }
+
+    #[test]
+    fn test_loopback_exchange_with_skewed_leader() {
+        let server = spawn_time_server(5_000, 8);
+        let clock = measure(server, 8);
+
+        assert!(
+            (clock.offset_ms() - 5_000).abs() <= 5,
+            "Offset was {}",
+            clock.offset_ms()
+        );
+    }
+
+    #[test]
+    fn test_loopback_exchange_with_leader_responder() {
+        let leader = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let leader_addr = leader.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
+            loop {
+                let (size, src) = leader.recv_from(&mut buf).unwrap();
+                let receive = system_time_ms();
+                if let Ok(packet) = protocol::decode(&buf[..size]) {
+                    if let Message::TimeRequest { origin } = packet.message {
+                        respond_to_time_request(&leader, origin, receive, src).unwrap();
+                    }
+                }
+            }
+        });
+
+        let clock = measure(leader_addr, 4);
+
+        assert!(
+            clock.offset_ms().abs() <= 5,
+            "Offset was {}",
+            clock.offset_ms()
+        );
+    }
+}
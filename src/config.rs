@@ -0,0 +1,273 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::loudness::GainMode;
+
+/// Name of the optional configuration file, looked up in the working directory.
+pub const CONFIG_FILE: &str = "syncstream.conf";
+
+/// Tunable settings of a SyncStream session.
+///
+/// Every field has a default, so the configuration file only needs to list the settings that
+/// should be changed. The file consists of `key = value` lines; empty lines and lines starting
+/// with `#` are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// How often the leader broadcasts its playback position (`heartbeat_interval_ms`).
+    pub heartbeat_interval: Duration,
+    /// Drift below this is ignored (`drift_resample_threshold_ms`).
+    pub drift_resample_threshold: Duration,
+    /// Drift from this on is corrected with a seek instead of resampling (`drift_seek_threshold_ms`).
+    pub drift_seek_threshold: Duration,
+    /// Largest relative speed change used to resample away small drift (`drift_max_speed_adjustment`).
+    pub drift_max_speed_adjustment: f32,
+    /// How often a member tells the leader it is still there (`keepalive_interval_ms`).
+    pub keepalive_interval: Duration,
+    /// Silence after which the leader evicts a member, and a member warns about the leader (`member_timeout_ms`).
+    pub member_timeout: Duration,
+    /// How long to wait for an ACK before a command is sent again (`command_retry_interval_ms`).
+    pub command_retry_interval: Duration,
+    /// Time the leader leaves between broadcasting an action and carrying it out, on top of the
+    /// slowest member's round trip (`start_lead_margin_ms`).
+    pub start_lead_margin: Duration,
+    /// Shortest and longest time between broadcasting an action and carrying it out
+    /// (`start_lead_min_ms` and `start_lead_max_ms`).
+    pub start_lead_min: Duration,
+    pub start_lead_max: Duration,
+    /// How long the leader crossfades consecutive tracks, 0 for gapless transitions (`crossfade_ms`).
+    pub crossfade: Duration,
+    /// How long it takes this device's audio output to turn samples into sound, e.g. over
+    /// Bluetooth. Every action is carried out this much earlier, so it is heard in sync with the
+    /// other peers (`output_latency_ms`).
+    pub output_latency: Duration,
+    /// Which ReplayGain the leader normalizes the tracks with: `off`, `track` or `album`
+    /// (`replay_gain`).
+    pub gain_mode: GainMode,
+    /// Directory the leader scans for audio files, including its subdirectories (`media_dir`).
+    pub media_dir: PathBuf,
+    /// Scan every media file again instead of trusting the library index (`--rebuild-index` flag).
+    pub rebuild_index: bool,
+    /// Playlist file the leader plays instead of asking for a track selection (`playlist`, or the
+    /// `--playlist` flag).
+    pub playlist: Option<PathBuf>,
+    /// File this peer logs late actions and their statistics to, if any (`session_log`).
+    pub session_log: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            heartbeat_interval: Duration::from_secs(2),
+            drift_resample_threshold: Duration::from_millis(15),
+            drift_seek_threshold: Duration::from_millis(200),
+            drift_max_speed_adjustment: 0.01,
+            keepalive_interval: Duration::from_secs(1),
+            member_timeout: Duration::from_secs(5),
+            command_retry_interval: Duration::from_millis(100),
+            start_lead_margin: Duration::from_millis(100),
+            start_lead_min: Duration::from_millis(150),
+            start_lead_max: Duration::from_secs(2),
+            crossfade: Duration::ZERO,
+            output_latency: Duration::ZERO,
+            gain_mode: GainMode::default(),
+            media_dir: PathBuf::from("media"),
+            rebuild_index: false,
+            playlist: None,
+            session_log: Some(PathBuf::from("syncstream-session.log")),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `path`, falling back to the defaults if the file is missing.
+    pub fn load(path: &Path) -> Config {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                println!("Loaded configuration from {}", path.display());
+                Config::parse(&contents)
+            }
+            Err(_) => Config::default(),
+        }
+    }
+
+    /// Parses the contents of a configuration file.
+    ///
+    /// Unknown keys and invalid values are reported and skipped, so a typo never prevents a
+    /// session from starting.
+    pub fn parse(contents: &str) -> Config {
+        let mut config = Config::default();
+
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                eprintln!("Config line {}: expected `key = value`", line_number + 1);
+                continue;
+            };
+
+            if let Err(e) = config.set(key.trim(), value.trim()) {
+                eprintln!("Config line {}: {}", line_number + 1, e);
+            }
+        }
+
+        config
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "heartbeat_interval_ms" => self.heartbeat_interval = parse_millis(value)?,
+            "drift_resample_threshold_ms" => self.drift_resample_threshold = parse_millis(value)?,
+            "drift_seek_threshold_ms" => self.drift_seek_threshold = parse_millis(value)?,
+            "drift_max_speed_adjustment" => {
+                self.drift_max_speed_adjustment = value
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|adjustment| (0.0..0.5).contains(adjustment))
+                    .ok_or_else(|| format!("invalid speed adjustment `{}`", value))?
+            }
+            "keepalive_interval_ms" => self.keepalive_interval = parse_millis(value)?,
+            "member_timeout_ms" => self.member_timeout = parse_millis(value)?,
+            "command_retry_interval_ms" => self.command_retry_interval = parse_millis(value)?,
+            "start_lead_margin_ms" => self.start_lead_margin = parse_millis(value)?,
+            "start_lead_min_ms" => self.start_lead_min = parse_millis(value)?,
+            "start_lead_max_ms" => self.start_lead_max = parse_millis(value)?,
+            "crossfade_ms" => self.crossfade = parse_millis(value)?,
+            "output_latency_ms" => self.output_latency = parse_millis(value)?,
+            "replay_gain" => {
+                self.gain_mode = GainMode::from_name(value)
+                    .ok_or_else(|| format!("invalid ReplayGain mode `{}`", value))?
+            }
+            "media_dir" if !value.is_empty() => self.media_dir = PathBuf::from(value),
+            "media_dir" => return Err("empty media directory".to_string()),
+            "playlist" => {
+                self.playlist = Some(value)
+                    .filter(|path| !path.is_empty())
+                    .map(PathBuf::from)
+            }
+            "session_log" => {
+                self.session_log = Some(value)
+                    .filter(|path| !path.is_empty())
+                    .map(PathBuf::from)
+            }
+            _ => return Err(format!("unknown key `{}`", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Sets `key` to `value` in the configuration file at `path`, keeping the rest of the file as it is.
+///
+/// The first line setting `key` is replaced, or the setting is appended if there is none. A missing
+/// file is created.
+pub fn save_setting(path: &Path, key: &str, value: &str) -> io::Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let setting = format!("{} = {}", key, value);
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    let existing = lines.iter_mut().find(|line| {
+        line.split_once('=').is_some_and(|(line_key, _)| {
+            line_key.trim() == key && !line.trim_start().starts_with('#')
+        })
+    });
+    match existing {
+        Some(line) => *line = setting,
+        None => lines.push(setting),
+    }
+
+    fs::write(path, lines.join("\n") + "\n")
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<u64>()
+        .map(Duration::from_millis)
+        .map_err(|_| format!("invalid number of milliseconds `{}`", value))
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_overrides_defaults() {
+        let config = Config::parse(
+            "# Drift correction\n\
+             heartbeat_interval_ms = 500\n\
+             \n\
+             drift_seek_threshold_ms=100\n\
+             drift_max_speed_adjustment = 0.02\n\
+             media_dir = /srv/music\n\
+             playlist = party.m3u8\n\
+             replay_gain = album\n\
+             start_lead_max_ms = 800\n\
+             session_log =\n",
+        );
+
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(500));
+        assert_eq!(config.drift_seek_threshold, Duration::from_millis(100));
+        assert_eq!(config.drift_max_speed_adjustment, 0.02);
+        assert_eq!(config.media_dir, PathBuf::from("/srv/music"));
+        assert_eq!(config.playlist, Some(PathBuf::from("party.m3u8")));
+        assert_eq!(config.gain_mode, GainMode::Album);
+        assert_eq!(config.start_lead_max, Duration::from_millis(800));
+        assert_eq!(config.session_log, None);
+        assert_eq!(
+            config.drift_resample_threshold,
+            Config::default().drift_resample_threshold
+        );
+    }
+
+    #[test]
+    fn test_parse_skips_invalid_lines() {
+        let config = Config::parse(
+            "heartbeat_interval_ms = soon\n\
+             no_such_key = 1\n\
+             just some text\n\
+             drift_max_speed_adjustment = 3\n\
+             media_dir =\n",
+        );
+
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_save_setting_keeps_other_lines() {
+        let path = std::env::temp_dir().join(format!("syncstream-conf-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# output_latency_ms = 1\nheartbeat_interval_ms = 500\noutput_latency_ms = 80\n",
+        )
+        .unwrap();
+
+        save_setting(&path, "output_latency_ms", "120").unwrap();
+        save_setting(&path, "crossfade_ms", "3000").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            contents,
+            "# output_latency_ms = 1\nheartbeat_interval_ms = 500\noutput_latency_ms = 120\n\
+             crossfade_ms = 3000\n"
+        );
+        let config = Config::parse(&contents);
+        assert_eq!(config.output_latency, Duration::from_millis(120));
+        assert_eq!(config.crossfade, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_load_missing_file_uses_defaults() {
+        let config = Config::load(Path::new("/nonexistent/syncstream.conf"));
+
+        assert_eq!(config, Config::default());
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::player::Playback;
+
+/// What a member does about the difference between its own position and the leader's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    /// The drift is below the resample threshold; play at normal speed.
+    None,
+    /// Play slightly faster or slower until the drift is gone.
+    Resample(f32),
+    /// The drift is too large to resample away; jump to the given position.
+    Seek(Duration),
+}
+
+/// Decides how to correct a drift of `drift_ms`, where a positive drift means the member is behind.
+///
+/// Small drifts are resampled away over one heartbeat interval, with the speed change capped by
+/// `drift_max_speed_adjustment`. Drifts from `drift_seek_threshold` on are corrected by seeking to
+/// `expected_position`.
+pub fn plan_correction(drift_ms: i64, expected_position: Duration, config: &Config) -> Correction {
+    let drift = Duration::from_millis(drift_ms.unsigned_abs());
+
+    if drift >= config.drift_seek_threshold {
+        Correction::Seek(expected_position)
+    } else if drift >= config.drift_resample_threshold {
+        let max = config.drift_max_speed_adjustment;
+        let adjustment = drift_ms as f32 / config.heartbeat_interval.as_millis().max(1) as f32;
+        Correction::Resample(1.0 + adjustment.clamp(-max, max))
+    } else {
+        Correction::None
+    }
+}
+
+/// Compares a position heartbeat from the leader with the local playhead and corrects the drift.
+///
+/// The heartbeat tells what the leader's listeners heard of `track_index` at `leader_time`. Heartbeats for
+/// another track, or received while paused, are ignored since the next command resynchronizes
+/// those anyway.
+pub fn correct_drift(
+    track_index: usize,
+    position_ms: u64,
+    leader_time: u64,
+    playback: &Playback,
+    config: &Config,
+) {
+    if *playback.current_track_index.lock().unwrap() != track_index {
+        return;
+    }
+
+    let elapsed_ms = playback
+        .clock
+        .lock()
+        .unwrap()
+        .leader_time_ms()
+        .saturating_sub(leader_time);
+    // The heartbeat tells what the leader's listeners heard, so the sink has to be ahead of that
+    // by this member's own output latency
+    let expected_position =
+        Duration::from_millis(position_ms + elapsed_ms) + *playback.output_latency.lock().unwrap();
+
+    let sink = playback.sink.lock().unwrap();
+    if sink.is_paused() {
+        return;
+    }
+
+    let drift_ms =
+        expected_position.as_millis() as i64 - playback.playhead.get().as_millis() as i64;
+    match plan_correction(drift_ms, expected_position, config) {
+        Correction::None => {
+            if sink.speed() != 1.0 {
+                println!("\nDrift {} ms: back to normal speed", drift_ms);
+                sink.set_speed(1.0);
+            }
+        }
+        Correction::Resample(speed) => {
+            println!("\nDrift {} ms: resampling at {:.3}x", drift_ms, speed);
+            sink.set_speed(speed);
+        }
+        Correction::Seek(position) => {
+            println!(
+                "\nDrift {} ms: seeking to {:.3} s",
+                drift_ms,
+                position.as_secs_f64()
+            );
+            sink.set_speed(1.0);
+            if let Err(e) = sink.try_seek(position) {
+                eprintln!("Failed to correct drift: {}", e);
+            }
+        }
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_speed(correction: Correction, expected: f32) {
+        match correction {
+            Correction::Resample(speed) => assert!(
+                (speed - expected).abs() < 1e-6,
+                "Expected speed {}, got {}",
+                expected,
+                speed
+            ),
+            other => panic!("Expected resampling, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_small_drift_is_ignored() {
+        let config = Config::default();
+        let correction = plan_correction(5, Duration::from_secs(10), &config);
+
+        assert_eq!(correction, Correction::None);
+    }
+
+    #[test]
+    fn test_member_behind_speeds_up() {
+        let config = Config::default();
+        let correction = plan_correction(20, Duration::from_secs(10), &config);
+
+        // 20 ms over a 2 s heartbeat interval
+        assert_speed(correction, 1.01);
+    }
+
+    #[test]
+    fn test_member_ahead_slows_down() {
+        let config = Config::default();
+        let correction = plan_correction(-16, Duration::from_secs(10), &config);
+
+        assert_speed(correction, 0.992);
+    }
+
+    #[test]
+    fn test_speed_adjustment_is_capped() {
+        let config = Config::default();
+        let correction = plan_correction(-150, Duration::from_secs(10), &config);
+
+        assert_speed(correction, 1.0 - config.drift_max_speed_adjustment);
+    }
+
+    #[test]
+    fn test_large_drift_seeks() {
+        let config = Config::default();
+        let correction = plan_correction(-450, Duration::from_millis(12_345), &config);
+
+        assert_eq!(correction, Correction::Seek(Duration::from_millis(12_345)));
+    }
+
+    #[test]
+    fn test_thresholds_come_from_config() {
+        let config = Config::parse("drift_seek_threshold_ms = 10\n");
+        let correction = plan_correction(12, Duration::from_secs(1), &config);
+
+        assert_eq!(correction, Correction::Seek(Duration::from_secs(1)));
+    }
+}
@@ -1,261 +1,469 @@
 use asky::Text;
 use rodio::{OutputStream, Sink};
-use std::collections::HashSet;
-use std::io;
-use std::net::UdpSocket;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-
-use crate::player::{add_tracks_to_sink, display_progress, load_audio_files};
-use crate::track::Track;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::calibration;
+use crate::clock::{self, ClockSample, ClockSync};
+use crate::config::Config;
+use crate::members::Members;
+use crate::order::{PlayOrder, Sequence};
+use crate::player::{
+    add_tracks_to_sink, display_progress, load_audio_files, AudioSource, Playback, Volume,
+};
+use crate::playlist;
+use crate::protocol::{self, Command, Edit, Message, TrackInfo};
+use crate::reliable::{self, Deduplicator, ReliableSender};
+use crate::scheduler::Scheduler;
+use crate::session_log::SessionLog;
+use crate::stream;
+use crate::track::{Availability, Track};
+use crate::transition::Playhead;
 use crate::utils;
 
-use asky::MultiSelect;
+use asky::{MultiSelect, SelectOption};
+
+/// State of a running session, needed by the listener to serve commands and late joiners.
+#[derive(Clone)]
+struct Session {
+    playback: Playback,
+    stream_port: u16,
+    /// The whole media library, which playlist edits can add tracks from.
+    library: Arc<Vec<Track>>,
+    /// Held while a command or edit is resolved and scheduled. Commands from the console and from
+    /// members are handled one after another, so each one is resolved against the playlist the
+    /// previous one left behind.
+    commands: Arc<Mutex<()>>,
+    config: Arc<Config>,
+    /// The start time picked last, which later start times are after.
+    last_start_time: Arc<Mutex<u64>>,
+}
 
-pub fn run_leader() -> std::io::Result<()> {
+pub fn run_leader(config: &Config) -> std::io::Result<()> {
     let socket = Arc::new(UdpSocket::bind("0.0.0.0:0")?);
     socket.set_broadcast(true)?;
-    let broadcast_addr = "255.255.255.255:12345";
+    let broadcast_addr: SocketAddr = "255.255.255.255:12345".parse().unwrap();
 
-    let members = Arc::new(Mutex::new(HashSet::new()));
-    let ping_thread_should_terminate = Arc::new(Mutex::new(false));
+    let members = Arc::new(Mutex::new(Members::default()));
+    let session = Arc::new(Mutex::new(None));
+    let sender = ReliableSender::new(socket.clone(), config.command_retry_interval);
+    sender.start_retransmit_thread();
 
     println!("Starting to ping members.");
 
-    let ping_thread = start_ping_thread(
+    // Discovery keeps running for the whole session, so members can join late
+    start_ping_thread(Arc::clone(&socket), broadcast_addr);
+    start_listener_thread(
         Arc::clone(&socket),
-        broadcast_addr.to_string(),
+        Arc::clone(&session),
         Arc::clone(&members),
-        Arc::clone(&ping_thread_should_terminate),
+        Arc::clone(&sender),
     );
+    start_liveness_thread(Arc::clone(&members), config.member_timeout);
+    start_round_trip_thread(Arc::clone(&socket), Arc::clone(&members));
 
     Text::new("Pinging for members. Press ENTER when ready to proceed.").prompt()?;
-    stop_ping_thread(
-        ping_thread,
-        &ping_thread_should_terminate,
-        &socket,
-        &members,
-    )?;
-
-    println!("Final member count: {}", members.lock().unwrap().len());
+
+    println!(
+        "Member count: {} (members can still join during playback)",
+        members.lock().unwrap().len()
+    );
     Text::new("Press ENTER to start the playback!").prompt()?;
-    println!("Commands:\n\t'p' to play/pause\n\t'n' to next\n\t'r' to restart\n\t's' to stop");
+    println!(
+        "Commands:\n\t'p' to play/pause\n\t'n' to next\n\t'b' to go back to the previous track\n\t'track N' to play track N\n\t'r' to restart\n\t'+' or '-' to seek 10 seconds (or e.g. '+30s')\n\t'goto M:SS' to jump to a timestamp\n\t'vol +', 'vol -' or 'vol N' to set everybody's volume\n\t'trim +', 'trim -' or 'trim N' to turn down only this device\n\t'latency N' to compensate an output latency of N ms on this device (e.g. Bluetooth speakers)\n\t'calibrate' to play calibration clicks on every member, which measure their output latency from a recording of them\n\t'shuffle' to turn shuffling on or off\n\t'repeat' to switch between repeating nothing, the playlist or the track (or 'repeat off', 'repeat all', 'repeat one')\n\t'library' to list the media library\n\t'add N' or 'add next N' to queue track N of the library at the end or after the current track\n\t'remove N' to remove track N of the playlist\n\t'move N M' to move track N of the playlist to position M\n\t'members' to show every member's round trip, output latency and which tracks it can play\n\t'save FILE' to save the playlist as .m3u8, .pls or .json\n\t's' to stop"
+    );
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     let sink = Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap()));
     sink.lock().unwrap().pause(); // To prevent playing before synchronization
 
-    let mut tracks = Vec::<Track>::new();
-    load_audio_files("media", &mut tracks);
-
-    let track_names = &tracks
-        .iter()
-        .map(|track| track.name.clone())
-        .collect::<Vec<String>>();
-
-    let selected_tracks = MultiSelect::new(
-        "Please select the tracks (with SPACE) you want to include and then confirm with ENTER!",
-        track_names,
-    )
-    .prompt()?;
-
-    // Filter out the selected tracks from tracks variable
-    let tracks: Vec<Track> = tracks
-        .into_iter()
-        .filter(|track| selected_tracks.contains(&&track.name))
-        .collect();
-
-    add_tracks_to_sink("media", Arc::clone(&sink), &tracks);
-
-    // Send the new track names to all members, keep in mind they can contain unicode characters
-    let track_names = tracks
-        .iter()
-        .map(|track| track.name.clone())
-        .collect::<Vec<String>>()
-        .join(",");
-    let message = format!("tracks:{}", track_names);
-    let member_list = members.lock().unwrap();
-    for member in member_list.iter() {
-        socket.send_to(message.as_bytes(), member)?;
+    let mut library = Vec::<Track>::new();
+    load_audio_files(&config.media_dir, config.rebuild_index, &mut library);
+    if library.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No audio files found in {}", config.media_dir.display()),
+        ));
     }
 
-    let current_track_index = Arc::new(Mutex::new(0));
-    let should_reset = Arc::new(Mutex::new(false));
+    // A playlist file replaces the interactive selection
+    let playlist = config.playlist.as_ref().and_then(|path| {
+        match playlist::load_playlist(path, &config.media_dir) {
+            Ok(entries) => Some(playlist::select_tracks(&entries, &library)),
+            Err(e) => {
+                eprintln!("Failed to load playlist {}: {}", path.display(), e);
+                None
+            }
+        }
+    });
+    let tracks = match playlist {
+        Some(tracks) if !tracks.is_empty() => {
+            println!("Playing {} tracks from the playlist", tracks.len());
+            tracks
+        }
+        Some(_) => {
+            eprintln!("None of the playlist's tracks are in the media library");
+            choose_tracks(&library, &config.media_dir)?
+        }
+        None => choose_tracks(&library, &config.media_dir)?,
+    };
+
+    // The whole library is served, since tracks can be added to the playlist during playback
+    let stream_port = stream::start_stream_server(&library)?;
+    let (track_ends, ended_tracks) = mpsc::channel();
+    let clock = Arc::new(Mutex::new(ClockSync::default()));
+    let output_latency = Arc::new(Mutex::new(config.output_latency));
+    let order = Sequence::new(PlayOrder::default(), tracks.len());
+    let playback = Playback {
+        sink,
+        tracks: Arc::new(Mutex::new(tracks)),
+        current_track_index: Arc::new(Mutex::new(0)),
+        should_reset: Arc::new(Mutex::new(false)),
+        clock: Arc::clone(&clock),
+        audio: AudioSource::Local,
+        volume: Arc::new(Mutex::new(Volume::default())),
+        order: Arc::new(Mutex::new(order)),
+        playhead: Arc::new(Playhead::default()),
+        crossfade: config.crossfade,
+        output_latency: Arc::clone(&output_latency),
+        gain_mode: config.gain_mode,
+        track_ends,
+        queue_generation: Arc::new(Mutex::new(0)),
+        log: Arc::new(Mutex::new(SessionLog::open(
+            config.session_log.as_deref(),
+            "leader",
+        ))),
+        scheduler: Scheduler::start(clock, output_latency),
+    };
+    add_tracks_to_sink(&playback, 0);
+    println!("ReplayGain: {}", playback.gain_mode);
+    let started_session = Session {
+        playback: playback.clone(),
+        stream_port,
+        library: Arc::new(library),
+        commands: Arc::new(Mutex::new(())),
+        config: Arc::new(config.clone()),
+        last_start_time: Arc::new(Mutex::new(0)),
+    };
+
+    // Announce the playlist to all members, who fetch the audio from the stream server
+    let addrs = members.lock().unwrap().addrs();
+    let start_time = next_start_time(&started_session, &members);
+    for member in addrs {
+        send_session(&socket, &started_session, member, start_time)?;
+    }
+    *session.lock().unwrap() = Some(started_session.clone());
 
     display_progress(
-        Arc::clone(&sink),
-        tracks.clone(),
-        Arc::clone(&current_track_index),
-        Arc::clone(&should_reset),
+        Arc::clone(&playback.sink),
+        Arc::clone(&playback.playhead),
+        Arc::clone(&playback.tracks),
+        Arc::clone(&playback.current_track_index),
+        Arc::clone(&playback.should_reset),
+        Arc::clone(&playback.volume),
     );
 
-    start_listener_thread(
+    start_heartbeat_thread(
         Arc::clone(&socket),
-        Arc::clone(&sink),
-        Arc::clone(&current_track_index),
-        Arc::clone(&should_reset),
-        tracks.clone(),
+        playback.clone(),
         Arc::clone(&members),
+        config.heartbeat_interval,
     );
 
-    utils::start_track_position_thread(
-        Arc::clone(&sink),
-        Arc::clone(&current_track_index),
-        Arc::clone(&should_reset),
-        tracks.clone(),
-    );
+    utils::start_track_transition_thread(playback.clone(), ended_tracks);
 
-    user_input_loop(
-        &socket,
-        &sink,
-        &current_track_index,
-        &tracks,
-        &should_reset,
-        &members,
-    )
+    user_input_loop(&sender, &started_session, &members, &config.media_dir)
 }
 
-/// Starts a background thread to broadcast ping messages and collect member responses.
+/// Lets the user pick the tracks of the session from the media library, asking again until at
+/// least one track is picked.
+fn choose_tracks(library: &[Track], media_dir: &Path) -> std::io::Result<Vec<Track>> {
+    // Tagged tracks are shown as "Artist – Title", with the file they come from as a hint
+    let labels = library
+        .iter()
+        .map(|track| {
+            let file = track
+                .path
+                .strip_prefix(media_dir)
+                .unwrap_or(&track.path)
+                .display()
+                .to_string();
+            (track.display_name(), file)
+        })
+        .collect::<Vec<(String, String)>>();
+    let options = || {
+        labels
+            .iter()
+            .enumerate()
+            .map(|(index, (name, file))| SelectOption::new(index).title(name).description(file))
+            .collect()
+    };
+
+    let selected_tracks = loop {
+        let selected = MultiSelect::new_complex(
+            "Please select the tracks (with SPACE) you want to include and then confirm with ENTER!",
+            options(),
+        )
+        .prompt()?;
+        if !selected.is_empty() {
+            break selected;
+        }
+        println!("Select at least one track to start the session.");
+    };
+
+    // Filter out the selected tracks from the library
+    Ok(library
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| selected_tracks.contains(index))
+        .map(|(_, track)| track.clone())
+        .collect())
+}
+
+/// Starts a background thread that keeps broadcasting ping messages.
 ///
-/// This function continuously sends ping messages to a broadcast address to discover and register active members.
-/// Each member's response is recorded in a shared `HashSet`. The thread stops when a termination signal is received.
-fn start_ping_thread(
-    socket: Arc<UdpSocket>,
-    broadcast_addr: String,
-    members: Arc<Mutex<HashSet<std::net::SocketAddr>>>,
-    ping_thread_should_terminate: Arc<Mutex<bool>>,
-) -> std::thread::JoinHandle<()> {
+/// Members answer a ping with a JOIN message, which is handled by the listener thread. The pings
+/// are sent for the whole session, so members that start late can still find the leader.
+fn start_ping_thread(socket: Arc<UdpSocket>, broadcast_addr: SocketAddr) {
     std::thread::spawn(move || {
         let mut broadcast_id = 0;
         loop {
-            if *ping_thread_should_terminate.lock().unwrap() {
-                break;
-            }
-
             broadcast_id += 1;
-            let ping_message = format!("PING,{}", broadcast_id);
-            if let Err(e) = socket.send_to(ping_message.as_bytes(), &broadcast_addr) {
+            let ping_message = Message::Ping { broadcast_id };
+            if let Err(e) = protocol::send_message(&socket, &ping_message, broadcast_addr) {
                 eprintln!("Failed to send ping: {}", e);
             }
 
-            socket
-                .set_read_timeout(Some(Duration::from_millis(100)))
-                .unwrap();
-
-            loop {
-                let mut buf = [0u8; 1024];
-                match socket.recv_from(&mut buf) {
-                    Ok((_, addr)) => {
-                        let mut members = members.lock().unwrap();
-                        if members.insert(addr) {
-                            println!("Member count: {}", members.len());
-                        }
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                    Err(e) => {
-                        eprintln!("Failed to receive: {}", e);
-                        break;
-                    }
-                }
-            }
-
             std::thread::sleep(Duration::from_millis(500));
         }
-    })
+    });
 }
 
-/// Stops the ping thread and notifies all members that broadcasting is complete.
+/// Sends a member everything it needs to take part in a running session.
 ///
-/// This function terminates the ping thread by setting a shared flag and sending a termination message
-/// to all registered members.
-fn stop_ping_thread(
-    ping_thread: std::thread::JoinHandle<()>,
-    ping_thread_should_terminate: &Arc<Mutex<bool>>,
-    socket: &Arc<UdpSocket>,
-    members: &Arc<Mutex<HashSet<std::net::SocketAddr>>>,
+/// The member receives the playlist and the current session state: the track index, whether the
+/// playback is paused, and the position the playback will be at when the next global start time is
+/// reached. The member seeks to that position at that time, so it joins in sync with everybody else.
+fn send_session(
+    socket: &UdpSocket,
+    session: &Session,
+    addr: SocketAddr,
+    start_time: u64,
 ) -> std::io::Result<()> {
-    *ping_thread_should_terminate.lock().unwrap() = true;
-    ping_thread.join().unwrap();
-    let message = "Done broadcasting";
-    for member in members.lock().unwrap().iter() {
-        socket.send_to(message.as_bytes(), member)?;
-    }
-    Ok(())
+    let playlist = Message::Playlist {
+        stream_port: session.stream_port,
+        tracks: session
+            .playback
+            .tracks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(TrackInfo::from)
+            .collect(),
+    };
+    protocol::send_message(socket, &playlist, addr)?;
+
+    let (paused, position) = {
+        let output_latency = *session.playback.output_latency.lock().unwrap();
+        let sink = session.playback.sink.lock().unwrap();
+        let position = session.playback.playhead.get();
+        if sink.is_paused() {
+            (true, position)
+        } else {
+            // The joining member is heard at the start time, as is the leader's playback
+            let lead = Duration::from_millis(start_time.saturating_sub(clock::system_time_ms()));
+            (false, (position + lead).saturating_sub(output_latency))
+        }
+    };
+    let order = session.playback.order.lock().unwrap().clone();
+    let state = Message::SessionState {
+        track_index: *session.playback.current_track_index.lock().unwrap() as u32,
+        paused,
+        volume: session.playback.volume.lock().unwrap().level,
+        order: order.order(),
+        sequence: order.indices(),
+        crossfade_ms: session.playback.crossfade.as_millis().min(u32::MAX as u128) as u32,
+        gain_mode: session.playback.gain_mode,
+        position_ms: position.as_millis() as u64,
+        start_time,
+    };
+    protocol::send_message(socket, &state, addr)
 }
 
-/// Starts a background thread to listen for and handle incoming commands from members.
+/// Starts a background thread to listen for and handle incoming messages from members.
 ///
-/// This function spawns a thread to receive commands from members via UDP and processes them.
-/// Supported commands include playback control (`p`, `n`, `r`, `s`). The thread ensures synchronization
-/// by broadcasting a global start time with each command.
+/// This function spawns a thread that receives all member messages via UDP for the whole session:
+/// JOIN messages register members (and bring them up to date once the session has started), time
+/// requests are answered for clock synchronization, and command requests (play/pause, next,
+/// restart, stop) and playlist edits are broadcast with a global start time to keep everybody
+/// synchronized.
+///
+/// Command and edit requests are acknowledged and de-duplicated, since members retransmit them until the
+/// ACK arrives. ACKs from members stop the retransmission of the commands sent to them. Requests
+/// are handled on this thread, one after another in the order they arrive; resolving and
+/// broadcasting them never waits for the network.
 fn start_listener_thread(
     socket: Arc<UdpSocket>,
-    sink: Arc<Mutex<Sink>>,
-    current_track_index: Arc<Mutex<usize>>,
-    should_reset: Arc<Mutex<bool>>,
-    tracks: Vec<Track>,
-    members: Arc<Mutex<HashSet<std::net::SocketAddr>>>,
+    session: Arc<Mutex<Option<Session>>>,
+    members: Arc<Mutex<Members>>,
+    sender: Arc<ReliableSender>,
 ) {
     std::thread::spawn(move || {
-        let mut buf = [0u8; 1024];
+        let mut dedup = Deduplicator::default();
+        let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
         loop {
             match socket.recv_from(&mut buf) {
-                Ok((size, _addr)) => {
-                    let message = String::from_utf8_lossy(&buf[..size]).to_string();
-                    if message.starts_with("PING") {
-                        continue; // Ignore PING messages
-                    }
-
-                    let global_start_time =
-                        utils::broadcast_start_time().expect("Cannot obtain current time");
-                    match message.trim() {
-                        "p" => handle_command(
-                            "0",
-                            global_start_time,
-                            &socket,
-                            &sink,
-                            &current_track_index,
-                            &tracks,
-                            &should_reset,
-                            &members,
-                        )
-                        .unwrap(),
-                        "n" => handle_command(
-                            "2",
-                            global_start_time,
-                            &socket,
-                            &sink,
-                            &current_track_index,
-                            &tracks,
-                            &should_reset,
-                            &members,
-                        )
-                        .unwrap(),
-                        "s" => handle_command(
-                            "3",
-                            global_start_time,
-                            &socket,
-                            &sink,
-                            &current_track_index,
-                            &tracks,
-                            &should_reset,
-                            &members,
-                        )
-                        .unwrap(),
-                        "r" => handle_command(
-                            "4",
-                            global_start_time,
-                            &socket,
-                            &sink,
-                            &current_track_index,
-                            &tracks,
-                            &should_reset,
-                            &members,
-                        )
-                        .unwrap(),
-                        _ => println!("Unknown command from member: {}", message),
-                    }
-                }
+                Ok((size, addr)) => match protocol::decode(&buf[..size]) {
+                    Ok(packet) => match packet.message {
+                        Message::Join => {
+                            let mut member_list = members.lock().unwrap();
+                            if member_list.join(addr) {
+                                println!(
+                                    "\nMember joined: {} (member count: {})",
+                                    addr,
+                                    member_list.len()
+                                );
+                            }
+                            drop(member_list);
+
+                            // A member re-sends JOIN until it has the playlist, so always answer
+                            let current_session = session.lock().unwrap().clone();
+                            if let Some(current_session) = current_session {
+                                let start_time = next_start_time(&current_session, &members);
+                                if let Err(e) =
+                                    send_session(&socket, &current_session, addr, start_time)
+                                {
+                                    eprintln!("Failed to send session to {}: {}", addr, e);
+                                }
+                            }
+                        }
+                        Message::KeepAlive => {
+                            let mut member_list = members.lock().unwrap();
+                            // A member that was evicted by mistake, e.g. after a network hiccup
+                            if !member_list.touch(addr) && member_list.join(addr) {
+                                println!(
+                                    "\nMember rejoined: {} (member count: {})",
+                                    addr,
+                                    member_list.len()
+                                );
+                            }
+                        }
+                        Message::Leave => {
+                            let mut member_list = members.lock().unwrap();
+                            if member_list.leave(addr) {
+                                println!(
+                                    "\nMember left: {} (member count: {})",
+                                    addr,
+                                    member_list.len()
+                                );
+                            }
+                        }
+                        Message::TimeRequest { origin } => {
+                            members.lock().unwrap().touch(addr);
+                            let receive_ms = clock::system_time_ms();
+                            if let Err(e) =
+                                clock::respond_to_time_request(&socket, origin, receive_ms, addr)
+                            {
+                                eprintln!("Failed to answer time request: {}", e);
+                            }
+                        }
+                        Message::TimeResponse {
+                            origin,
+                            receive,
+                            transmit,
+                        } => {
+                            let destination = clock::system_time_ms();
+                            let sample =
+                                ClockSample::from_exchange(origin, receive, transmit, destination);
+                            let round_trip = Duration::from_millis(sample.round_trip_ms);
+                            let mut member_list = members.lock().unwrap();
+                            member_list.touch(addr);
+                            member_list.add_round_trip(addr, round_trip);
+                        }
+                        Message::Ack { sequence } => {
+                            members.lock().unwrap().touch(addr);
+                            sender.acknowledge(addr, sequence);
+                        }
+                        Message::Request { command } => {
+                            members.lock().unwrap().touch(addr);
+                            reliable::send_ack(&socket, packet.sequence, addr);
+                            if !dedup.is_new(addr, packet.epoch, packet.sequence) {
+                                continue; // A retransmission of a request that was already handled
+                            }
+                            let current_session = session.lock().unwrap().clone();
+                            let Some(current_session) = current_session else {
+                                println!(
+                                    "\nIgnoring command from {}: playback has not started",
+                                    addr
+                                );
+                                continue;
+                            };
+                            // Handled right here, so requests are resolved in the order they arrive
+                            handle_command(command, &sender, &current_session, &members);
+                        }
+                        Message::EditRequest { edit } => {
+                            members.lock().unwrap().touch(addr);
+                            reliable::send_ack(&socket, packet.sequence, addr);
+                            if !dedup.is_new(addr, packet.epoch, packet.sequence) {
+                                continue; // A retransmission of a request that was already handled
+                            }
+                            let current_session = session.lock().unwrap().clone();
+                            let Some(current_session) = current_session else {
+                                println!(
+                                    "\nIgnoring playlist edit from {}: playback has not started",
+                                    addr
+                                );
+                                continue;
+                            };
+                            handle_edit(edit, &sender, &current_session, &members);
+                        }
+                        Message::TrackStatus { hash, availability } => {
+                            reliable::send_ack(&socket, packet.sequence, addr);
+                            if !dedup.is_new(addr, packet.epoch, packet.sequence) {
+                                continue; // A retransmission of a report that was already handled
+                            }
+                            if !members
+                                .lock()
+                                .unwrap()
+                                .set_availability(addr, hash, availability)
+                            {
+                                continue;
+                            }
+                            if availability != Availability::Playable {
+                                let current_session = session.lock().unwrap().clone();
+                                let name = current_session
+                                    .as_ref()
+                                    .and_then(|session| {
+                                        session.library.iter().find(|track| track.hash == hash)
+                                    })
+                                    .map(Track::display_name)
+                                    .unwrap_or_default();
+                                println!(
+                                    "\nMember {} cannot play {}: {}",
+                                    addr, name, availability
+                                );
+                            }
+                        }
+                        Message::Latency { latency_ms } => {
+                            reliable::send_ack(&socket, packet.sequence, addr);
+                            if !dedup.is_new(addr, packet.epoch, packet.sequence) {
+                                continue; // A retransmission of a report that was already handled
+                            }
+                            let latency = Duration::from_millis(latency_ms as u64);
+                            if members.lock().unwrap().set_latency(addr, latency) {
+                                println!(
+                                    "\nMember {} compensates an output latency of {} ms",
+                                    addr, latency_ms
+                                );
+                            }
+                        }
+                        Message::Ping { .. } => continue, // Ignore PING messages
+                        other => println!("Unexpected message from member: {:?}", other),
+                    },
+                    Err(e) => eprintln!("Ignoring packet from {}: {}", addr, e),
+                },
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     std::thread::sleep(Duration::from_millis(100)); // Optional sleep to reduce CPU usage
                 }
@@ -268,113 +476,347 @@ fn start_listener_thread(
     });
 }
 
+/// Starts a background thread that evicts members that stopped sending keepalives.
+///
+/// A member that has not been heard from for `timeout` is considered dead, e.g. because it crashed
+/// or lost its network connection, and the leader stops sending packets to it.
+fn start_liveness_thread(members: Arc<Mutex<Members>>, timeout: Duration) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+
+        let mut member_list = members.lock().unwrap();
+        for addr in member_list.evict_stale(Instant::now(), timeout) {
+            println!(
+                "\nMember timed out: {} (member count: {})",
+                addr,
+                member_list.len()
+            );
+        }
+    });
+}
+
+/// Starts a background thread that measures the round trip to every member once a second.
+///
+/// The leader sends time requests like the ones members use for their clock synchronization, and
+/// the listener thread records the round trips of the answers. They decide how far ahead actions
+/// are scheduled (see `Members::start_lead`).
+fn start_round_trip_thread(socket: Arc<UdpSocket>, members: Arc<Mutex<Members>>) {
+    std::thread::spawn(move || loop {
+        let addrs = members.lock().unwrap().addrs();
+        for addr in addrs {
+            let request = Message::TimeRequest {
+                origin: clock::system_time_ms(),
+            };
+            if let Err(e) = protocol::send_message(&socket, &request, addr) {
+                eprintln!("Failed to send time request to {}: {}", addr, e);
+            }
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    });
+}
+
+/// Starts a background thread that periodically broadcasts the leader's playback position.
+///
+/// While playing, every heartbeat carries the current track index, the position the leader's
+/// listeners hear (the sink position less the output latency) and the leader's clock at the
+/// moment the position was read, so members can measure and correct their
+/// drift (see `drift::correct_drift`). Nothing is sent while paused.
+fn start_heartbeat_thread(
+    socket: Arc<UdpSocket>,
+    playback: Playback,
+    members: Arc<Mutex<Members>>,
+    interval: Duration,
+) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+
+        let (position, leader_time) = {
+            let sink = playback.sink.lock().unwrap();
+            if sink.is_paused() {
+                continue;
+            }
+            let output_latency = *playback.output_latency.lock().unwrap();
+            (
+                playback.playhead.get().saturating_sub(output_latency),
+                clock::system_time_ms(),
+            )
+        };
+        let message = Message::Position {
+            track_index: *playback.current_track_index.lock().unwrap() as u32,
+            position_ms: position.as_millis() as u64,
+            leader_time,
+        };
+
+        for member in members.lock().unwrap().addrs() {
+            if let Err(e) = protocol::send_message(&socket, &message, member) {
+                eprintln!("Failed to send heartbeat to {}: {}", member, e);
+            }
+        }
+    });
+}
+
 /// Handles user input to control playback and sends commands to all members.
 ///
-/// This function continuously reads user input to process playback commands (`p`, `n`, `r`, `s`).
-/// For each command, it broadcasts the command and a global start time to all members for synchronization.
+/// This function continuously reads user input to process playback commands (`p`, `n`, `r`, `s`)
+/// and playlist edits (`add N`, `remove N`, `move N M`). For each of them, it broadcasts the
+/// outcome and a global start time to all members for synchronization.
 fn user_input_loop(
-    socket: &Arc<UdpSocket>,
-    sink: &Arc<Mutex<Sink>>,
-    current_track_index: &Arc<Mutex<usize>>,
-    tracks: &Vec<Track>,
-    should_reset: &Arc<Mutex<bool>>,
-    members: &Arc<Mutex<HashSet<std::net::SocketAddr>>>,
+    sender: &ReliableSender,
+    session: &Session,
+    members: &Arc<Mutex<Members>>,
+    media_dir: &Path,
 ) -> std::io::Result<()> {
+    let playback = &session.playback;
     loop {
         let mut input = String::new();
         if std::io::stdin().read_line(&mut input).is_ok() {
-            let global_start_time =
-                utils::broadcast_start_time().expect("Cannot obtain current time");
-            match input.trim() {
-                "p" => handle_command(
-                    "0",
-                    global_start_time,
-                    socket,
-                    sink,
-                    current_track_index,
-                    tracks,
-                    should_reset,
-                    members,
-                )?,
-                "n" => handle_command(
-                    "2",
-                    global_start_time,
-                    socket,
-                    sink,
-                    current_track_index,
-                    tracks,
-                    should_reset,
-                    members,
-                )?,
-                "s" => handle_command(
-                    "3",
-                    global_start_time,
-                    socket,
-                    sink,
-                    current_track_index,
-                    tracks,
-                    should_reset,
-                    members,
-                )?,
-                "r" => handle_command(
-                    "4",
-                    global_start_time,
-                    socket,
-                    sink,
-                    current_track_index,
-                    tracks,
-                    should_reset,
-                    members,
-                )?,
-                _ => println!("Invalid command! Use 'p', 'n', 'r', or 's'."),
+            if utils::handle_trim_input(&input, playback) {
+                continue;
+            }
+            if utils::handle_latency_input(&input, playback, |_| {}) {
+                continue;
+            }
+            if input.trim() == "members" {
+                print_member_reports(&members.lock().unwrap(), &playback.tracks.lock().unwrap());
+                continue;
+            }
+            if input.trim() == "library" {
+                print_library(&session.library, media_dir);
+                continue;
+            }
+            if input.trim() == "calibrate" {
+                start_calibration(sender, session, members);
+                continue;
+            }
+            if let Some(file) = input.trim().strip_prefix("save ") {
+                let path = Path::new(file.trim());
+                let tracks = playback.tracks.lock().unwrap().clone();
+                match playlist::save_playlist(path, &tracks, media_dir) {
+                    Ok(()) => println!("\nSaved the playlist to {}", path.display()),
+                    Err(e) => eprintln!("\nFailed to save the playlist: {}", e),
+                }
+                continue;
+            }
+            if let Some(edit) = Edit::from_input(&input) {
+                handle_edit(edit, sender, session, members);
+                continue;
+            }
+            match Command::from_input(&input) {
+                Some(command) => handle_command(command, sender, session, members),
+                None => {
+                    println!("Invalid command! Use 'p', 'n', 'b', 'track N', 'r', '+', '-', 'goto M:SS', 'vol +', 'vol -', 'vol N', 'trim N', 'latency N', 'calibrate', 'shuffle', 'repeat', 'library', 'add N', 'add next N', 'remove N', 'move N M', 'members', 'save FILE', or 's'.")
+                }
             }
         }
     }
 }
 
-/// Processes a playback command and broadcasts it to all members.
+/// Shows, for every member, the round trip to it, the output latency it compensates for and which
+/// tracks of the playlist it can play.
 ///
-/// This function executes a playback command locally and synchronizes it across all members by broadcasting
-/// the command and a global start time. Supported commands include:
-/// - `"0"`: Play/Pause toggle.
-/// - `"2"`: Skip to the next track.
-/// - `"3"`: Stop playback and exit.
-/// - `"4"`: Restart the current track.
-fn handle_command(
-    command: &str,
-    global_start_time: u64,
-    socket: &UdpSocket,
-    sink: &Arc<Mutex<Sink>>,
-    current_track_index: &Arc<Mutex<usize>>,
-    tracks: &Vec<Track>,
-    should_reset: &Arc<Mutex<bool>>,
-    addr_list: &Arc<Mutex<HashSet<std::net::SocketAddr>>>,
-) -> std::io::Result<()> {
-    let message = format!("{} : {}", command, global_start_time);
-    let addr_list = addr_list.lock().unwrap();
-    for addr in addr_list.iter() {
-        socket.send_to(message.as_bytes(), addr)?;
+/// Members check each track once it has been streamed to them, so tracks they have not downloaded
+/// yet are listed as not checked.
+fn print_member_reports(members: &Members, tracks: &[Track]) {
+    let reports = members.reports();
+    if reports.is_empty() {
+        println!("\nNo members have joined.");
+        return;
     }
 
-    match command {
-        "0" => utils::synchronized_action("p", global_start_time, sink),
-        "2" => {
-            {
-                let mut track_index = current_track_index.lock().unwrap();
-                *track_index += 1;
-                if *track_index >= tracks.len() {
-                    println!("\nNo more tracks!");
-                    println!("Thanks for using the SyncStream!");
-                    std::process::exit(0);
-                }
+    println!("\nMembers and the tracks they can play:");
+    for (addr, member) in reports {
+        let reported = &member.tracks;
+        let mut playable = Vec::new();
+        let mut unchecked = Vec::new();
+        let mut problems = Vec::new();
+        for (index, track) in tracks.iter().enumerate() {
+            match reported.get(&track.hash) {
+                Some(Availability::Playable) => playable.push((index + 1).to_string()),
+                Some(problem) => problems.push(format!(
+                    "{} ({}): {}",
+                    index + 1,
+                    track.display_name(),
+                    problem
+                )),
+                None => unchecked.push((index + 1).to_string()),
             }
-            utils::synchronized_action("n", global_start_time, sink);
-            *should_reset.lock().unwrap() = true;
         }
-        "3" => utils::synchronized_action("s", global_start_time, sink),
-        "4" => utils::synchronized_action("r", global_start_time, sink),
-        _ => {}
+
+        let mut details = Vec::new();
+        if let Some(round_trip) = member.round_trip() {
+            details.push(format!("round trip {} ms", round_trip.as_millis()));
+        }
+        if let Some(latency) = member.latency {
+            details.push(format!("output latency {} ms", latency.as_millis()));
+        }
+        if details.is_empty() {
+            println!("\t{}", addr);
+        } else {
+            println!("\t{} ({})", addr, details.join(", "));
+        }
+        if !playable.is_empty() {
+            println!("\t\tplayable: {}", playable.join(", "));
+        }
+        if !unchecked.is_empty() {
+            println!("\t\tnot checked yet: {}", unchecked.join(", "));
+        }
+        for problem in problems {
+            println!("\t\tcannot play {}", problem);
+        }
+    }
+}
+
+/// Schedules the calibration clicks on every member.
+///
+/// Members play the clicks without compensating their output latency, and measure how late they
+/// are heard from a recording of them (`calibrate FILE` on the member). The music would drown the
+/// clicks, so the playback has to be paused.
+fn start_calibration(sender: &ReliableSender, session: &Session, members: &Arc<Mutex<Members>>) {
+    if !session.playback.sink.lock().unwrap().is_paused() {
+        println!("\nPause the playback before calibrating.");
+        return;
+    }
+    if members.lock().unwrap().len() == 0 {
+        println!("\nNo members have joined.");
+        return;
+    }
+
+    let start_time = next_start_time(session, members);
+    broadcast_reliably(
+        &Message::Calibrate { start_time },
+        start_time,
+        sender,
+        members,
+    );
+    println!(
+        "\nMembers play {} calibration clicks in a moment. Record them, then run 'calibrate FILE' on each member with its recording.",
+        calibration::CLICK_COUNT
+    );
+}
+
+/// Lists the media library, numbered for `add N` and `add next N`.
+fn print_library(library: &[Track], media_dir: &Path) {
+    println!("\nLibrary:");
+    for (i, track) in library.iter().enumerate() {
+        println!(
+            "\t{}: {} ({})",
+            i + 1,
+            track.display_name(),
+            track
+                .path
+                .strip_prefix(media_dir)
+                .unwrap_or(&track.path)
+                .display()
+        );
     }
+    println!("\n");
+}
 
-    Ok(())
+/// Processes a playback command and broadcasts it to all members.
+///
+/// This function synchronizes a playback command across all members: the command is resolved into
+/// the absolute state the playback has to be in at the global start time, broadcast together with
+/// that start time, and then scheduled locally for that same time. The command is retransmitted to
+/// every member that has not acknowledged it, until the start time is reached.
+///
+/// The command is resolved against the state the pending actions leave behind, without waiting for
+/// them. Commands and edits are handled one at a time, so each one builds on the previous one.
+fn handle_command(
+    command: Command,
+    sender: &ReliableSender,
+    session: &Session,
+    addr_list: &Arc<Mutex<Members>>,
+) {
+    let _serialized = session.commands.lock().unwrap();
+    let global_start_time = next_start_time(session, addr_list);
+    let action = match utils::resolve_command(command, global_start_time, &session.playback) {
+        Ok(action) => action,
+        Err(message) => {
+            println!("\n{}", message);
+            return;
+        }
+    };
+    let message = Message::Command {
+        action,
+        start_time: global_start_time,
+    };
+    broadcast_reliably(&message, global_start_time, sender, addr_list);
+
+    utils::schedule_action(action, global_start_time, &session.playback);
+}
+
+/// Processes a playlist edit and broadcasts the new playlist to all members.
+///
+/// Like a command, the edit is resolved by the leader alone: the complete new playlist is broadcast
+/// along with the action that keeps the playback going, and every peer switches to it at the global
+/// start time. Edits that cannot be carried out are reported on the leader's console. Like a
+/// command, an edit is resolved against the state the pending actions and edits leave behind.
+fn handle_edit(
+    edit: Edit,
+    sender: &ReliableSender,
+    session: &Session,
+    addr_list: &Arc<Mutex<Members>>,
+) {
+    let _serialized = session.commands.lock().unwrap();
+    let global_start_time = next_start_time(session, addr_list);
+    let (tracks, sequence, action) =
+        match utils::resolve_edit(edit, global_start_time, &session.playback, &session.library) {
+            Ok(edited) => edited,
+            Err(message) => {
+                println!("\n{}", message);
+                return;
+            }
+        };
+    let message = Message::PlaylistEdit {
+        tracks: tracks.iter().map(TrackInfo::from).collect(),
+        sequence: sequence.indices(),
+        action,
+        start_time: global_start_time,
+    };
+    broadcast_reliably(&message, global_start_time, sender, addr_list);
+
+    let sequence = sequence.indices();
+    utils::schedule_edit(
+        tracks,
+        sequence,
+        action,
+        global_start_time,
+        &session.playback,
+    );
+}
+
+/// Picks the start time of an action that is broadcast now, far enough ahead for every member to
+/// receive it in time (see `Members::start_lead`).
+///
+/// Start times only ever increase, even when the lead shrinks, so peers can tell a retransmitted
+/// action from a newer one (see `Scheduler::schedule`).
+fn next_start_time(session: &Session, members: &Mutex<Members>) -> u64 {
+    let own_latency = *session.playback.output_latency.lock().unwrap();
+    let lead = members
+        .lock()
+        .unwrap()
+        .start_lead(own_latency, &session.config);
+    let start_time = utils::broadcast_start_time(lead).expect("Cannot obtain current time");
+
+    let mut last_start_time = session.last_start_time.lock().unwrap();
+    *last_start_time = start_time.max(*last_start_time + 1);
+    *last_start_time
+}
+
+/// Sends a message to every member, retransmitting it to those that have not acknowledged it until
+/// the start time is reached.
+fn broadcast_reliably(
+    message: &Message,
+    start_time: u64,
+    sender: &ReliableSender,
+    addr_list: &Arc<Mutex<Members>>,
+) {
+    let lead = Duration::from_millis(start_time.saturating_sub(clock::system_time_ms()));
+    let deadline = Instant::now() + lead;
+    for addr in addr_list.lock().unwrap().addrs() {
+        if let Err(e) = sender.send(message, addr, deadline) {
+            eprintln!("Failed to send command to {}: {}", addr, e);
+        }
+    }
 }
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::loudness::{Gain, ReplayGain};
+use crate::tags::Tags;
+use crate::track::{AudioFormat, Track};
+
+/// Name of the library index file, kept in the media directory. The leading dot keeps the media
+/// scan from picking it up.
+pub const INDEX_FILE: &str = ".syncstream-index";
+
+/// First line of an index file. Files starting with anything else, e.g. written by another
+/// version, are ignored and rebuilt.
+const INDEX_HEADER: &str = "# SyncStream library index, version 2";
+
+/// Size and modification time of a media file. A file whose stamp changed has to be scanned again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Modification time in nanoseconds since the UNIX epoch.
+    pub modified_ns: u128,
+}
+
+impl FileStamp {
+    /// Reads the stamp of the file at `path`.
+    pub fn of(path: &Path) -> io::Result<FileStamp> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?;
+        Ok(FileStamp {
+            size: metadata.len(),
+            modified_ns: modified
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_nanos()),
+        })
+    }
+}
+
+/// What scanning a media file found out, so the next start does not have to decode it again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IndexEntry {
+    stamp: FileStamp,
+    duration: Duration,
+    hash: u64,
+    tags: Tags,
+}
+
+/// On-disk cache of the scanned media library, keyed by file path.
+///
+/// The index is a text file with one tab-separated line per track: path, size, modification time,
+/// duration, content hash, title, artist, album, track number, year, and the track and album gain in
+/// hundredths of a decibel. Tabs, line breaks and backslashes inside values are escaped with a
+/// backslash.
+#[derive(Debug, Default)]
+pub struct LibraryIndex {
+    entries: HashMap<PathBuf, IndexEntry>,
+}
+
+impl LibraryIndex {
+    /// Loads the index from `path`. A missing or unreadable index is treated as empty, since it
+    /// only means that every file has to be scanned.
+    pub fn load(path: &Path) -> LibraryIndex {
+        match fs::read_to_string(path) {
+            Ok(contents) => LibraryIndex::parse(&contents),
+            Err(_) => LibraryIndex::default(),
+        }
+    }
+
+    fn parse(contents: &str) -> LibraryIndex {
+        let mut index = LibraryIndex::default();
+        let mut lines = contents.lines();
+        if lines.next() != Some(INDEX_HEADER) {
+            return index;
+        }
+
+        // Damaged lines are dropped; their files are simply scanned again
+        for line in lines {
+            if let Some((path, entry)) = parse_entry(line) {
+                index.entries.insert(path, entry);
+            }
+        }
+
+        index
+    }
+
+    /// Writes the index to `path`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut paths: Vec<&PathBuf> = self.entries.keys().collect();
+        paths.sort();
+
+        let mut contents = format!("{}\n", INDEX_HEADER);
+        for path in paths {
+            // Paths that are not valid UTF-8 cannot be written, so such files are always scanned
+            let Some(path_str) = path.to_str() else {
+                continue;
+            };
+            let entry = &self.entries[path];
+            let fields = [
+                escape(path_str),
+                entry.stamp.size.to_string(),
+                entry.stamp.modified_ns.to_string(),
+                entry.duration.as_nanos().to_string(),
+                format!("{:016x}", entry.hash),
+                escape(entry.tags.title.as_deref().unwrap_or_default()),
+                escape(entry.tags.artist.as_deref().unwrap_or_default()),
+                escape(entry.tags.album.as_deref().unwrap_or_default()),
+                entry
+                    .tags
+                    .track_number
+                    .map_or_else(String::new, |n| n.to_string()),
+                entry
+                    .tags
+                    .year
+                    .map_or_else(String::new, |year| year.to_string()),
+                gain_field(entry.tags.replay_gain.track),
+                gain_field(entry.tags.replay_gain.album),
+            ];
+            contents.push_str(&fields.join("\t"));
+            contents.push('\n');
+        }
+
+        fs::write(path, contents)
+    }
+
+    /// Returns the track at `path` as it was scanned before, provided the file has not changed since.
+    pub fn lookup(&self, path: &Path, format: AudioFormat, stamp: FileStamp) -> Option<Track> {
+        let entry = self
+            .entries
+            .get(path)
+            .filter(|entry| entry.stamp == stamp)?;
+        Some(Track {
+            name: path.file_stem()?.to_string_lossy().to_string(),
+            path: path.to_path_buf(),
+            format,
+            duration: entry.duration,
+            tags: entry.tags.clone(),
+            hash: entry.hash,
+        })
+    }
+
+    /// Adds a freshly scanned track, replacing what was known about its file.
+    pub fn insert(&mut self, track: &Track, stamp: FileStamp) {
+        self.entries.insert(
+            track.path.clone(),
+            IndexEntry {
+                stamp,
+                duration: track.duration,
+                hash: track.hash,
+                tags: track.tags.clone(),
+            },
+        );
+    }
+
+    /// Returns the number of indexed files.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+fn parse_entry(line: &str) -> Option<(PathBuf, IndexEntry)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [path, size, modified_ns, duration_ns, hash, title, artist, album, track_number, year, track_gain, album_gain] =
+        fields[..]
+    else {
+        return None;
+    };
+
+    let optional_text = |value: &str| Some(unescape(value)?).filter(|text| !text.is_empty());
+    let optional_number = |value: &str| match value {
+        "" => Some(None),
+        number => number.parse().ok().map(Some),
+    };
+    let optional_gain = |value: &str| match value {
+        "" => Some(None),
+        gain => gain.parse().ok().map(|gain| Some(Gain(gain))),
+    };
+    let duration_ns: u128 = duration_ns.parse().ok()?;
+
+    Some((
+        PathBuf::from(unescape(path)?),
+        IndexEntry {
+            stamp: FileStamp {
+                size: size.parse().ok()?,
+                modified_ns: modified_ns.parse().ok()?,
+            },
+            duration: Duration::new(
+                u64::try_from(duration_ns / 1_000_000_000).ok()?,
+                (duration_ns % 1_000_000_000) as u32,
+            ),
+            hash: u64::from_str_radix(hash, 16).ok()?,
+            tags: Tags {
+                title: optional_text(title),
+                artist: optional_text(artist),
+                album: optional_text(album),
+                track_number: optional_number(track_number)?,
+                year: optional_number(year)?,
+                replay_gain: ReplayGain {
+                    track: optional_gain(track_gain)?,
+                    album: optional_gain(album_gain)?,
+                },
+            },
+        },
+    ))
+}
+
+fn gain_field(gain: Option<Gain>) -> String {
+    gain.map_or_else(String::new, |gain| gain.0.to_string())
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            't' => unescaped.push('\t'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_track() -> Track {
+        Track {
+            name: "01 intro".to_string(),
+            path: PathBuf::from("media/Band\tName/01 intro.flac"),
+            format: AudioFormat::Flac,
+            duration: Duration::new(181, 250_000_123),
+            tags: Tags {
+                title: Some("Intro\\Outro".to_string()),
+                artist: Some("Band\nName".to_string()),
+                album: None,
+                track_number: Some(1),
+                year: None,
+                replay_gain: ReplayGain {
+                    track: Some(Gain(-712)),
+                    album: None,
+                },
+            },
+            hash: 0xaf63dc4c8601ec8c,
+        }
+    }
+
+    #[test]
+    fn test_index_round_trip() {
+        let track = sample_track();
+        let stamp = FileStamp {
+            size: 4_000_000,
+            modified_ns: 1_700_000_000_123_456_789,
+        };
+        let mut index = LibraryIndex::default();
+        index.insert(&track, stamp);
+
+        let path = std::env::temp_dir().join(format!("syncstream-index-{}", std::process::id()));
+        index.save(&path).unwrap();
+        let loaded = LibraryIndex::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(
+            loaded.lookup(&track.path, AudioFormat::Flac, stamp),
+            Some(track.clone())
+        );
+
+        // A file that was modified or replaced has to be scanned again
+        let touched = FileStamp {
+            modified_ns: stamp.modified_ns + 1,
+            ..stamp
+        };
+        assert_eq!(loaded.lookup(&track.path, AudioFormat::Flac, touched), None);
+    }
+
+    #[test]
+    fn test_parse_ignores_foreign_and_damaged_contents() {
+        let line = "media/a.mp3\t10\t20\t1000000000\t00000000000000ff\t\t\t\t\t\t\t";
+
+        assert_eq!(LibraryIndex::parse(line).len(), 0);
+        assert_eq!(
+            LibraryIndex::parse(&format!("{}\n{}\n", INDEX_HEADER, line)).len(),
+            1
+        );
+        assert_eq!(
+            LibraryIndex::parse(&format!("{}\nmedia/a.mp3\tten\n{}\n", INDEX_HEADER, "\\x")).len(),
+            0
+        );
+    }
+}
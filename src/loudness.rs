@@ -0,0 +1,363 @@
+use rodio::{Sample, Source};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::time::Duration;
+
+use crate::track::Track;
+
+/// Loudness every track is normalized to, in LUFS. This is the ReplayGain 2.0 reference level, so
+/// measured gains match the ReplayGain tags written by other tools.
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// A loudness adjustment in hundredths of a decibel, the precision ReplayGain tags are written
+/// with. Keeping it an integer makes it compare and travel over the wire exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gain(pub i32);
+
+impl Gain {
+    pub fn from_db(db: f64) -> Gain {
+        Gain((db * 100.0).round() as i32)
+    }
+
+    /// The gain that brings audio with the given integrated loudness to `REFERENCE_LOUDNESS`.
+    pub fn from_loudness(loudness: f64) -> Gain {
+        Gain::from_db(REFERENCE_LOUDNESS - loudness)
+    }
+
+    pub fn db(self) -> f64 {
+        self.0 as f64 / 100.0
+    }
+
+    /// The factor samples are multiplied with.
+    pub fn factor(self) -> f32 {
+        10f64.powf(self.db() / 20.0) as f32
+    }
+
+    /// Parses a ReplayGain tag value such as `-6.20 dB` or `+1.5 dB`.
+    pub fn parse(value: &str) -> Option<Gain> {
+        let value = value.trim();
+        let number = value
+            .strip_suffix("dB")
+            .or_else(|| value.strip_suffix("db"))
+            .unwrap_or(value)
+            .trim();
+        let db: f64 = number.strip_prefix('+').unwrap_or(number).parse().ok()?;
+        Some(db)
+            .filter(|db| db.is_finite() && db.abs() < 100.0)
+            .map(Gain::from_db)
+    }
+}
+
+/// The ReplayGain of a track and of the album it belongs to, taken from the file's tags or
+/// measured during the library scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayGain {
+    pub track: Option<Gain>,
+    pub album: Option<Gain>,
+}
+
+impl ReplayGain {
+    /// Returns the gain to play the track with. Album mode falls back to the track gain for tracks
+    /// that are not part of an album.
+    pub fn gain(&self, mode: GainMode) -> Option<Gain> {
+        match mode {
+            GainMode::Off => None,
+            GainMode::Track => self.track,
+            GainMode::Album => self.album.or(self.track),
+        }
+    }
+}
+
+/// Which ReplayGain the tracks are played with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GainMode {
+    /// Play the tracks as they are.
+    Off,
+    /// Bring every track to the same loudness.
+    #[default]
+    Track,
+    /// Bring every album to the same loudness, keeping the differences between its tracks.
+    Album,
+}
+
+impl GainMode {
+    /// Parses `off`, `track` or `album`.
+    pub fn from_name(name: &str) -> Option<GainMode> {
+        match name.trim() {
+            "off" => Some(GainMode::Off),
+            "track" => Some(GainMode::Track),
+            "album" => Some(GainMode::Album),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GainMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            GainMode::Off => "off",
+            GainMode::Track => "track",
+            GainMode::Album => "album",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Measures the integrated loudness of a source as defined by EBU R128 (ITU-R BS.1770).
+///
+/// Returns the loudness in LUFS, or `None` if the source is silent, along with the duration of the
+/// audio, since the whole source is decoded anyway.
+pub fn measure<S: Source<Item = i16>>(source: S) -> (Option<f64>, Duration) {
+    let channels = source.channels().max(1) as usize;
+    let sample_rate = source.sample_rate().max(1);
+    let mut meter = LoudnessMeter::new(channels, sample_rate);
+    let mut samples = 0u64;
+    for sample in source {
+        meter.add(sample.to_f32());
+        samples += 1;
+    }
+
+    let seconds = samples as f64 / (sample_rate as f64 * channels as f64);
+    (meter.integrated(), Duration::from_secs_f64(seconds))
+}
+
+/// Fills in the album gain of tracks whose tags have none, from the track gains of their album.
+///
+/// The album loudness is the duration-weighted energy average of the tracks' loudness, which is
+/// what measuring the album as a whole gives, apart from the gating. Tracks are grouped by their
+/// album tag; tracks without one are not part of an album.
+pub fn fill_album_gains(tracks: &mut [Track]) {
+    let mut albums: HashMap<String, (f64, f64)> = HashMap::new();
+    for track in tracks.iter() {
+        if let (Some(album), Some(gain)) = (&track.tags.album, track.tags.replay_gain.track) {
+            let loudness = REFERENCE_LOUDNESS - gain.db();
+            let seconds = track.duration.as_secs_f64();
+            let (energy, duration) = albums.entry(album.clone()).or_default();
+            *energy += seconds * 10f64.powf(loudness / 10.0);
+            *duration += seconds;
+        }
+    }
+
+    for track in tracks.iter_mut() {
+        if track.tags.replay_gain.album.is_some() {
+            continue;
+        }
+        let Some((energy, duration)) = track.tags.album.as_ref().and_then(|a| albums.get(a)) else {
+            continue;
+        };
+        if *energy > 0.0 && *duration > 0.0 {
+            let loudness = 10.0 * (energy / duration).log10();
+            track.tags.replay_gain.album = Some(Gain::from_loudness(loudness));
+        }
+    }
+}
+
+/// Length of a gating block, and how far consecutive blocks are apart, in 100 ms steps.
+const BLOCK_STEPS: usize = 4;
+
+/// Blocks quieter than this are silence and do not count (absolute gate, in LUFS).
+const ABSOLUTE_GATE: f64 = -70.0;
+
+/// Blocks this far below the loudness of the remaining blocks do not count (relative gate, in LU).
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Incremental EBU R128 integrated loudness measurement.
+///
+/// Samples are K-weighted per channel, and their mean square is collected in 100 ms steps, from
+/// which the overlapping 400 ms gating blocks are formed. All channels are weighted equally, which
+/// is exact for mono and stereo.
+struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    channel: usize,
+    step_frames: usize,
+    frames: usize,
+    energy: f64,
+    /// Mean square of the last steps, and of every gating block so far.
+    steps: Vec<f64>,
+    blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        LoudnessMeter {
+            channels,
+            filters: vec![k_weighting(sample_rate as f64); channels],
+            channel: 0,
+            step_frames: (sample_rate as usize / 10).max(1),
+            frames: 0,
+            energy: 0.0,
+            steps: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, sample: f32) {
+        let [shelf, high_pass] = &mut self.filters[self.channel];
+        let weighted = high_pass.process(shelf.process(sample as f64));
+        self.energy += weighted * weighted;
+
+        self.channel += 1;
+        if self.channel < self.channels {
+            return;
+        }
+        self.channel = 0;
+        self.frames += 1;
+        if self.frames < self.step_frames {
+            return;
+        }
+
+        self.steps.push(self.energy / self.step_frames as f64);
+        self.frames = 0;
+        self.energy = 0.0;
+        if self.steps.len() == BLOCK_STEPS {
+            self.blocks
+                .push(self.steps.iter().sum::<f64>() / BLOCK_STEPS as f64);
+            self.steps.remove(0);
+        }
+    }
+
+    /// Returns the gated loudness of everything measured so far, or `None` if it was silent.
+    fn integrated(&self) -> Option<f64> {
+        let gated_mean = |threshold: f64| {
+            let gated: Vec<f64> = self
+                .blocks
+                .iter()
+                .copied()
+                .filter(|power| block_loudness(*power) > threshold)
+                .collect();
+            Some(gated.iter().sum::<f64>() / gated.len() as f64).filter(|_| !gated.is_empty())
+        };
+
+        let relative_gate = block_loudness(gated_mean(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+        gated_mean(relative_gate.max(ABSOLUTE_GATE)).map(block_loudness)
+    }
+}
+
+fn block_loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// A second-order IIR filter in transposed direct form II.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting filter of BS.1770: a high shelf modelling the head, followed by a high pass.
+/// The coefficients are derived for `sample_rate` from the analog prototypes, so they match the
+/// ones given for 48 kHz in the standard.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        state: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Tags;
+    use crate::track::AudioFormat;
+    use rodio::buffer::SamplesBuffer;
+
+    /// A stereo 997 Hz sine at 48 kHz with the given peak level in dBFS.
+    fn sine(peak_db: f64, seconds: usize) -> SamplesBuffer<i16> {
+        let amplitude = 10f64.powf(peak_db / 20.0) * i16::MAX as f64;
+        let samples = (0..48_000 * seconds)
+            .flat_map(|i| {
+                let sample = (amplitude * (2.0 * PI * 997.0 * i as f64 / 48_000.0).sin()) as i16;
+                [sample, sample]
+            })
+            .collect::<Vec<i16>>();
+        SamplesBuffer::new(2, 48_000, samples)
+    }
+
+    #[test]
+    fn test_measure_integrated_loudness() {
+        // The EBU reference signal: a stereo sine at -23 dBFS reads -23 LUFS
+        let (loudness, duration) = measure(sine(-23.0, 5));
+        let loudness = loudness.unwrap();
+        assert!((loudness + 23.0).abs() < 0.1, "loudness {}", loudness);
+        assert_eq!(duration, Duration::from_secs(5));
+        assert_eq!(Gain::from_loudness(loudness), Gain(500));
+
+        assert_eq!(
+            measure(SamplesBuffer::new(1, 48_000, vec![0i16; 48_000])).0,
+            None
+        );
+    }
+
+    #[test]
+    fn test_gain_tags_and_album_gain() {
+        assert_eq!(Gain::parse("-6.20 dB"), Some(Gain(-620)));
+        assert_eq!(Gain::parse("+1.5 dB"), Some(Gain(150)));
+        assert_eq!(Gain::parse("loud"), None);
+        assert!((Gain(-600).factor() - 0.501).abs() < 0.001);
+
+        let track = |album: Option<&str>, track_gain: i32, album_gain: Option<i32>| Track {
+            name: "Track".to_string(),
+            path: "/nonexistent/track.flac".into(),
+            format: AudioFormat::Flac,
+            duration: Duration::from_secs(180),
+            tags: Tags {
+                album: album.map(str::to_string),
+                replay_gain: ReplayGain {
+                    track: Some(Gain(track_gain)),
+                    album: album_gain.map(Gain),
+                },
+                ..Tags::default()
+            },
+            hash: 0,
+        };
+        let mut tracks = [
+            track(Some("Live"), -300, None),
+            track(Some("Live"), -300, None),
+            track(Some("Tagged"), -300, Some(-100)),
+            track(None, -300, None),
+        ];
+        fill_album_gains(&mut tracks);
+
+        assert_eq!(tracks[0].tags.replay_gain.album, Some(Gain(-300)));
+        assert_eq!(tracks[2].tags.replay_gain.album, Some(Gain(-100)));
+        assert_eq!(
+            tracks[3].tags.replay_gain.gain(GainMode::Album),
+            Some(Gain(-300))
+        );
+        assert_eq!(tracks[0].tags.replay_gain.gain(GainMode::Off), None);
+    }
+}
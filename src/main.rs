@@ -1,19 +1,50 @@
+mod calibration;
+mod clock;
+mod config;
+mod drift;
 mod leader;
+mod library;
+mod loudness;
 mod member;
+mod members;
+mod order;
 mod player;
+mod playlist;
+mod protocol;
+mod reliable;
+mod scheduler;
+mod session_log;
+mod stream;
+mod tags;
 mod track;
+mod transition;
 mod utils;
 
 use asky::Select;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, CONFIG_FILE};
 
 fn main() -> std::io::Result<()> {
     println!("Welcome to SyncStream!");
+    let mut config = Config::load(Path::new(CONFIG_FILE));
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rebuild-index" => config.rebuild_index = true,
+            "--playlist" => match args.next() {
+                Some(playlist) => config.playlist = Some(PathBuf::from(playlist)),
+                None => eprintln!("Ignoring `--playlist` without a playlist file"),
+            },
+            other => eprintln!("Ignoring unknown argument `{}`", other),
+        }
+    }
     let options = ["Leader (Playback Controller)", "Member (Music Enjoyer)"];
     let answer = Select::new("Which role do you want?", options).prompt()?;
 
     match answer {
-        "Leader (Playback Controller)" => leader::run_leader()?,
-        "Member (Music Enjoyer)" => member::run_member()?,
+        "Leader (Playback Controller)" => leader::run_leader(&config)?,
+        "Member (Music Enjoyer)" => member::run_member(&config)?,
         _ => {}
     }
 
@@ -1,130 +1,421 @@
-use rodio::{OutputStream, Sink};
-use std::net::UdpSocket;
-use std::sync::{Arc, Mutex};
+use rodio::{OutputStream, OutputStreamHandle, Sink};
+use std::collections::HashSet;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::player::{add_tracks_to_sink, display_progress, load_audio_files};
-use crate::track::Track;
+use crate::calibration::{self, Capture};
+use crate::clock::{self, ClockSync};
+use crate::config::Config;
+use crate::drift;
+use crate::order::Sequence;
+use crate::player::{self, add_tracks_to_sink, display_progress, AudioSource, Playback, Volume};
+use crate::protocol::{self, Action, Command, Edit, Message, ProtocolError};
+use crate::reliable::{self, Deduplicator, ReliableSender};
+use crate::scheduler::Scheduler;
+use crate::session_log::SessionLog;
+use crate::track::{Availability, Track};
+use crate::transition::Playhead;
 use crate::utils;
 
 /// Executes the member's role in the synchronization process.
-/// 
-/// This function coordinates the synchronization process for a member device. It listens for 
+///
+/// This function coordinates the synchronization process for a member device. It listens for
 /// broadcast messages from the leader, establishes a connection, and synchronizes audio playback.
-/// 
+///
 /// # Steps
 /// 1. Binds to a specified UDP port and listens for leader broadcasts.
-/// 2. Responds to leader pings and establishes communication.
-/// 3. Starts a user input thread to send playback commands to the leader.
-/// 4. Loads audio tracks and displays playback progress.
-/// 5. Listens for synchronization messages from the leader to control playback.
-pub fn run_member() -> std::io::Result<()> {
+/// 2. Starts estimating the offset between the local clock and the leader's clock.
+/// 3. Joins the session once the clock estimate is available.
+/// 4. Receives the playlist and the session state, which also works for a session already in progress.
+/// 5. Starts a user input thread to send playback commands and playlist edits to the leader.
+/// 6. Streams the audio from the leader, seeks to the session's position and displays playback progress.
+/// 7. Listens for synchronization messages from the leader to control playback.
+pub fn run_member(config: &Config) -> std::io::Result<()> {
     let socket = UdpSocket::bind("0.0.0.0:12345")?;
     println!("Welcome to SyncStream!\nListening for broadcasts...");
 
     let mut last_received_id = 0;
     let leader_addr = Arc::new(Mutex::new(None));
+    let clock = Arc::new(Mutex::new(ClockSync::default()));
 
-    loop {
-        let mut buf = [0u8; 1024];
+    // Wait for the playlist and the session state, whose audio is streamed from the leader's side channel
+    let mut playlist = None;
+    let mut session_state = None;
+    let mut incompatible = HashSet::new();
+    let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
+    while playlist.is_none() || session_state.is_none() {
         let (size, src) = socket.recv_from(&mut buf)?;
-        let message = String::from_utf8_lossy(&buf[..size]);
 
-        if message.starts_with("PING") {
-            handle_ping_message(&message, &mut last_received_id, &leader_addr, &socket, src)?;
-        } else if message == "Done broadcasting" {
-            break;
+        match protocol::decode(&buf[..size]) {
+            Ok(packet) => match packet.message {
+                Message::Ping { broadcast_id } => handle_ping_message(
+                    broadcast_id,
+                    &mut last_received_id,
+                    &leader_addr,
+                    &clock,
+                    &socket,
+                    src,
+                )?,
+                // Only the leader that was connected to takes part in the session
+                _ if *leader_addr.lock().unwrap() != Some(src) => {}
+                Message::TimeResponse {
+                    origin,
+                    receive,
+                    transmit,
+                } => clock::handle_time_response(&clock, origin, receive, transmit),
+                Message::Playlist {
+                    stream_port,
+                    tracks,
+                } => {
+                    let tracks: Vec<Track> = tracks.iter().map(Track::from).collect();
+                    playlist = Some((SocketAddr::new(src.ip(), stream_port), tracks));
+                }
+                Message::SessionState {
+                    track_index,
+                    paused,
+                    volume,
+                    order,
+                    sequence,
+                    crossfade_ms,
+                    gain_mode,
+                    position_ms,
+                    start_time,
+                } => {
+                    session_state = Some((
+                        track_index as usize,
+                        paused,
+                        volume,
+                        (order, sequence),
+                        Duration::from_millis(crossfade_ms as u64),
+                        gain_mode,
+                        position_ms,
+                        start_time,
+                    ));
+                }
+                _ => {}
+            },
+            Err(e) => report_undecodable(src, &e, &mut incompatible),
         }
     }
+    let (stream_addr, tracks) = playlist.unwrap();
+    let (
+        track_index,
+        paused,
+        volume,
+        (order, sequence),
+        crossfade,
+        gain_mode,
+        position_ms,
+        start_time,
+    ) = session_state.unwrap();
+    let order = Sequence::from_indices(order, &sequence, tracks.len());
+    let tracks = Arc::new(Mutex::new(tracks));
 
-    spawn_user_input_thread(socket.try_clone()?, Arc::clone(&leader_addr));
+    let sender = ReliableSender::new(Arc::new(socket.try_clone()?), config.command_retry_interval);
+    sender.start_retransmit_thread();
+
+    let leader = leader_addr
+        .lock()
+        .unwrap()
+        .expect("The session comes from the leader");
+    spawn_keepalive_thread(socket.try_clone()?, leader, config.keepalive_interval);
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
     let sink = Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap()));
     sink.lock().unwrap().pause(); // To prevent playing before synchronization
 
-    let mut tracks = Vec::<Track>::new();
-    load_audio_files("media", &mut tracks);
-
-    // Wait for a message from the leader for the list of track names
-    let mut buf = [0u8; 1024];
-    let (size, _) = socket.recv_from(&mut buf)?;
-    let message = String::from_utf8_lossy(&buf[..size]);
-    let selected_tracks: Vec<&str> = message.split(':').collect();
-    let selected_tracks: Vec<&str> = selected_tracks[1].split(',').collect();
-
-    let tracks: Vec<Track> = tracks
-        .into_iter()
-        .filter(|track| selected_tracks.contains(&&track.name.as_str()))
-        .collect();
+    let (verified, verifications) = mpsc::channel();
+    spawn_verification_thread(
+        verifications,
+        Arc::clone(&sender),
+        Arc::clone(&leader_addr),
+        Arc::clone(&tracks),
+        config.member_timeout,
+    );
 
-    add_tracks_to_sink("media", Arc::clone(&sink), &tracks);
+    let (track_ends, ended_tracks) = mpsc::channel();
+    let output_latency = Arc::new(Mutex::new(config.output_latency));
+    let playback = Playback {
+        sink,
+        tracks,
+        current_track_index: Arc::new(Mutex::new(track_index)),
+        should_reset: Arc::new(Mutex::new(false)),
+        clock: Arc::clone(&clock),
+        audio: AudioSource::streamed(stream_addr, verified),
+        volume: Arc::new(Mutex::new(Volume::default())),
+        order: Arc::new(Mutex::new(order)),
+        playhead: Arc::new(Playhead::default()),
+        crossfade,
+        output_latency: Arc::clone(&output_latency),
+        gain_mode,
+        track_ends,
+        queue_generation: Arc::new(Mutex::new(0)),
+        log: Arc::new(Mutex::new(SessionLog::open(
+            config.session_log.as_deref(),
+            "member",
+        ))),
+        scheduler: Scheduler::start(clock, output_latency),
+    };
+    add_tracks_to_sink(&playback, track_index);
+    player::set_volume(&playback, Some(volume), None);
+    report_latency(
+        &sender,
+        &leader_addr,
+        config.output_latency,
+        config.member_timeout,
+    );
 
-    let current_track_index = Arc::new(Mutex::new(0));
-    let should_reset = Arc::new(Mutex::new(false));
+    let calibration_clicks = Arc::new(Mutex::new(None));
+    spawn_user_input_thread(
+        socket.try_clone()?,
+        Arc::clone(&sender),
+        Arc::clone(&leader_addr),
+        playback.clone(),
+        Arc::clone(&calibration_clicks),
+        config.member_timeout,
+    );
 
     display_progress(
-        Arc::clone(&sink),
-        tracks.clone(),
-        Arc::clone(&current_track_index),
-        Arc::clone(&should_reset),
+        Arc::clone(&playback.sink),
+        Arc::clone(&playback.playhead),
+        Arc::clone(&playback.tracks),
+        Arc::clone(&playback.current_track_index),
+        Arc::clone(&playback.should_reset),
+        Arc::clone(&playback.volume),
     );
 
-    utils::start_track_position_thread(
-        sink.clone(),
-        current_track_index.clone(),
-        should_reset.clone(),
-        tracks.clone(),
+    utils::start_track_transition_thread(playback.clone(), ended_tracks);
+
+    println!(
+        "Drift correction: resampling from {} ms, seeking from {} ms",
+        config.drift_resample_threshold.as_millis(),
+        config.drift_seek_threshold.as_millis()
     );
 
-    handle_incoming_messages(socket, sink, tracks, current_track_index, should_reset)
+    // Joining is the same as executing the session's state at its start time
+    let track_index = track_index as u32;
+    let join = if paused {
+        Action::Pause {
+            track_index,
+            position_ms,
+        }
+    } else {
+        Action::Play {
+            track_index,
+            position_ms,
+        }
+    };
+    utils::schedule_action(join, start_time, &playback);
+
+    handle_incoming_messages(
+        socket,
+        leader,
+        &sender,
+        playback,
+        stream_handle,
+        calibration_clicks,
+        config,
+    )
 }
 
 /// Handles incoming PING messages from the leader.
-/// 
-/// This function processes PING messages from the leader, determines if the leader's ID 
-/// is valid, and responds with an ACK message to establish a connection.
+///
+/// The first leader that pings becomes this member's leader, and the clock synchronization with
+/// it is started right away. Once the clock estimate is available, every new PING from the leader
+/// is answered with a JOIN message until the session has been received, so a lost JOIN or a lost
+/// playlist is simply retried.
 fn handle_ping_message(
-    message: &str,
+    broadcast_id: u64,
     last_received_id: &mut u64,
-    leader_addr: &Arc<Mutex<Option<std::net::SocketAddr>>>,
+    leader_addr: &Arc<Mutex<Option<SocketAddr>>>,
+    clock: &Arc<Mutex<ClockSync>>,
     socket: &UdpSocket,
-    src: std::net::SocketAddr,
+    src: SocketAddr,
 ) -> std::io::Result<()> {
-    let parts: Vec<&str> = message.split(',').collect();
-    if let Ok(id) = parts[1].parse::<u64>() {
-        if id > *last_received_id {
-            *last_received_id = id;
-            if leader_addr.lock().unwrap().is_none() {
-                socket.send_to(b"ACK", src)?;
-                println!("Connected to leader at {}", src);
-                *leader_addr.lock().unwrap() = Some(src);
-            }
+    if broadcast_id <= *last_received_id {
+        return Ok(());
+    }
+    *last_received_id = broadcast_id;
+
+    let mut leader = leader_addr.lock().unwrap();
+    match *leader {
+        None => {
+            println!("Connected to leader at {}", src);
+            *leader = Some(src);
+            clock::start_sync_thread(socket.try_clone()?, src);
+        }
+        Some(addr) if addr == src && clock.lock().unwrap().is_ready() => {
+            protocol::send_message(socket, &Message::Join, src)?;
         }
+        Some(_) => {}
     }
     Ok(())
 }
 
+/// Spawns a thread that periodically tells the leader this member is still there.
+fn spawn_keepalive_thread(socket: UdpSocket, leader_addr: SocketAddr, interval: Duration) {
+    thread::spawn(move || loop {
+        if let Err(e) = protocol::send_message(&socket, &Message::KeepAlive, leader_addr) {
+            eprintln!("Failed to send keepalive: {}", e);
+        }
+        thread::sleep(interval);
+    });
+}
+
+/// Spawns a thread that reports to the leader whether the streamed tracks can be played.
+///
+/// Every downloaded track is checked against the content hash announced in the playlist (see
+/// `stream::fetch_track`). The outcome is sent to the leader, which shows per member which tracks
+/// it can play, and problems are also shown on this member's console. Tracks are reported by
+/// content hash, since their playlist position can change while they download. Reports are
+/// retransmitted until the leader acknowledges them, or until `timeout` has passed.
+fn spawn_verification_thread(
+    verifications: mpsc::Receiver<(u64, Availability)>,
+    sender: Arc<ReliableSender>,
+    leader_addr: Arc<Mutex<Option<SocketAddr>>>,
+    tracks: Arc<Mutex<Vec<Track>>>,
+    timeout: Duration,
+) {
+    thread::spawn(move || {
+        for (hash, availability) in verifications {
+            if availability != Availability::Playable {
+                let name = tracks
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|track| track.hash == hash)
+                    .map(|track| track.name.clone())
+                    .unwrap_or_default();
+                eprintln!("\nTrack {} cannot be played: {}", name, availability);
+            }
+
+            let Some(addr) = *leader_addr.lock().unwrap() else {
+                continue;
+            };
+            let message = Message::TrackStatus { hash, availability };
+            if let Err(e) = sender.send(&message, addr, Instant::now() + timeout) {
+                eprintln!("Failed to report track status to leader: {}", e);
+            }
+        }
+    });
+}
+
+/// Tells the leader which output latency this member compensates for, retransmitting until the
+/// leader acknowledges it or `timeout` has passed.
+fn report_latency(
+    sender: &ReliableSender,
+    leader_addr: &Mutex<Option<SocketAddr>>,
+    latency: Duration,
+    timeout: Duration,
+) {
+    let Some(addr) = *leader_addr.lock().unwrap() else {
+        return;
+    };
+    let message = Message::Latency {
+        latency_ms: latency.as_millis().min(u32::MAX as u128) as u32,
+    };
+    if let Err(e) = sender.send(&message, addr, Instant::now() + timeout) {
+        eprintln!("Failed to report output latency to leader: {}", e);
+    }
+}
+
+/// Measures the output latency of this member from a WAV recording of the calibration clicks that
+/// were played at `clicks`, and compensates it from now on.
+///
+/// The recording can be made with a microphone in front of the speakers, or as a loopback capture
+/// of the audio output; it has to be saved right after the clicks (see `Capture::from_wav`).
+/// Returns the new latency, or `None` if it could not be measured.
+fn calibrate(file: &Path, clicks: Option<SystemTime>, playback: &Playback) -> Option<Duration> {
+    let Some(played_at) = clicks else {
+        println!("No calibration clicks have been played yet. Start them with 'calibrate' on the leader.");
+        return None;
+    };
+    let capture = match Capture::from_wav(file) {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("\nFailed to read the recording {}: {}", file.display(), e);
+            return None;
+        }
+    };
+    match capture.measure_latency(played_at) {
+        Ok(latency) => {
+            println!("\nMeasured an output latency of {} ms", latency.as_millis());
+            utils::set_output_latency(latency, playback);
+            Some(latency)
+        }
+        Err(message) => {
+            println!("\n{}", message);
+            None
+        }
+    }
+}
+
 /// Spawns a thread to handle user input and send commands to the leader.
-/// 
-/// This function continuously reads user input and sends supported commands (`p`, `n`, `r`, `s`) 
-/// to the leader via UDP. If the leader address is not known, it informs the user to wait.
+///
+/// This function continuously reads user input and sends supported commands (`p`, `n`, `b`, `r`, seeks, track jumps)
+/// and playlist edits (`add N`, `remove N`, `move N M`) to the leader via UDP. If the leader address is not known, it informs the user to wait.
+/// `s` only ends this member's participation: the leader is told with a LEAVE message and the
+/// rest of the session keeps playing. `trim` commands only change this member's own volume trim,
+/// and `latency` commands its output latency, which is reported to the leader. `calibrate FILE`
+/// measures the output latency from a recording of the last calibration clicks (see
+/// `calibrate`). Requests are retransmitted until the leader acknowledges them, or until `timeout`
+/// has passed.
 fn spawn_user_input_thread(
     socket: UdpSocket,
-    leader_addr: Arc<Mutex<Option<std::net::SocketAddr>>>,
+    sender: Arc<ReliableSender>,
+    leader_addr: Arc<Mutex<Option<SocketAddr>>>,
+    playback: Playback,
+    calibration_clicks: Arc<Mutex<Option<SystemTime>>>,
+    timeout: Duration,
 ) {
     thread::spawn(move || loop {
         let mut input = String::new();
         if std::io::stdin().read_line(&mut input).is_ok() {
-            let trimmed = input.trim();
+            if utils::handle_trim_input(&input, &playback) {
+                continue;
+            }
+            let report = |latency| report_latency(&sender, &leader_addr, latency, timeout);
+            if utils::handle_latency_input(&input, &playback, report) {
+                continue;
+            }
+            if let Some(file) = input.trim().strip_prefix("calibrate ") {
+                let clicks = *calibration_clicks.lock().unwrap();
+                if let Some(latency) = calibrate(Path::new(file.trim()), clicks, &playback) {
+                    report_latency(&sender, &leader_addr, latency, timeout);
+                }
+                continue;
+            }
             if let Some(addr) = *leader_addr.lock().unwrap() {
-                match trimmed {
-                    "p" | "n" | "s" | "r" => {
-                        if let Err(e) = socket.send_to(trimmed.as_bytes(), addr) {
+                if let Some(edit) = Edit::from_input(&input) {
+                    let message = Message::EditRequest { edit };
+                    let deadline = Instant::now() + timeout;
+                    if let Err(e) = sender.send(&message, addr, deadline) {
+                        eprintln!("Failed to send input to leader: {}", e);
+                    }
+                    continue;
+                }
+                match Command::from_input(&input) {
+                    Some(Command::Stop) => {
+                        if let Err(e) = protocol::send_message(&socket, &Message::Leave, addr) {
+                            eprintln!("Failed to notify the leader: {}", e);
+                        }
+                        utils::end_session(&playback);
+                        println!("\nThanks for using the SyncStream!");
+                        std::process::exit(0);
+                    }
+                    Some(command) => {
+                        let message = Message::Request { command };
+                        let deadline = Instant::now() + timeout;
+                        if let Err(e) = sender.send(&message, addr, deadline) {
                             eprintln!("Failed to send input to leader: {}", e);
                         }
                     }
-                    _ => println!("Unknown command. Use 'p', 'n', 'r', or 's'."),
+                    None => println!(
+                        "Unknown command. Use 'p', 'n', 'b', 'track N', 'r', '+', '-', 'goto M:SS', 'vol +', 'vol -', 'vol N', 'trim N', 'latency N', 'calibrate FILE', 'shuffle', 'repeat', 'add N', 'add next N', 'remove N', 'move N M', or 's'."
+                    ),
                 }
             } else {
                 println!("Leader address not known yet. Please wait.");
@@ -133,75 +424,154 @@ fn spawn_user_input_thread(
     });
 }
 
+/// Reports a packet that could not be decoded.
+///
+/// A leader running an incompatible build keeps pinging every half second, so a protocol version
+/// mismatch is reported once per sender instead of for every packet.
+fn report_undecodable(
+    src: SocketAddr,
+    error: &ProtocolError,
+    incompatible: &mut HashSet<SocketAddr>,
+) {
+    match error {
+        ProtocolError::UnsupportedVersion(version) => {
+            if incompatible.insert(src) {
+                eprintln!(
+                    "Ignoring {}: leader speaks protocol v{}, this build speaks v{}",
+                    src,
+                    version,
+                    protocol::PROTOCOL_VERSION
+                );
+            }
+        }
+        e => eprintln!("Ignoring packet from {}: {}", src, e),
+    }
+}
+
 /// Listens for and processes synchronization messages from the leader.
-/// 
-/// This function continuously listens for messages from the leader to synchronize 
-/// playback. It extracts the timestamp and playback mode from each message and 
-/// executes the corresponding action.
+///
+/// This function continuously listens for messages from the leader to synchronize
+/// playback. Each command message carries the absolute playback state and the
+/// timestamp at which it has to be reached, and playlist edits carry the complete new playlist.
+/// Position heartbeats are used to correct drift
+/// that builds up between commands.
+///
+/// Commands are acknowledged as soon as they arrive. The leader retransmits commands
+/// whose ACK got lost, so commands that were already seen are not executed again.
+///
+/// Calibration clicks are scheduled like actions but played on a sink of their own, and the local
+/// time at which they started is kept in `calibration_clicks` for `calibrate FILE`.
+///
+/// The leader pings continuously, so if nothing arrives from it for `member_timeout`
+/// the member warns that the leader is not responding.
+///
+/// Only messages from `leader` are handled. Other hosts on the network, e.g. a leader of an
+/// earlier session, cannot drive the playback.
 fn handle_incoming_messages(
     socket: UdpSocket,
-    sink: Arc<Mutex<Sink>>,
-    tracks: Vec<Track>,
-    current_track_index: Arc<Mutex<usize>>,
-    should_reset: Arc<Mutex<bool>>,
+    leader: SocketAddr,
+    sender: &ReliableSender,
+    playback: Playback,
+    stream_handle: OutputStreamHandle,
+    calibration_clicks: Arc<Mutex<Option<SystemTime>>>,
+    config: &Config,
 ) -> std::io::Result<()> {
-    let mut buf = [0u8; 1024];
+    let mut dedup = Deduplicator::default();
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut last_seen_leader = Instant::now();
+    let mut leader_lost = false;
+    let mut incompatible = HashSet::new();
+
+    let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
     loop {
-        match socket.recv_from(&mut buf) {
-            Ok((size, _)) => {
-                let message = String::from_utf8_lossy(&buf[..size]);
-
-                if let Some(timestamp) = utils::extract_timestamp(&message) {
-                    if let Some(mode) = utils::extract_mode(&message) {
-                        handle_mode(
-                            mode,
-                            timestamp,
-                            &sink,
-                            &tracks,
-                            &current_track_index,
-                            &should_reset,
-                        );
-                    } else {
-                        println!("Failed to extract a mode.");
-                    }
-                } else {
-                    println!("Failed to extract a timestamp.");
-                }
+        let received = socket.recv_from(&mut buf);
+        if received.as_ref().is_ok_and(|(_, src)| *src == leader) {
+            last_seen_leader = Instant::now();
+            if leader_lost {
+                println!("\nLeader is responding again.");
+                leader_lost = false;
             }
-            Err(e) => eprintln!("Error receiving: {}", e),
+        } else if !leader_lost && last_seen_leader.elapsed() >= config.member_timeout {
+            println!("\nLeader is not responding!");
+            leader_lost = true;
         }
-    }
-}
 
-/// Executes a playback command based on the received mode and timestamp.
-/// 
-/// This function synchronizes playback by executing the specified mode (play, pause, 
-/// next track, restart track, stop) at the given timestamp.
-fn handle_mode(
-    mode: u64,
-    timestamp: u64,
-    sink: &Arc<Mutex<Sink>>,
-    tracks: &[Track],
-    current_track_index: &Arc<Mutex<usize>>,
-    should_reset: &Arc<Mutex<bool>>,
-) {
-    match mode {
-        0 => utils::synchronized_action("p", timestamp, sink),
-        2 => {
-            {
-                let mut track_index = current_track_index.lock().unwrap();
-                *track_index += 1;
-                if *track_index >= tracks.len() {
-                    println!("\nNo more tracks!");
-                    println!("Thanks for using the SyncStream!");
-                    std::process::exit(0);
-                }
-            }
-            utils::synchronized_action("n", timestamp, sink);
-            *should_reset.lock().unwrap() = true;
+        match received {
+            Ok((_, src)) if src != leader => {}
+            Ok((size, src)) => match protocol::decode(&buf[..size]) {
+                Ok(packet) => match packet.message {
+                    Message::Command { action, start_time } => {
+                        reliable::send_ack(&socket, packet.sequence, src);
+                        if dedup.is_new(src, packet.epoch, packet.sequence) {
+                            utils::schedule_action(action, start_time, &playback);
+                        }
+                    }
+                    Message::PlaylistEdit {
+                        tracks,
+                        sequence,
+                        action,
+                        start_time,
+                    } => {
+                        reliable::send_ack(&socket, packet.sequence, src);
+                        if dedup.is_new(src, packet.epoch, packet.sequence) {
+                            let tracks = tracks.iter().map(Track::from).collect();
+                            utils::schedule_edit(tracks, sequence, action, start_time, &playback);
+                        }
+                    }
+                    Message::Calibrate { start_time } => {
+                        reliable::send_ack(&socket, packet.sequence, src);
+                        if dedup.is_new(src, packet.epoch, packet.sequence) {
+                            let clicks = Arc::clone(&calibration_clicks);
+                            let scheduled = calibration::schedule_clicks(
+                                &stream_handle,
+                                start_time,
+                                &playback,
+                                move |played_at| {
+                                    *clicks.lock().unwrap() = Some(played_at);
+                                    println!("\nPlaying the calibration clicks. Once they are over, run 'calibrate FILE' with a WAV recording of them to measure the output latency.");
+                                },
+                            );
+                            if let Err(e) = scheduled {
+                                eprintln!("\nFailed to play the calibration clicks: {}", e);
+                            }
+                        }
+                    }
+                    Message::Ack { sequence } => {
+                        sender.acknowledge(src, sequence);
+                    }
+                    Message::TimeResponse {
+                        origin,
+                        receive,
+                        transmit,
+                    } => clock::handle_time_response(&playback.clock, origin, receive, transmit),
+                    Message::TimeRequest { origin } => {
+                        // The leader measures the round trip to pick how far ahead it schedules
+                        let receive_ms = clock::system_time_ms();
+                        if let Err(e) =
+                            clock::respond_to_time_request(&socket, origin, receive_ms, src)
+                        {
+                            eprintln!("Failed to answer time request: {}", e);
+                        }
+                    }
+                    Message::Position {
+                        track_index,
+                        position_ms,
+                        leader_time,
+                    } => drift::correct_drift(
+                        track_index as usize,
+                        position_ms,
+                        leader_time,
+                        &playback,
+                        config,
+                    ),
+                    _ => {}
+                },
+                Err(e) => report_undecodable(src, &e, &mut incompatible),
+            },
+            Err(ref e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(e) => eprintln!("Error receiving: {}", e),
         }
-        3 => utils::synchronized_action("s", timestamp, sink),
-        4 => utils::synchronized_action("r", timestamp, sink),
-        _ => {}
     }
 }
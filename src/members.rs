@@ -0,0 +1,311 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::track::Availability;
+
+/// How many of a member's latest round trips are kept.
+const ROUND_TRIP_WINDOW: usize = 8;
+
+/// What the leader knows about a single member.
+#[derive(Debug, Clone)]
+pub struct MemberInfo {
+    /// When the last packet from this member arrived.
+    pub last_seen: Instant,
+    /// What the member reported about the tracks it has checked so far, by content hash. Playlist
+    /// indices shift when the playlist is edited, the hash of a recording does not.
+    pub tracks: HashMap<u64, Availability>,
+    /// The output latency the member compensates for, once it has reported it.
+    pub latency: Option<Duration>,
+    /// The latest round trips to the member, measured with the leader's time requests.
+    pub round_trips: VecDeque<Duration>,
+}
+
+impl MemberInfo {
+    /// Returns the longest of the latest round trips, so a member with a jittery connection is
+    /// given time for its slow packets too.
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trips.iter().max().copied()
+    }
+}
+
+/// The leader's registry of the members taking part in the session.
+///
+/// Every packet from a member counts as a sign of life. Members that stay silent for longer than
+/// the configured timeout are considered dead and evicted, so the leader stops sending to them.
+#[derive(Debug, Default)]
+pub struct Members {
+    members: HashMap<SocketAddr, MemberInfo>,
+}
+
+impl Members {
+    /// Registers a member, returning `true` if it was not known yet.
+    ///
+    /// A member re-sends JOIN until it has the session, so a known member only counts as seen and
+    /// keeps what was measured about it, e.g. the round trips the start lead depends on.
+    pub fn join(&mut self, addr: SocketAddr) -> bool {
+        match self.members.entry(addr) {
+            Entry::Occupied(mut member) => {
+                member.get_mut().last_seen = Instant::now();
+                false
+            }
+            Entry::Vacant(member) => {
+                member.insert(MemberInfo {
+                    last_seen: Instant::now(),
+                    tracks: HashMap::new(),
+                    latency: None,
+                    round_trips: VecDeque::new(),
+                });
+                true
+            }
+        }
+    }
+
+    /// Records a sign of life from a member, returning `false` if the member is not known.
+    pub fn touch(&mut self, addr: SocketAddr) -> bool {
+        match self.members.get_mut(&addr) {
+            Some(member) => {
+                member.last_seen = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records whether a member can play the track with content hash `hash`, returning `false` if
+    /// the member is not known.
+    pub fn set_availability(
+        &mut self,
+        addr: SocketAddr,
+        hash: u64,
+        availability: Availability,
+    ) -> bool {
+        match self.members.get_mut(&addr) {
+            Some(member) => {
+                member.tracks.insert(hash, availability);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records the output latency a member compensates for, returning `false` if the member is not
+    /// known.
+    pub fn set_latency(&mut self, addr: SocketAddr, latency: Duration) -> bool {
+        match self.members.get_mut(&addr) {
+            Some(member) => {
+                member.latency = Some(latency);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a round trip to a member, returning `false` if the member is not known.
+    pub fn add_round_trip(&mut self, addr: SocketAddr, round_trip: Duration) -> bool {
+        match self.members.get_mut(&addr) {
+            Some(member) => {
+                if member.round_trips.len() == ROUND_TRIP_WINDOW {
+                    member.round_trips.pop_front();
+                }
+                member.round_trips.push_back(round_trip);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns how long before an action is carried out it has to be broadcast.
+    ///
+    /// Every member has to receive the action, and then acts its output latency ahead of the start
+    /// time, as does the leader with `own_latency`. The lead covers the slowest member's round trip
+    /// plus its latency and the configured margin, within the configured bounds. Members whose
+    /// round trip has not been measured yet are left out; if they are late, they catch up with a
+    /// seek.
+    pub fn start_lead(&self, own_latency: Duration, config: &Config) -> Duration {
+        let slowest = self
+            .members
+            .values()
+            .filter_map(|member| {
+                let round_trip = member.round_trip()?;
+                Some(round_trip + member.latency.unwrap_or_default())
+            })
+            .fold(own_latency, Duration::max);
+
+        (slowest + config.start_lead_margin)
+            .max(config.start_lead_min)
+            .min(config.start_lead_max)
+    }
+
+    /// Returns every member with what it has reported, ordered by address.
+    pub fn reports(&self) -> Vec<(SocketAddr, MemberInfo)> {
+        let mut reports: Vec<(SocketAddr, MemberInfo)> = self
+            .members
+            .iter()
+            .map(|(addr, member)| (*addr, member.clone()))
+            .collect();
+        reports.sort_by_key(|(addr, _)| *addr);
+        reports
+    }
+
+    /// Removes a member, returning `true` if it was known.
+    pub fn leave(&mut self, addr: SocketAddr) -> bool {
+        self.members.remove(&addr).is_some()
+    }
+
+    /// Removes and returns the members that have not been seen for `timeout` as of `now`.
+    pub fn evict_stale(&mut self, now: Instant, timeout: Duration) -> Vec<SocketAddr> {
+        let stale: Vec<SocketAddr> = self
+            .members
+            .iter()
+            .filter(|(_, member)| now.saturating_duration_since(member.last_seen) >= timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in &stale {
+            self.members.remove(addr);
+        }
+
+        stale
+    }
+
+    /// Returns the addresses of all members.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.members.keys().copied().collect()
+    }
+
+    /// Returns the number of members.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 10], port))
+    }
+
+    #[test]
+    fn test_join_and_leave() {
+        let mut members = Members::default();
+
+        assert!(members.join(addr(1)));
+        assert!(!members.join(addr(1)));
+        assert_eq!(members.len(), 1);
+
+        assert!(members.leave(addr(1)));
+        assert!(!members.leave(addr(1)));
+        assert_eq!(members.len(), 0);
+    }
+
+    #[test]
+    fn test_repeated_join_keeps_measurements() {
+        let mut members = Members::default();
+        members.join(addr(1));
+        members.add_round_trip(addr(1), Duration::from_millis(40));
+        members.set_latency(addr(1), Duration::from_millis(150));
+
+        assert!(!members.join(addr(1)));
+        let (_, member) = &members.reports()[0];
+        assert_eq!(member.round_trip(), Some(Duration::from_millis(40)));
+        assert_eq!(member.latency, Some(Duration::from_millis(150)));
+    }
+
+    #[test]
+    fn test_touch_unknown_member() {
+        let mut members = Members::default();
+
+        assert!(!members.touch(addr(1)));
+        assert_eq!(members.len(), 0);
+    }
+
+    #[test]
+    fn test_evict_stale_members() {
+        let mut members = Members::default();
+        members.join(addr(1));
+        members.join(addr(2));
+        let timeout = Duration::from_secs(5);
+
+        assert!(members.evict_stale(Instant::now(), timeout).is_empty());
+
+        // Only the member that keeps sending survives
+        let later = Instant::now() + Duration::from_secs(4);
+        members.members.get_mut(&addr(2)).unwrap().last_seen = later;
+        let evicted = members.evict_stale(later + Duration::from_secs(2), timeout);
+
+        assert_eq!(evicted, vec![addr(1)]);
+        assert_eq!(members.addrs(), vec![addr(2)]);
+    }
+
+    #[test]
+    fn test_track_availability() {
+        let mut members = Members::default();
+        members.join(addr(2));
+        members.join(addr(1));
+
+        assert!(!members.set_availability(addr(3), 0, Availability::Playable));
+        assert!(members.set_availability(addr(2), 0, Availability::Playable));
+        assert!(members.set_availability(addr(2), 1, Availability::Missing));
+        assert!(members.set_availability(addr(2), 1, Availability::Mismatch));
+
+        assert!(members.set_latency(addr(1), Duration::from_millis(150)));
+        assert!(!members.set_latency(addr(3), Duration::from_millis(150)));
+
+        let reports = members.reports();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].0, addr(1));
+        assert_eq!(reports[0].1.tracks, HashMap::new());
+        assert_eq!(reports[0].1.latency, Some(Duration::from_millis(150)));
+        assert_eq!(reports[1].0, addr(2));
+        assert_eq!(reports[1].1.tracks.get(&1), Some(&Availability::Mismatch));
+        assert_eq!(reports[1].1.tracks.len(), 2);
+        assert_eq!(reports[1].1.latency, None);
+    }
+
+    #[test]
+    fn test_start_lead_follows_slowest_member() {
+        let mut members = Members::default();
+        let config = Config::default();
+        assert_eq!(
+            members.start_lead(Duration::ZERO, &config),
+            config.start_lead_min
+        );
+
+        members.join(addr(1));
+        members.join(addr(2));
+        assert!(members.add_round_trip(addr(1), Duration::from_millis(30)));
+        assert!(members.add_round_trip(addr(1), Duration::from_millis(400)));
+        assert!(members.add_round_trip(addr(2), Duration::from_millis(250)));
+        assert!(!members.add_round_trip(addr(3), Duration::from_millis(900)));
+        members.set_latency(addr(2), Duration::from_millis(200));
+
+        // Member 2's round trip and output latency outweigh member 1's slowest round trip
+        assert_eq!(
+            members.start_lead(Duration::ZERO, &config),
+            Duration::from_millis(450) + config.start_lead_margin
+        );
+        assert_eq!(
+            members.start_lead(Duration::from_millis(500), &config),
+            Duration::from_millis(500) + config.start_lead_margin
+        );
+        assert_eq!(
+            members.start_lead(Duration::from_secs(5), &config),
+            config.start_lead_max
+        );
+
+        // Old round trips drop out of the window
+        for _ in 0..ROUND_TRIP_WINDOW {
+            members.add_round_trip(addr(1), Duration::from_millis(10));
+        }
+        assert_eq!(
+            members.reports()[0].1.round_trip(),
+            Some(Duration::from_millis(10))
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What happens when a track ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Repeat {
+    /// Play the playlist once; after the last track the playback stops at the first one.
+    #[default]
+    Off,
+    /// Start over with the first track after the last one.
+    All,
+    /// Play the current track again and again.
+    One,
+}
+
+impl Repeat {
+    /// The mode a plain `repeat` command switches to: off, all, one and back to off.
+    pub fn cycle(self) -> Repeat {
+        match self {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        }
+    }
+
+    /// Parses `off`, `all` or `one`.
+    pub fn from_name(name: &str) -> Option<Repeat> {
+        match name.trim() {
+            "off" => Some(Repeat::Off),
+            "all" => Some(Repeat::All),
+            "one" => Some(Repeat::One),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Repeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Repeat::Off => "off",
+            Repeat::All => "all",
+            Repeat::One => "one",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The order the playlist is played in.
+///
+/// Shuffling does not change the playlist itself, so tracks keep their numbers. Instead the
+/// leader picks a seed and broadcasts it, and every peer derives the same shuffled order from it
+/// (see `Sequence`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PlayOrder {
+    /// Seed of the shuffled order, or `None` to play the playlist from top to bottom.
+    pub shuffle_seed: Option<u64>,
+    pub repeat: Repeat,
+}
+
+impl PlayOrder {
+    /// Returns the playlist indices in the order a fresh sequence plays them.
+    fn sequence(&self, track_count: usize) -> Vec<usize> {
+        let mut sequence: Vec<usize> = (0..track_count).collect();
+        if let Some(seed) = self.shuffle_seed {
+            // Fisher-Yates, driven by a generator every peer seeds the same way
+            let mut state = seed;
+            for i in (1..track_count).rev() {
+                let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+                sequence.swap(i, j);
+            }
+        }
+        sequence
+    }
+}
+
+/// A play order applied to the playlist: the playlist indices in the order they are played.
+///
+/// A shuffled sequence is derived from the seed when shuffling is turned on, and kept from then
+/// on. A track added to the playlist is shuffled in somewhere after the current track, a removed
+/// track leaves the sequence without disturbing the others, and a moved track keeps its turn. The
+/// leader broadcasts the sequence along with every edited playlist, so all peers play the same one.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Sequence {
+    order: PlayOrder,
+    indices: Vec<usize>,
+}
+
+impl Sequence {
+    /// Returns the sequence `order` starts out with on a playlist of `track_count` tracks.
+    pub fn new(order: PlayOrder, track_count: usize) -> Sequence {
+        Sequence {
+            order,
+            indices: order.sequence(track_count),
+        }
+    }
+
+    /// Returns the sequence the leader broadcast as `indices`, or a fresh one if they are not an
+    /// ordering of all `track_count` tracks.
+    pub fn from_indices(order: PlayOrder, indices: &[u32], track_count: usize) -> Sequence {
+        let indices: Vec<usize> = indices.iter().map(|index| *index as usize).collect();
+        let mut sorted = indices.clone();
+        sorted.sort_unstable();
+        if !sorted.into_iter().eq(0..track_count) {
+            return Sequence::new(order, track_count);
+        }
+        Sequence { order, indices }
+    }
+
+    pub fn order(&self) -> PlayOrder {
+        self.order
+    }
+
+    /// Returns the playlist indices in the order they are played, as they are broadcast.
+    pub fn indices(&self) -> Vec<u32> {
+        self.indices.iter().map(|index| *index as u32).collect()
+    }
+
+    /// Switches to `order`. The sequence is only reshuffled if the shuffle seed changes.
+    pub fn set_order(&mut self, order: PlayOrder) {
+        if order.shuffle_seed != self.order.shuffle_seed {
+            self.indices = order.sequence(self.indices.len());
+        }
+        self.order = order;
+    }
+
+    /// Follows the insertion of a track at playlist index `index`, while the track at `current`
+    /// (numbered as before the insertion) plays. A shuffled sequence plays the new track at a
+    /// random turn after the current track.
+    pub fn insert(&mut self, index: usize, current: usize) {
+        let current = if current >= index {
+            current + 1
+        } else {
+            current
+        };
+        for i in &mut self.indices {
+            if *i >= index {
+                *i += 1;
+            }
+        }
+        let Some(seed) = self.order.shuffle_seed else {
+            self.indices.insert(index, index);
+            return;
+        };
+
+        let after = self
+            .indices
+            .iter()
+            .position(|i| *i == current)
+            .map_or(0, |position| position + 1);
+        let mut state = seed ^ ((self.indices.len() as u64) << 32) ^ index as u64;
+        let turns = (self.indices.len() - after + 1) as u64;
+        let position = after + (splitmix64(&mut state) % turns) as usize;
+        self.indices.insert(position, index);
+    }
+
+    /// Follows the removal of the track at playlist index `index`.
+    pub fn remove(&mut self, index: usize) {
+        self.indices.retain(|i| *i != index);
+        for i in &mut self.indices {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+    }
+
+    /// Follows the move of the track at playlist index `from` to `to`.
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        if self.order.shuffle_seed.is_none() {
+            return;
+        }
+        for i in &mut self.indices {
+            *i = match *i {
+                i if i == from => to,
+                mut i => {
+                    if from < i {
+                        i -= 1;
+                    }
+                    if to <= i {
+                        i += 1;
+                    }
+                    i
+                }
+            };
+        }
+    }
+
+    /// Returns the track the playback starts with, e.g. after the end of the playlist.
+    pub fn first(&self) -> usize {
+        self.indices.first().copied().unwrap_or(0)
+    }
+
+    /// Returns the track that plays when the track at `index` ends, or `None` at the end of the
+    /// playlist.
+    pub fn next(&self, index: usize) -> Option<usize> {
+        match self.order.repeat {
+            Repeat::One => Some(index),
+            repeat => self.following(index, repeat == Repeat::All),
+        }
+    }
+
+    /// Returns the track a `next` command skips to. Unlike the end of a track, skipping leaves a
+    /// repeated track behind, and wraps around to the start as with `Repeat::All`.
+    pub fn skip(&self, index: usize) -> Option<usize> {
+        self.following(index, self.order.repeat != Repeat::Off)
+    }
+
+    /// Returns the track a `previous` command goes back to, or the first track itself.
+    pub fn previous(&self, index: usize) -> usize {
+        let sequence = &self.indices;
+        match sequence.iter().position(|i| *i == index) {
+            Some(position) => sequence[position.saturating_sub(1)],
+            None => index,
+        }
+    }
+
+    fn following(&self, index: usize, wrap: bool) -> Option<usize> {
+        let sequence = &self.indices;
+        let position = sequence.iter().position(|i| *i == index)?;
+        match sequence.get(position + 1) {
+            Some(next) => Some(*next),
+            None if wrap => sequence.first().copied(),
+            None => None,
+        }
+    }
+}
+
+/// Picks a seed for a new shuffled order.
+pub fn new_shuffle_seed() -> u64 {
+    let mut state = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64);
+    splitmix64(&mut state)
+}
+
+/// SplitMix64, a small generator whose output only depends on the seed, on every platform.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shuffled_order_depends_only_on_seed() {
+        let order = PlayOrder {
+            shuffle_seed: Some(42),
+            repeat: Repeat::Off,
+        };
+        let shuffled = Sequence::new(order, 10);
+        let sequence = shuffled.indices.clone();
+
+        // Every peer derives the same permutation of all tracks
+        assert_eq!(shuffled, Sequence::new(order, 10));
+        assert_ne!(sequence, (0..10).collect::<Vec<_>>());
+        let mut sorted = sequence.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+
+        assert_eq!(shuffled.first(), sequence[0]);
+        assert_eq!(shuffled.next(sequence[3]), Some(sequence[4]));
+        assert_eq!(shuffled.previous(sequence[3]), sequence[2]);
+        assert_eq!(shuffled.next(sequence[9]), None);
+    }
+
+    #[test]
+    fn test_edits_keep_the_shuffled_sequence() {
+        let order = PlayOrder {
+            shuffle_seed: Some(42),
+            repeat: Repeat::Off,
+        };
+        let mut sequence = Sequence::new(order, 10);
+        let before = sequence.indices.clone();
+        let current = before[4];
+
+        // The added track comes after the current one, the others keep their turns
+        sequence.insert(10, current);
+        let position = sequence.indices.iter().position(|i| *i == 10).unwrap();
+        assert!(position > 4);
+        let others: Vec<usize> = sequence
+            .indices
+            .iter()
+            .copied()
+            .filter(|i| *i != 10)
+            .collect();
+        assert_eq!(others, before);
+
+        sequence.remove(10);
+        assert_eq!(sequence.indices, before);
+
+        // Removing a track renumbers the tracks after it, in place
+        sequence.remove(0);
+        let expected: Vec<usize> = before.iter().filter(|i| **i != 0).map(|i| i - 1).collect();
+        assert_eq!(sequence.indices, expected);
+
+        // A moved track keeps its turn under its new number
+        let mut moved = Sequence::new(order, 3);
+        let turns = moved.indices.clone();
+        moved.move_track(0, 2);
+        let renumbered: Vec<usize> = turns.iter().map(|i| [2, 0, 1][*i]).collect();
+        assert_eq!(moved.indices, renumbered);
+
+        // A repeat mode keeps the sequence, a new seed reshuffles it
+        sequence.set_order(PlayOrder {
+            repeat: Repeat::All,
+            ..order
+        });
+        assert_eq!(sequence.indices, expected);
+        sequence.set_order(PlayOrder::default());
+        assert_eq!(sequence.indices, (0..9).collect::<Vec<_>>());
+        assert_eq!(
+            Sequence::from_indices(order, &[0, 0, 1], 3),
+            Sequence::new(order, 3)
+        );
+    }
+
+    #[test]
+    fn test_repeat_modes() {
+        let mut order = PlayOrder::default();
+        let sequence = Sequence::new(order, 3);
+        assert_eq!(sequence.next(2), None);
+        assert_eq!(sequence.skip(2), None);
+        assert_eq!(sequence.previous(0), 0);
+
+        order.repeat = Repeat::All;
+        assert_eq!(Sequence::new(order, 3).next(2), Some(0));
+
+        // A repeated track plays again when it ends, but can still be skipped
+        order.repeat = Repeat::One;
+        let sequence = Sequence::new(order, 3);
+        assert_eq!(sequence.next(1), Some(1));
+        assert_eq!(sequence.skip(2), Some(0));
+
+        assert_eq!(Repeat::One.cycle(), Repeat::Off);
+        assert_eq!(Repeat::from_name("all"), Some(Repeat::All));
+    }
+}
@@ -1,64 +1,432 @@
 use rodio::{Decoder, Sink, Source};
+use std::collections::HashMap;
 use std::fs;
 use std::io::{BufReader, Write};
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use crate::track::Track;
+use crate::clock::ClockSync;
+use crate::library::{self, FileStamp, LibraryIndex};
+use crate::loudness::{self, Gain, GainMode};
+use crate::order::Sequence;
+use crate::scheduler::Scheduler;
+use crate::session_log::SessionLog;
+use crate::stream::{fetch_track, StreamBuffer, StreamFormat, StreamedSource};
+use crate::tags;
+use crate::track::{self, AudioFormat, Availability, Track};
+use crate::transition::{self, Audio, Crossfade, Playhead, TrackSource};
 use crate::utils::duration_to_minutes_seconds;
 
-pub fn load_audio_files(media_dir: &str, tracks: &mut Vec<Track>) {
+/// Shared playback state handed to every thread that drives the sink.
+#[derive(Clone)]
+pub struct Playback {
+    pub sink: Arc<Mutex<Sink>>,
+    /// The playlist, shared by every thread so edits during playback reach all of them.
+    pub tracks: Arc<Mutex<Vec<Track>>>,
+    pub current_track_index: Arc<Mutex<usize>>,
+    pub should_reset: Arc<Mutex<bool>>,
+    pub clock: Arc<Mutex<ClockSync>>,
+    pub audio: AudioSource,
+    pub volume: Arc<Mutex<Volume>>,
+    /// Shuffle and repeat mode, and the sequence they play the playlist in, the same on every
+    /// peer.
+    pub order: Arc<Mutex<Sequence>>,
+    /// Position in the current track, kept up to date by the source that plays it.
+    pub playhead: Arc<Playhead>,
+    /// How long consecutive tracks are crossfaded, the same on every peer. Zero plays them
+    /// gaplessly.
+    pub crossfade: Duration,
+    /// Output latency of this device, which actions are carried out ahead of (see
+    /// `Config::output_latency`).
+    pub output_latency: Arc<Mutex<Duration>>,
+    /// Which ReplayGain the tracks are played with, the same on every peer.
+    pub gain_mode: GainMode,
+    /// Where queued tracks report that they ended, with the queue generation they were queued in
+    /// (see `utils::start_track_transition_thread`).
+    pub track_ends: mpsc::Sender<u64>,
+    /// Counts how often the queue was rebuilt, so ends of tracks that were cleared from the queue
+    /// are recognized.
+    pub queue_generation: Arc<Mutex<u64>>,
+    /// Where this peer logs how late it carried out actions.
+    pub log: Arc<Mutex<SessionLog>>,
+    /// Carries out actions and playlist edits at their target time.
+    pub scheduler: Scheduler,
+}
+
+/// Percentage points a single volume up/down command changes the volume by.
+pub const VOLUME_STEP: u8 = 10;
+
+/// Playback volume in percent: the level shared by the whole session, and a private trim that only
+/// applies to this peer, e.g. to balance a loud speaker against the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Volume {
+    pub level: u8,
+    pub trim: u8,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Volume {
+            level: 100,
+            trim: 100,
+        }
+    }
+}
+
+impl Volume {
+    /// The factor the sink's volume is set to.
+    pub fn effective(&self) -> f32 {
+        self.level as f32 / 100.0 * self.trim as f32 / 100.0
+    }
+}
+
+/// Sets the session volume level or this peer's trim, and applies the result to the sink.
+pub fn set_volume(playback: &Playback, level: Option<u8>, trim: Option<u8>) {
+    let mut volume = playback.volume.lock().unwrap();
+    if let Some(level) = level {
+        volume.level = level.min(100);
+    }
+    if let Some(trim) = trim {
+        volume.trim = trim.min(100);
+    }
+    playback.sink.lock().unwrap().set_volume(volume.effective());
+}
+
+/// Loads every supported audio file (MP3, FLAC, WAV, OGG, AAC/M4A) below the media directory.
+///
+/// Subdirectories are scanned recursively, so the media directory can be organized by artist or
+/// album. Files that cannot be decoded are reported and skipped. The tracks are sorted in album
+/// order (see `Track`'s `Ord` impl).
+///
+/// What was found out about each file is kept in the library index in the media directory, so
+/// only new and changed files have to be decoded and hashed on the next start. `rebuild_index`
+/// ignores the stored index and scans every file again. Tracks without ReplayGain tags get their
+/// loudness measured during the scan (see `loudness::measure`).
+pub fn load_audio_files(media_dir: &Path, rebuild_index: bool, tracks: &mut Vec<Track>) {
     let entries = fs::read_dir(media_dir).expect("Failed to read media directory");
+    let mut files = Vec::new();
+    scan_directory(entries, &mut files);
 
-    for entry in entries {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-            if let Some(extension) = path.extension() {
-                if extension == "mp3" {
-                    let track = create_track(&path);
-                    tracks.push(track);
+    let index_path = media_dir.join(library::INDEX_FILE);
+    let known = if rebuild_index {
+        LibraryIndex::default()
+    } else {
+        LibraryIndex::load(&index_path)
+    };
+    let mut index = LibraryIndex::default();
+    let mut scanned = 0;
+    for (path, format) in files {
+        let stamp = match FileStamp::of(&path) {
+            Ok(stamp) => stamp,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let track = match known.lookup(&path, format, stamp) {
+            Some(track) => track,
+            None => {
+                scanned += 1;
+                match create_track(&path, format) {
+                    Ok(track) => track,
+                    Err(e) => {
+                        eprintln!("Skipping {}: {}", path.display(), e);
+                        continue;
+                    }
                 }
             }
+        };
+        index.insert(&track, stamp);
+        tracks.push(track);
+    }
+    println!(
+        "Found {} tracks, {} of them in the library index",
+        tracks.len(),
+        tracks.len().saturating_sub(scanned)
+    );
+
+    // The index only needs to be written if files were added, changed or removed
+    if scanned > 0 || index.len() != known.len() {
+        if let Err(e) = index.save(&index_path) {
+            eprintln!(
+                "Failed to save the library index {}: {}",
+                index_path.display(),
+                e
+            );
         }
     }
 
+    // Album gains are derived from the whole library, so they are not kept in the index
+    loudness::fill_album_gains(tracks);
     tracks.sort();
 }
 
+/// Collects the audio files of a directory and its subdirectories.
+///
+/// Hidden entries are skipped, and symlinked directories are not followed, so links pointing back
+/// up the tree cannot cause an endless scan.
+fn scan_directory(entries: fs::ReadDir, files: &mut Vec<(PathBuf, AudioFormat)>) {
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            match fs::read_dir(&path) {
+                Ok(entries) => scan_directory(entries, files),
+                Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+            }
+        } else if let Some(format) = AudioFormat::from_path(&path) {
+            files.push((path, format));
+        }
+    }
+}
+
 /// Creates a Track data structure from the given path.
-fn create_track(path: &std::path::Path) -> Track {
+///
+/// Some containers do not announce their length (e.g. raw AAC streams), so their duration is
+/// measured by decoding the whole file. Files without a ReplayGain track gain are decoded as well,
+/// to measure their loudness.
+fn create_track(path: &Path, format: AudioFormat) -> Result<Track, String> {
     let file_name = path.file_stem().unwrap().to_string_lossy().to_string();
-    let file = BufReader::new(fs::File::open(&path).expect("Failed to open file"));
-    let source = Decoder::new(file).expect("Failed to decode audio file");
-    let duration = source.total_duration().expect("Failed to get duration");
+    let file = BufReader::new(fs::File::open(path).map_err(|e| e.to_string())?);
+    let source = Decoder::new(file).map_err(|e| e.to_string())?;
+    let mut tags = tags::read_tags(path);
+    let announced_duration = source.total_duration();
+    let duration = if tags.replay_gain.track.is_none() {
+        let (loudness, measured_duration) = loudness::measure(source);
+        tags.replay_gain.track = loudness.map(Gain::from_loudness);
+        announced_duration.unwrap_or(measured_duration)
+    } else {
+        match announced_duration {
+            Some(duration) => duration,
+            None => {
+                let samples_per_second = source.sample_rate() as f64 * source.channels() as f64;
+                Duration::from_secs_f64(source.count() as f64 / samples_per_second)
+            }
+        }
+    };
 
-    Track {
+    Ok(Track {
         name: file_name,
+        path: path.to_path_buf(),
+        format,
         duration,
+        tags,
+        hash: track::hash_file(path).map_err(|e| e.to_string())?,
+    })
+}
+
+/// Where the audio of the playlist comes from.
+#[derive(Clone)]
+pub enum AudioSource {
+    /// The files in the leader's media directory.
+    Local,
+    /// The leader's stream server. The downloads of the tracks around the queued ones are kept by
+    /// content hash, so neither rebuilding the sink queue nor editing the playlist fetches them
+    /// again (see `forget_downloads`). The outcome of checking every finished download against the
+    /// track's hash is sent to `verified` along with the hash.
+    Streamed {
+        stream_addr: SocketAddr,
+        buffers: Arc<Mutex<HashMap<u64, Arc<StreamBuffer>>>>,
+        verified: mpsc::Sender<(u64, Availability)>,
+        format: StreamFormat,
+    },
+}
+
+impl AudioSource {
+    pub fn streamed(stream_addr: SocketAddr, verified: mpsc::Sender<(u64, Availability)>) -> Self {
+        AudioSource::Streamed {
+            stream_addr,
+            buffers: Arc::new(Mutex::new(HashMap::new())),
+            verified,
+            format: StreamFormat::of_default_output(),
+        }
+    }
+
+    /// Drops the downloads of every track but the ones in `kept`, so a session does not keep every
+    /// track it played in memory. Sources that still play a dropped track keep its download until
+    /// they end.
+    fn forget_downloads(&self, kept: &[u64]) {
+        if let AudioSource::Streamed { buffers, .. } = self {
+            buffers
+                .lock()
+                .unwrap()
+                .retain(|hash, _| kept.contains(hash));
+        }
+    }
+
+    /// Opens the audio of a track.
+    fn open(&self, track: &Track) -> Result<Audio, String> {
+        match self {
+            AudioSource::Local => {
+                let file = fs::File::open(&track.path).map_err(|e| e.to_string())?;
+                let source = Decoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+                Ok(Box::new(source))
+            }
+            AudioSource::Streamed {
+                stream_addr,
+                buffers,
+                verified,
+                format,
+            } => {
+                // Every track is downloaded on its own thread, so the sink can be filled without
+                // waiting for the files to arrive
+                let hash = track.hash;
+                let buffer = Arc::clone(buffers.lock().unwrap().entry(hash).or_insert_with(|| {
+                    let verified = verified.clone();
+                    fetch_track(*stream_addr, hash, move |availability| {
+                        let _ = verified.send((hash, availability));
+                    })
+                }));
+                Ok(Box::new(StreamedSource::new(
+                    buffer,
+                    track.duration,
+                    *format,
+                )))
+            }
+        }
+    }
+}
+
+/// Appends the track at `track_index` to the sink, starting at `position`, and the track that
+/// follows it in `order`.
+///
+/// The sink only ever holds these two tracks, so the next one is ready to start the moment the
+/// current one ends. The track after them is queued when the current track ends (see
+/// `utils::start_track_transition_thread`). With a crossfade, the next track starts at the end of
+/// its fade-in, since that part is already mixed into the current track.
+pub fn queue_tracks(
+    playback: &Playback,
+    sink: &Sink,
+    tracks: &[Track],
+    order: &Sequence,
+    track_index: usize,
+    position: Duration,
+) {
+    queue_track(playback, sink, tracks, order, track_index, position);
+    if let Some(next) = order.next(track_index) {
+        let fade_in =
+            transition::crossfade_length(playback.crossfade, &tracks[track_index], &tracks[next]);
+        queue_track(playback, sink, tracks, order, next, fade_in);
     }
 }
 
-// function to add tracks to the sink
-pub fn add_tracks_to_sink(media_dir: &str, sink: Arc<Mutex<Sink>>, tracks: &Vec<Track>) {
-    for track in tracks.iter() {
-        let path = format!("{}/{}.mp3", media_dir, track.name);
-        let file = BufReader::new(fs::File::open(&path).expect("Failed to open file"));
-        let source = Decoder::new(file).expect("Failed to decode audio file");
-        sink.lock().unwrap().append(source);
+/// Appends a single track to the sink, starting at `position`, with the start of the track that
+/// follows it in `order` mixed into its end.
+///
+/// The track is moved to `position` before it is appended, since a seek on the sink itself could
+/// still hit a source that is about to be cleared from the queue.
+pub fn queue_track(
+    playback: &Playback,
+    sink: &Sink,
+    tracks: &[Track],
+    order: &Sequence,
+    track_index: usize,
+    position: Duration,
+) {
+    let Some(track) = tracks.get(track_index) else {
+        return;
+    };
+    // The track before this one may still be playing, and the two after it are queued next
+    let before = order.previous(track_index);
+    let after = order.next(track_index);
+    let after_next = after.and_then(|after| order.next(after));
+    let kept: Vec<u64> = [Some(before), Some(track_index), after, after_next]
+        .into_iter()
+        .flatten()
+        .map(|index| tracks[index].hash)
+        .collect();
+    playback.audio.forget_downloads(&kept);
+
+    let mut audio = match open_normalized(playback, track) {
+        Ok(audio) => audio,
+        Err(e) => {
+            eprintln!("Failed to open track {}: {}", track.name, e);
+            return;
+        }
+    };
+    if !position.is_zero() {
+        if let Err(e) = audio.try_seek(position) {
+            eprintln!("Failed to seek in track {}: {}", track.name, e);
+        }
+    }
+
+    let mut crossfade = None;
+    if let Some(next) = order.next(track_index) {
+        let length = transition::crossfade_length(playback.crossfade, track, &tracks[next]);
+        if !length.is_zero() {
+            match open_normalized(playback, &tracks[next]) {
+                Ok(incoming) => {
+                    crossfade = Some(Crossfade {
+                        starts_at: track.duration.saturating_sub(length),
+                        length,
+                        incoming,
+                    })
+                }
+                Err(e) => eprintln!("Failed to open track {}: {}", tracks[next].name, e),
+            }
+        }
     }
 
-    print_playlist(tracks);
+    let track_ends = playback.track_ends.clone();
+    let generation = *playback.queue_generation.lock().unwrap();
+    sink.append(TrackSource::new(
+        audio,
+        position,
+        Arc::clone(&playback.playhead),
+        crossfade,
+        move || {
+            // The receiver is gone once the session is over
+            let _ = track_ends.send(generation);
+        },
+    ));
+}
+
+/// Opens the audio of a track, with its ReplayGain applied.
+///
+/// The gain comes with the playlist from the leader, so every peer plays a track equally loud.
+/// Samples that a positive gain pushes beyond full scale are clipped.
+fn open_normalized(playback: &Playback, track: &Track) -> Result<Audio, String> {
+    let audio = playback.audio.open(track)?;
+    Ok(match track.tags.replay_gain.gain(playback.gain_mode) {
+        Some(gain) => Box::new(audio.amplify(gain.factor())),
+        None => audio,
+    })
 }
 
-fn print_playlist(tracks: &[Track]) {
+/// Queues the track at `first_index` and the one after it in the order, and prints the playlist.
+///
+/// Streamed tracks play silence while their download falls behind the playback position.
+pub fn add_tracks_to_sink(playback: &Playback, first_index: usize) {
+    let sink = playback.sink.lock().unwrap();
+    let tracks = playback.tracks.lock().unwrap();
+    let order = playback.order.lock().unwrap();
+    queue_tracks(
+        playback,
+        &sink,
+        &tracks,
+        &order,
+        first_index,
+        Duration::ZERO,
+    );
+
+    print_playlist(&tracks);
+}
+
+pub fn print_playlist(tracks: &[Track]) {
     println!("\nPlaylist:");
     for (i, track) in tracks.iter().enumerate() {
         println!(
-            "\t{}: {} ({})",
+            "\t{}: {} ({}, {})",
             i + 1,
-            track.name,
-            duration_to_minutes_seconds(track.duration.as_secs())
+            track.display_name(),
+            duration_to_minutes_seconds(track.duration.as_secs()),
+            track.format
         );
     }
     println!("\n");
@@ -67,14 +435,21 @@ fn print_playlist(tracks: &[Track]) {
 /// Displays the progress of the current track in the sink.
 pub fn display_progress(
     sink: Arc<Mutex<Sink>>,
-    tracks: Vec<Track>,
+    playhead: Arc<Playhead>,
+    tracks: Arc<Mutex<Vec<Track>>>,
     current_track_index: Arc<Mutex<usize>>,
     should_reset: Arc<Mutex<bool>>,
+    volume: Arc<Mutex<Volume>>,
 ) {
     thread::spawn(move || loop {
         let track_index = *current_track_index.lock().unwrap();
-        let track_name = &tracks[track_index].name;
-        let track_duration = tracks[track_index].duration;
+        let (track_name, track_duration) = match tracks.lock().unwrap().get(track_index) {
+            Some(track) => (track.display_name(), track.duration),
+            None => {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
 
         loop {
             if *should_reset.lock().unwrap() {
@@ -82,39 +457,181 @@ pub fn display_progress(
                 break;
             }
 
-            let position = sink.lock().unwrap().get_pos();
-            display_progress_bar(&sink, track_name, track_duration, position);
+            let position = playhead.get();
+            let current_volume = *volume.lock().unwrap();
+            display_progress_bar(&sink, &track_name, track_duration, position, current_volume);
 
             thread::sleep(Duration::from_millis(100));
         }
     });
 }
 
+/// Draws `width` characters of a progress bar at `position` of a track of `track_duration`.
+///
+/// Tracks end when their audio runs out, which can be after the duration their metadata announces
+/// (e.g. for VBR MP3 files), so the bar stays full once the position overruns the duration. A track
+/// without a known duration shows an empty bar.
+fn progress_bar(position: Duration, track_duration: Duration, width: usize) -> String {
+    let progress = if track_duration.is_zero() {
+        0.0
+    } else {
+        (position.as_secs_f64() / track_duration.as_secs_f64()).clamp(0.0, 1.0)
+    };
+    let filled = (progress * width as f64).round() as usize;
+    format!(
+        "{}{}",
+        "=".repeat(filled),
+        " ".repeat(width.saturating_sub(filled))
+    )
+}
+
 /// Displays a progress bar for the current track in the Sink.
 fn display_progress_bar(
     sink: &Arc<Mutex<Sink>>,
     track_name: &str,
     track_duration: Duration,
     position: Duration,
+    volume: Volume,
 ) {
-    let bar_width = 50;
-    let progress = position.as_secs_f64() / track_duration.as_secs_f64();
-    let filled = (progress * bar_width as f64).round() as usize;
-    let empty = bar_width - filled;
+    // The trim is private to this peer, so it is only shown when it is in use
+    let trim = if volume.trim < 100 {
+        format!(" (trim {}%)", volume.trim)
+    } else {
+        String::new()
+    };
 
     print!(
-        "\r{}: {} [{}{}] {} / {}\t",
+        "\r{}: {} [{}] {} / {} Vol {}%{}\t",
         if sink.lock().unwrap().is_paused() {
             "Paused"
         } else {
             "Playing"
         },
         track_name,
-        "=".repeat(filled),
-        " ".repeat(empty),
+        progress_bar(position, track_duration, 50),
         duration_to_minutes_seconds(position.as_secs()),
         duration_to_minutes_seconds(track_duration.as_secs()),
+        volume.level,
+        trim,
     );
 
     std::io::stdout().flush().unwrap();
 }
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_bar_survives_overrun() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(progress_bar(minute / 2, minute, 10), "=====     ");
+
+        // The audio can run longer than the announced duration, or have no duration at all
+        assert_eq!(progress_bar(minute * 2, minute, 10), "=".repeat(10));
+        assert_eq!(progress_bar(minute, Duration::ZERO, 10), " ".repeat(10));
+    }
+
+    /// A short mono WAV file with a RIFF INFO chunk holding the given tags.
+    fn tagged_wav_bytes(info: &[(&[u8; 4], &str)]) -> Vec<u8> {
+        let mut list = b"INFO".to_vec();
+        for (key, value) in info {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            if value.len() % 2 == 1 {
+                value.push(0);
+            }
+            list.extend_from_slice(*key);
+            list.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            list.extend_from_slice(&value);
+        }
+
+        let samples = vec![0u8; 1600];
+        let mut body = b"WAVEfmt ".to_vec();
+        body.extend_from_slice(&16u32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        body.extend_from_slice(&1u16.to_le_bytes()); // mono
+        body.extend_from_slice(&8000u32.to_le_bytes());
+        body.extend_from_slice(&16000u32.to_le_bytes());
+        body.extend_from_slice(&2u16.to_le_bytes());
+        body.extend_from_slice(&16u16.to_le_bytes());
+        body.extend_from_slice(b"LIST");
+        body.extend_from_slice(&(list.len() as u32).to_le_bytes());
+        body.extend_from_slice(&list);
+        body.extend_from_slice(b"data");
+        body.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        body.extend_from_slice(&samples);
+
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    #[test]
+    fn test_load_audio_files_recursively_with_tags() {
+        let media_dir =
+            std::env::temp_dir().join(format!("syncstream-media-{}", std::process::id()));
+        let album_dir = media_dir.join("Band").join("Album");
+        fs::create_dir_all(&album_dir).unwrap();
+        fs::create_dir_all(media_dir.join(".hidden")).unwrap();
+        fs::write(
+            album_dir.join("a.wav"),
+            tagged_wav_bytes(&[
+                (b"INAM", "Outro"),
+                (b"IART", "Band"),
+                (b"IPRD", "Album"),
+                (b"IPRT", "2"),
+                (b"ICRD", "2019-05-01"),
+            ]),
+        )
+        .unwrap();
+        fs::write(
+            album_dir.join("b.wav"),
+            tagged_wav_bytes(&[(b"INAM", "Intro"), (b"IPRD", "Album"), (b"IPRT", "1")]),
+        )
+        .unwrap();
+        fs::write(
+            media_dir.join("loose.wav"),
+            tagged_wav_bytes(&[(b"INAM", "Loose")]),
+        )
+        .unwrap();
+        fs::write(
+            media_dir.join(".hidden").join("c.wav"),
+            tagged_wav_bytes(&[]),
+        )
+        .unwrap();
+        fs::write(media_dir.join("cover.jpg"), b"not audio").unwrap();
+
+        let mut tracks = Vec::new();
+        load_audio_files(&media_dir, false, &mut tracks);
+
+        // The second scan takes the tracks from the index instead of decoding the files again, so
+        // a file with the same size and modification time keeps its indexed tags
+        let loose = media_dir.join("loose.wav");
+        let stamp = FileStamp::of(&loose).unwrap();
+        fs::write(&loose, tagged_wav_bytes(&[(b"INAM", "Other")])).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&loose)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + Duration::from_nanos(stamp.modified_ns as u64))
+            .unwrap();
+        let mut indexed = Vec::new();
+        load_audio_files(&media_dir, false, &mut indexed);
+        assert_eq!(indexed, tracks);
+
+        // Unless the index is rebuilt
+        let mut rebuilt = Vec::new();
+        load_audio_files(&media_dir, true, &mut rebuilt);
+        assert_eq!(rebuilt[0].display_name(), "Other");
+        fs::remove_dir_all(&media_dir).unwrap();
+
+        let names: Vec<String> = tracks.iter().map(Track::display_name).collect();
+        assert_eq!(names, vec!["Loose", "Intro", "Band – Outro"]);
+        assert_eq!(tracks[2].tags.album.as_deref(), Some("Album"));
+        assert_eq!(tracks[2].tags.track_number, Some(2));
+        assert_eq!(tracks[2].tags.year, Some(2019));
+    }
+}
@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::track::Track;
+
+/// Playlist file formats the leader can load and save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Extended M3U, one path per line with `#EXTINF` lines carrying length and title.
+    M3u,
+    /// The INI-style `[playlist]` format with numbered `FileN`, `TitleN` and `LengthN` keys.
+    Pls,
+    /// `{"tracks": [{"path": ..., "title": ...}]}`.
+    Json,
+}
+
+impl PlaylistFormat {
+    /// Detects the format from a file's extension, ignoring case.
+    pub fn from_path(path: &Path) -> Option<PlaylistFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u),
+            "pls" => Some(PlaylistFormat::Pls),
+            "json" => Some(PlaylistFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonPlaylist {
+    tracks: Vec<JsonEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonEntry {
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+}
+
+/// Reads the entries of a playlist file, in playlist order.
+///
+/// Relative entries are resolved against `media_dir`, so a playlist stays valid wherever the media
+/// directory is mounted.
+pub fn load_playlist(path: &Path, media_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let format = PlaylistFormat::from_path(path)
+        .ok_or_else(|| "unknown playlist format, expected .m3u8, .pls or .json".to_string())?;
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let contents = contents.trim_start_matches('\u{feff}');
+
+    let entries = match format {
+        PlaylistFormat::M3u => parse_m3u(contents),
+        PlaylistFormat::Pls => parse_pls(contents)?,
+        PlaylistFormat::Json => serde_json::from_str::<JsonPlaylist>(contents)
+            .map_err(|e| e.to_string())?
+            .tracks
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect(),
+    };
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| media_dir.join(entry))
+        .collect())
+}
+
+fn parse_m3u(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+fn parse_pls(contents: &str) -> Result<Vec<String>, String> {
+    let mut lines = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    if !lines
+        .next()
+        .is_some_and(|header| header.eq_ignore_ascii_case("[playlist]"))
+    {
+        return Err("missing [playlist] header".to_string());
+    }
+
+    // Entries are numbered, and the numbers decide the order rather than the line order
+    let mut files = Vec::new();
+    for line in lines {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let Some(number) = key.trim().strip_prefix("File") else {
+            continue;
+        };
+        let number: u32 = number
+            .parse()
+            .map_err(|_| format!("invalid entry number in `{}`", line))?;
+        files.push((number, value.trim().to_string()));
+    }
+    files.sort_by_key(|(number, _)| *number);
+
+    Ok(files.into_iter().map(|(_, file)| file).collect())
+}
+
+/// Writes `tracks` to a playlist file, in the format given by the file's extension.
+///
+/// Tracks below `media_dir` are written with paths relative to it.
+pub fn save_playlist(path: &Path, tracks: &[Track], media_dir: &Path) -> Result<(), String> {
+    let format = PlaylistFormat::from_path(path)
+        .ok_or_else(|| "unknown playlist format, expected .m3u8, .pls or .json".to_string())?;
+    let entries: Vec<(String, &Track)> = tracks
+        .iter()
+        .map(|track| {
+            let relative = track.path.strip_prefix(media_dir).unwrap_or(&track.path);
+            (relative.to_string_lossy().to_string(), track)
+        })
+        .collect();
+
+    let mut contents = String::new();
+    match format {
+        PlaylistFormat::M3u => {
+            contents.push_str("#EXTM3U\n");
+            for (entry, track) in &entries {
+                let _ = writeln!(
+                    contents,
+                    "#EXTINF:{},{}\n{}",
+                    track.duration.as_secs(),
+                    track.display_name(),
+                    entry
+                );
+            }
+        }
+        PlaylistFormat::Pls => {
+            contents.push_str("[playlist]\n");
+            for (number, (entry, track)) in entries.iter().enumerate() {
+                let number = number + 1;
+                let _ = writeln!(contents, "File{}={}", number, entry);
+                let _ = writeln!(contents, "Title{}={}", number, track.display_name());
+                let _ = writeln!(contents, "Length{}={}", number, track.duration.as_secs());
+            }
+            let _ = writeln!(contents, "NumberOfEntries={}\nVersion=2", entries.len());
+        }
+        PlaylistFormat::Json => {
+            let playlist = JsonPlaylist {
+                tracks: entries
+                    .iter()
+                    .map(|(entry, track)| JsonEntry {
+                        path: entry.clone(),
+                        title: Some(track.display_name()),
+                    })
+                    .collect(),
+            };
+            contents = serde_json::to_string_pretty(&playlist).map_err(|e| e.to_string())?;
+            contents.push('\n');
+        }
+    }
+
+    fs::write(path, contents).map_err(|e| e.to_string())
+}
+
+/// Picks the playlist's entries out of the media library, in playlist order.
+///
+/// Entries that are not part of the library, e.g. because the file was moved or cannot be
+/// decoded, are reported and skipped.
+pub fn select_tracks(entries: &[PathBuf], library: &[Track]) -> Vec<Track> {
+    let by_path: HashMap<PathBuf, &Track> = library
+        .iter()
+        .map(|track| (canonical(&track.path), track))
+        .collect();
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let track = by_path.get(&canonical(entry));
+            if track.is_none() {
+                eprintln!(
+                    "Skipping playlist entry {}: not in the media library",
+                    entry.display()
+                );
+            }
+            track.map(|track| (*track).clone())
+        })
+        .collect()
+}
+
+/// Makes paths comparable no matter how they were written, e.g. `media/../media/a.mp3`.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Tags;
+    use crate::track::AudioFormat;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_m3u_and_pls() {
+        let m3u = "#EXTM3U\n#EXTINF:61,Band – Intro\nBand/01 Intro.mp3\n\n/srv/other.flac\n";
+        assert_eq!(parse_m3u(m3u), vec!["Band/01 Intro.mp3", "/srv/other.flac"]);
+
+        let pls = "[playlist]\nFile2=b.mp3\nTitle2=B\nFile1=a.mp3\nNumberOfEntries=2\nVersion=2\n";
+        assert_eq!(parse_pls(pls).unwrap(), vec!["a.mp3", "b.mp3"]);
+        assert!(parse_pls("File1=a.mp3\n").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_every_format() {
+        let dir = std::env::temp_dir().join(format!("syncstream-playlist-{}", std::process::id()));
+        let media_dir = dir.join("media");
+        let tracks: Vec<Track> = ["Band/02 Outro.flac", "01 Intro.mp3"]
+            .iter()
+            .map(|entry| Track {
+                name: entry.to_string(),
+                path: media_dir.join(entry),
+                format: AudioFormat::Flac,
+                duration: Duration::from_secs(90),
+                tags: Tags::default(),
+                hash: 0,
+            })
+            .collect();
+        fs::create_dir_all(&dir).unwrap();
+
+        for file in ["list.m3u8", "list.pls", "list.json"] {
+            let path = dir.join(file);
+            save_playlist(&path, &tracks, &media_dir).unwrap();
+            let contents = fs::read_to_string(&path).unwrap();
+            let entries = load_playlist(&path, &media_dir).unwrap();
+
+            // Paths are stored relative to the media directory and resolved against it again
+            assert!(contents.contains("Band/02 Outro.flac"), "{}", file);
+            assert!(!contents.contains(media_dir.to_str().unwrap()), "{}", file);
+            assert_eq!(
+                entries,
+                vec![
+                    media_dir.join("Band/02 Outro.flac"),
+                    media_dir.join("01 Intro.mp3")
+                ]
+            );
+            assert_eq!(select_tracks(&entries, &tracks), tracks);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(load_playlist(Path::new("list.txt"), &media_dir).is_err());
+    }
+}
@@ -0,0 +1,1422 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::loudness::{Gain, GainMode, ReplayGain};
+use crate::order::{PlayOrder, Repeat};
+use crate::tags::Tags;
+use crate::track::{AudioFormat, Availability, Track};
+use crate::utils;
+
+/// Magic bytes that open every SyncStream packet.
+pub const MAGIC: [u8; 4] = *b"SYNC";
+
+/// Version of the wire format spoken by this build.
+///
+/// Bump this whenever the layout of an existing message changes. Adding a new message type does
+/// not require a bump, since older peers reject unknown types instead of misparsing them.
+pub const PROTOCOL_VERSION: u8 = 14;
+
+/// Size of the fixed header: magic (4), version (1), message type (1), epoch (4), sequence number
+/// (4).
+pub const HEADER_LEN: usize = 14;
+
+/// Largest payload a single UDP datagram can carry.
+pub const MAX_PACKET_SIZE: usize = 65_507;
+
+static NEXT_SEQUENCE: AtomicU32 = AtomicU32::new(1);
+static EPOCH: OnceLock<u32> = OnceLock::new();
+
+/// How far `+` and `-` seek without an explicit amount.
+const DEFAULT_SEEK_SECONDS: i64 = 10;
+
+/// Playback commands typed on the console of the leader or of a member.
+///
+/// These are only intents: the leader resolves them against its own playback state into an
+/// `Action`, which is what actually gets broadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    PlayPause,
+    Next,
+    Stop,
+    Restart,
+    /// Seek `offset_ms` forward (or backward, if negative) from the current position.
+    SeekBy {
+        offset_ms: i64,
+    },
+    /// Seek to `position_ms` of the current track.
+    SeekTo {
+        position_ms: u64,
+    },
+    /// Go back to the previous track.
+    Previous,
+    /// Play the track at `track_index` of the playlist.
+    JumpTo {
+        track_index: u32,
+    },
+    /// Raise the session volume by one step.
+    VolumeUp,
+    /// Lower the session volume by one step.
+    VolumeDown,
+    /// Set the session volume to `percent`.
+    SetVolume {
+        percent: u8,
+    },
+    /// Turn shuffling on with a new order, or off.
+    Shuffle,
+    /// Set the repeat mode, or switch to the next one if `repeat` is `None`.
+    Repeat {
+        repeat: Option<Repeat>,
+    },
+}
+
+impl Command {
+    /// Parses a console command (`p`, `n`, `b`, `s`, `r`, `+10`, `-10s`, `goto 2:35`, `track 3`,
+    /// `vol +`, `vol -`, `vol 80`, `shuffle`, `repeat`, `repeat one`).
+    ///
+    /// `+` and `-` without an amount seek by ten seconds. Tracks are numbered from 1, as in the
+    /// printed playlist.
+    pub fn from_input(input: &str) -> Option<Command> {
+        let input = input.trim();
+        match input {
+            "p" => Some(Command::PlayPause),
+            "n" => Some(Command::Next),
+            "s" => Some(Command::Stop),
+            "r" => Some(Command::Restart),
+            "b" => Some(Command::Previous),
+            "vol +" => Some(Command::VolumeUp),
+            "vol -" => Some(Command::VolumeDown),
+            "shuffle" => Some(Command::Shuffle),
+            "repeat" => Some(Command::Repeat { repeat: None }),
+            _ => {
+                if let Some(name) = input.strip_prefix("repeat ") {
+                    return Some(Command::Repeat {
+                        repeat: Some(Repeat::from_name(name)?),
+                    });
+                }
+
+                if let Some(percent) = input.strip_prefix("vol ") {
+                    let percent = percent.trim().parse::<u8>().ok().filter(|p| *p <= 100)?;
+                    return Some(Command::SetVolume { percent });
+                }
+
+                if let Some(number) = input.strip_prefix("track ") {
+                    return Some(Command::JumpTo {
+                        track_index: parse_number(number)?,
+                    });
+                }
+
+                if let Some(timestamp) = input.strip_prefix("goto ") {
+                    let position = utils::minutes_seconds_to_duration(timestamp.trim())?;
+                    return Some(Command::SeekTo {
+                        position_ms: position.as_millis() as u64,
+                    });
+                }
+
+                let (sign, amount) = match input.split_at_checked(1)? {
+                    ("+", amount) => (1, amount),
+                    ("-", amount) => (-1, amount),
+                    _ => return None,
+                };
+                let amount = amount.strip_suffix('s').unwrap_or(amount);
+                let seconds = if amount.is_empty() {
+                    DEFAULT_SEEK_SECONDS
+                } else {
+                    amount.parse::<u32>().ok()? as i64
+                };
+                Some(Command::SeekBy {
+                    offset_ms: sign * seconds * 1000,
+                })
+            }
+        }
+    }
+
+    fn put(self, bytes: &mut Vec<u8>) {
+        match self {
+            Command::PlayPause => bytes.push(0),
+            Command::Next => bytes.push(2),
+            Command::Stop => bytes.push(3),
+            Command::Restart => bytes.push(4),
+            Command::SeekBy { offset_ms } => {
+                bytes.push(5);
+                bytes.extend_from_slice(&offset_ms.to_be_bytes());
+            }
+            Command::SeekTo { position_ms } => {
+                bytes.push(6);
+                bytes.extend_from_slice(&position_ms.to_be_bytes());
+            }
+            Command::Previous => bytes.push(7),
+            Command::JumpTo { track_index } => {
+                bytes.push(8);
+                bytes.extend_from_slice(&track_index.to_be_bytes());
+            }
+            Command::VolumeUp => bytes.push(9),
+            Command::VolumeDown => bytes.push(10),
+            Command::SetVolume { percent } => bytes.extend_from_slice(&[11, percent]),
+            Command::Shuffle => bytes.push(12),
+            Command::Repeat { repeat } => {
+                // The modes are 0 to 2, so 3 stands for switching to the next one
+                bytes.extend_from_slice(&[13, repeat.map_or(3, repeat_to_byte)]);
+            }
+        }
+    }
+
+    fn read(reader: &mut Reader) -> Result<Command, ProtocolError> {
+        match reader.u8()? {
+            0 => Ok(Command::PlayPause),
+            2 => Ok(Command::Next),
+            3 => Ok(Command::Stop),
+            4 => Ok(Command::Restart),
+            5 => Ok(Command::SeekBy {
+                offset_ms: reader.u64()? as i64,
+            }),
+            6 => Ok(Command::SeekTo {
+                position_ms: reader.u64()?,
+            }),
+            7 => Ok(Command::Previous),
+            8 => Ok(Command::JumpTo {
+                track_index: reader.u32()?,
+            }),
+            9 => Ok(Command::VolumeUp),
+            10 => Ok(Command::VolumeDown),
+            11 => Ok(Command::SetVolume {
+                percent: reader.u8()?,
+            }),
+            12 => Ok(Command::Shuffle),
+            13 => Ok(Command::Repeat {
+                repeat: match reader.u8()? {
+                    3 => None,
+                    byte => Some(repeat_from_byte(byte)?),
+                },
+            }),
+            other => Err(ProtocolError::UnknownCommand(other)),
+        }
+    }
+}
+
+/// Changes to the playlist typed on the console of the leader or of a member.
+///
+/// Like commands, these are intents that only the leader resolves: it works out the new playlist
+/// and broadcasts it, so every peer ends up with the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    /// Append the track at `library_index` of the leader's media library to the playlist.
+    Append { library_index: u32 },
+    /// Insert the track at `library_index` of the leader's media library after the current track.
+    InsertNext { library_index: u32 },
+    /// Remove the track at `track_index` from the playlist.
+    Remove { track_index: u32 },
+    /// Move the track at `from` of the playlist to position `to`.
+    Move { from: u32, to: u32 },
+}
+
+impl Edit {
+    /// Parses a console playlist edit (`add 4`, `add next 4`, `remove 2`, `move 5 1`).
+    ///
+    /// Tracks are numbered from 1, as in the printed playlist and the leader's printed library.
+    pub fn from_input(input: &str) -> Option<Edit> {
+        let input = input.trim();
+        if let Some(number) = input.strip_prefix("add next ") {
+            return Some(Edit::InsertNext {
+                library_index: parse_number(number)?,
+            });
+        }
+        if let Some(number) = input.strip_prefix("add ") {
+            return Some(Edit::Append {
+                library_index: parse_number(number)?,
+            });
+        }
+        if let Some(number) = input.strip_prefix("remove ") {
+            return Some(Edit::Remove {
+                track_index: parse_number(number)?,
+            });
+        }
+        let (from, to) = input.strip_prefix("move ")?.trim().split_once(' ')?;
+        Some(Edit::Move {
+            from: parse_number(from)?,
+            to: parse_number(to)?,
+        })
+    }
+
+    fn put(self, bytes: &mut Vec<u8>) {
+        match self {
+            Edit::Append { library_index } => {
+                bytes.push(0);
+                bytes.extend_from_slice(&library_index.to_be_bytes());
+            }
+            Edit::InsertNext { library_index } => {
+                bytes.push(1);
+                bytes.extend_from_slice(&library_index.to_be_bytes());
+            }
+            Edit::Remove { track_index } => {
+                bytes.push(2);
+                bytes.extend_from_slice(&track_index.to_be_bytes());
+            }
+            Edit::Move { from, to } => {
+                bytes.push(3);
+                bytes.extend_from_slice(&from.to_be_bytes());
+                bytes.extend_from_slice(&to.to_be_bytes());
+            }
+        }
+    }
+
+    fn read(reader: &mut Reader) -> Result<Edit, ProtocolError> {
+        match reader.u8()? {
+            0 => Ok(Edit::Append {
+                library_index: reader.u32()?,
+            }),
+            1 => Ok(Edit::InsertNext {
+                library_index: reader.u32()?,
+            }),
+            2 => Ok(Edit::Remove {
+                track_index: reader.u32()?,
+            }),
+            3 => Ok(Edit::Move {
+                from: reader.u32()?,
+                to: reader.u32()?,
+            }),
+            other => Err(ProtocolError::InvalidValue(other)),
+        }
+    }
+}
+
+/// Parses a track number as shown on the console (counting from 1) into an index.
+fn parse_number(number: &str) -> Option<u32> {
+    let number = number.trim().parse::<u32>().ok().filter(|n| *n > 0)?;
+    Some(number - 1)
+}
+
+/// The absolute playback state a broadcast command moves every peer to.
+///
+/// Executing the same action twice leaves a peer in the same state as executing it once, and a
+/// peer that missed earlier commands still ends up where everybody else is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Play `track_index` from `position_ms`.
+    Play { track_index: u32, position_ms: u64 },
+    /// Pause `track_index` at `position_ms`.
+    Pause { track_index: u32, position_ms: u64 },
+    /// Move to `position_ms` of `track_index`, keeping playback running or paused.
+    Seek { track_index: u32, position_ms: u64 },
+    /// End the session.
+    Stop,
+    /// Set the session volume to `percent`.
+    Volume { percent: u8 },
+    /// Play the playlist in `order` from now on.
+    Order { order: PlayOrder },
+}
+
+impl Action {
+    fn put(self, bytes: &mut Vec<u8>) {
+        let mut put_position = |kind: u8, track_index: u32, position_ms: u64| {
+            bytes.push(kind);
+            bytes.extend_from_slice(&track_index.to_be_bytes());
+            bytes.extend_from_slice(&position_ms.to_be_bytes());
+        };
+        match self {
+            Action::Play {
+                track_index,
+                position_ms,
+            } => put_position(0, track_index, position_ms),
+            Action::Pause {
+                track_index,
+                position_ms,
+            } => put_position(1, track_index, position_ms),
+            Action::Seek {
+                track_index,
+                position_ms,
+            } => put_position(2, track_index, position_ms),
+            Action::Stop => bytes.push(3),
+            Action::Volume { percent } => bytes.extend_from_slice(&[4, percent]),
+            Action::Order { order } => {
+                bytes.push(5);
+                put_order(bytes, order);
+            }
+        }
+    }
+
+    fn read(reader: &mut Reader) -> Result<Action, ProtocolError> {
+        match reader.u8()? {
+            0 => Ok(Action::Play {
+                track_index: reader.u32()?,
+                position_ms: reader.u64()?,
+            }),
+            1 => Ok(Action::Pause {
+                track_index: reader.u32()?,
+                position_ms: reader.u64()?,
+            }),
+            2 => Ok(Action::Seek {
+                track_index: reader.u32()?,
+                position_ms: reader.u64()?,
+            }),
+            3 => Ok(Action::Stop),
+            4 => Ok(Action::Volume {
+                percent: reader.u8()?,
+            }),
+            5 => Ok(Action::Order {
+                order: reader.order()?,
+            }),
+            other => Err(ProtocolError::InvalidValue(other)),
+        }
+    }
+}
+
+/// Every message exchanged between the leader and its members.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Leader discovery broadcast.
+    Ping { broadcast_id: u64 },
+    /// A member's answer to a `Ping`.
+    Join,
+    /// A member's periodic sign of life.
+    KeepAlive,
+    /// A member quits the session.
+    Leave,
+    /// Confirms that the packet with `sequence` arrived, so its sender stops retransmitting it.
+    Ack { sequence: u32 },
+    /// The tracks selected by the leader, in playback order, and the TCP port their audio is
+    /// streamed from.
+    Playlist {
+        stream_port: u16,
+        tracks: Vec<TrackInfo>,
+    },
+    /// An action the leader asks every member to execute at `start_time` (ms since the UNIX epoch).
+    Command { action: Action, start_time: u64 },
+    /// A command a member asks the leader to broadcast.
+    Request { command: Command },
+    /// A playlist edit a member asks the leader to carry out.
+    EditRequest { edit: Edit },
+    /// A member asks for the leader's clock; `origin` is the member's send time. The leader sends
+    /// time requests to members as well, to measure the round trip to them.
+    TimeRequest { origin: u64 },
+    /// The answer, with the answering peer's clock at `receive` and `transmit` time.
+    TimeResponse {
+        origin: u64,
+        receive: u64,
+        transmit: u64,
+    },
+    /// Where the session stands: a joining member seeks to `position_ms` of `track_index` at
+    /// `start_time` and then plays, or stays paused if `paused` is set. The session volume is
+    /// `volume` percent, the playlist is played in `order`, following the playlist indices in
+    /// `sequence`, consecutive tracks are crossfaded for `crossfade_ms` (0 for gapless
+    /// transitions), and tracks are normalized with `gain_mode`.
+    SessionState {
+        track_index: u32,
+        paused: bool,
+        volume: u8,
+        order: PlayOrder,
+        sequence: Vec<u32>,
+        crossfade_ms: u32,
+        gain_mode: GainMode,
+        position_ms: u64,
+        start_time: u64,
+    },
+    /// Periodic heartbeat: the leader's listeners heard `position_ms` of `track_index` at
+    /// `leader_time`.
+    Position {
+        track_index: u32,
+        position_ms: u64,
+        leader_time: u64,
+    },
+    /// A member reports whether it can play the track with the content hash `hash`.
+    TrackStatus {
+        hash: u64,
+        availability: Availability,
+    },
+    /// The playlist was edited: every peer replaces its playlist with `tracks`, plays it in the
+    /// order of the playlist indices in `sequence`, and rebuilds its queue to the state described
+    /// by `action` at `start_time`.
+    PlaylistEdit {
+        tracks: Vec<TrackInfo>,
+        sequence: Vec<u32>,
+        action: Action,
+        start_time: u64,
+    },
+    /// A member reports the output latency it compensates for, so the leader can show it.
+    Latency { latency_ms: u32 },
+    /// Every member plays the calibration clicks at `start_time`, without compensating its output
+    /// latency, so a recording of them shows how late the member is heard.
+    Calibrate { start_time: u64 },
+}
+
+impl Message {
+    fn type_id(&self) -> u8 {
+        match self {
+            Message::Ping { .. } => 1,
+            Message::Join => 2,
+            Message::Playlist { .. } => 4,
+            Message::Command { .. } => 5,
+            Message::Request { .. } => 6,
+            Message::TimeRequest { .. } => 7,
+            Message::TimeResponse { .. } => 8,
+            Message::Position { .. } => 9,
+            Message::SessionState { .. } => 10,
+            Message::KeepAlive => 11,
+            Message::Leave => 12,
+            Message::Ack { .. } => 13,
+            Message::TrackStatus { .. } => 14,
+            Message::PlaylistEdit { .. } => 15,
+            Message::EditRequest { .. } => 16,
+            Message::Latency { .. } => 17,
+            Message::Calibrate { .. } => 18,
+        }
+    }
+}
+
+/// What a member needs to know about a playlist entry to play it from the leader's stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub name: String,
+    pub format: AudioFormat,
+    pub duration_ms: u64,
+    /// `ContentHasher` hash of the file, which members check their copy against.
+    pub hash: u64,
+    /// Loudness adjustments measured by the leader, so every peer plays the track equally loud.
+    pub replay_gain: ReplayGain,
+}
+
+impl From<&Track> for TrackInfo {
+    fn from(track: &Track) -> Self {
+        TrackInfo {
+            name: track.display_name(),
+            format: track.format,
+            duration_ms: track.duration.as_millis() as u64,
+            hash: track.hash,
+            replay_gain: track.tags.replay_gain,
+        }
+    }
+}
+
+impl From<&TrackInfo> for Track {
+    fn from(info: &TrackInfo) -> Self {
+        Track {
+            name: info.name.clone(),
+            path: PathBuf::new(),
+            format: info.format,
+            duration: Duration::from_millis(info.duration_ms),
+            tags: Tags {
+                replay_gain: info.replay_gain,
+                ..Tags::default()
+            },
+            hash: info.hash,
+        }
+    }
+}
+
+fn format_to_byte(format: AudioFormat) -> u8 {
+    match format {
+        AudioFormat::Mp3 => 0,
+        AudioFormat::Flac => 1,
+        AudioFormat::Wav => 2,
+        AudioFormat::Ogg => 3,
+        AudioFormat::Aac => 4,
+    }
+}
+
+fn format_from_byte(byte: u8) -> Result<AudioFormat, ProtocolError> {
+    match byte {
+        0 => Ok(AudioFormat::Mp3),
+        1 => Ok(AudioFormat::Flac),
+        2 => Ok(AudioFormat::Wav),
+        3 => Ok(AudioFormat::Ogg),
+        4 => Ok(AudioFormat::Aac),
+        other => Err(ProtocolError::InvalidValue(other)),
+    }
+}
+
+fn gain_mode_to_byte(mode: GainMode) -> u8 {
+    match mode {
+        GainMode::Off => 0,
+        GainMode::Track => 1,
+        GainMode::Album => 2,
+    }
+}
+
+fn gain_mode_from_byte(byte: u8) -> Result<GainMode, ProtocolError> {
+    match byte {
+        0 => Ok(GainMode::Off),
+        1 => Ok(GainMode::Track),
+        2 => Ok(GainMode::Album),
+        other => Err(ProtocolError::InvalidValue(other)),
+    }
+}
+
+fn repeat_to_byte(repeat: Repeat) -> u8 {
+    match repeat {
+        Repeat::Off => 0,
+        Repeat::All => 1,
+        Repeat::One => 2,
+    }
+}
+
+fn repeat_from_byte(byte: u8) -> Result<Repeat, ProtocolError> {
+    match byte {
+        0 => Ok(Repeat::Off),
+        1 => Ok(Repeat::All),
+        2 => Ok(Repeat::One),
+        other => Err(ProtocolError::InvalidValue(other)),
+    }
+}
+
+fn availability_to_byte(availability: Availability) -> u8 {
+    match availability {
+        Availability::Playable => 0,
+        Availability::Mismatch => 1,
+        Availability::Missing => 2,
+    }
+}
+
+fn availability_from_byte(byte: u8) -> Result<Availability, ProtocolError> {
+    match byte {
+        0 => Ok(Availability::Playable),
+        1 => Ok(Availability::Mismatch),
+        2 => Ok(Availability::Missing),
+        other => Err(ProtocolError::InvalidValue(other)),
+    }
+}
+
+/// A decoded packet: the sender's epoch and sequence number, and the message it carried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub epoch: u32,
+    pub sequence: u32,
+    pub message: Message,
+}
+
+/// Reasons a packet can be rejected by the codec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The packet does not start with `MAGIC`, so it is not ours.
+    BadMagic,
+    /// The packet was produced by a peer speaking another protocol version.
+    UnsupportedVersion(u8),
+    /// The message type is unknown to this build (most likely sent by a newer peer).
+    UnknownMessageType(u8),
+    /// The command byte is unknown to this build.
+    UnknownCommand(u8),
+    /// A field holds a value this build does not understand.
+    InvalidValue(u8),
+    /// The packet ended before the header or payload was complete.
+    Truncated,
+    /// The payload has bytes left over after the message was decoded.
+    TrailingBytes,
+    /// A string in the payload is not valid UTF-8.
+    InvalidUtf8,
+    /// The encoded message does not fit into a single datagram.
+    TooLarge,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic => write!(f, "not a SyncStream packet"),
+            ProtocolError::UnsupportedVersion(version) => write!(
+                f,
+                "peer speaks protocol version {}, this build speaks version {}",
+                version, PROTOCOL_VERSION
+            ),
+            ProtocolError::UnknownMessageType(kind) => write!(f, "unknown message type {}", kind),
+            ProtocolError::UnknownCommand(command) => write!(f, "unknown command {}", command),
+            ProtocolError::InvalidValue(value) => write!(f, "invalid field value {}", value),
+            ProtocolError::Truncated => write!(f, "packet is truncated"),
+            ProtocolError::TrailingBytes => write!(f, "packet has trailing bytes"),
+            ProtocolError::InvalidUtf8 => write!(f, "packet contains invalid UTF-8"),
+            ProtocolError::TooLarge => write!(f, "message does not fit into a single packet"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Serializes a message into a framed packet.
+///
+/// The frame consists of `MAGIC`, `PROTOCOL_VERSION`, the message type, the epoch of this process
+/// (see `epoch`), the sequence number and the message payload. Integers are big-endian and strings
+/// are prefixed with their length as a `u16`.
+pub fn encode(sequence: u32, message: &Message) -> Result<Vec<u8>, ProtocolError> {
+    encode_frame(epoch(), sequence, message)
+}
+
+fn encode_frame(epoch: u32, sequence: u32, message: &Message) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + 16);
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(PROTOCOL_VERSION);
+    bytes.push(message.type_id());
+    bytes.extend_from_slice(&epoch.to_be_bytes());
+    bytes.extend_from_slice(&sequence.to_be_bytes());
+
+    match message {
+        Message::Ping { broadcast_id } => bytes.extend_from_slice(&broadcast_id.to_be_bytes()),
+        Message::Join | Message::KeepAlive | Message::Leave => {}
+        Message::Playlist {
+            stream_port,
+            tracks,
+        } => {
+            bytes.extend_from_slice(&stream_port.to_be_bytes());
+            put_tracks(&mut bytes, tracks)?;
+        }
+        Message::Command { action, start_time } => {
+            action.put(&mut bytes);
+            bytes.extend_from_slice(&start_time.to_be_bytes());
+        }
+        Message::PlaylistEdit {
+            tracks,
+            sequence,
+            action,
+            start_time,
+        } => {
+            put_tracks(&mut bytes, tracks)?;
+            put_sequence(&mut bytes, sequence)?;
+            action.put(&mut bytes);
+            bytes.extend_from_slice(&start_time.to_be_bytes());
+        }
+        Message::Request { command } => command.put(&mut bytes),
+        Message::EditRequest { edit } => edit.put(&mut bytes),
+        Message::Latency { latency_ms } => bytes.extend_from_slice(&latency_ms.to_be_bytes()),
+        Message::Calibrate { start_time } => bytes.extend_from_slice(&start_time.to_be_bytes()),
+        Message::Ack { sequence } => bytes.extend_from_slice(&sequence.to_be_bytes()),
+        Message::TimeRequest { origin } => bytes.extend_from_slice(&origin.to_be_bytes()),
+        Message::TimeResponse {
+            origin,
+            receive,
+            transmit,
+        } => {
+            bytes.extend_from_slice(&origin.to_be_bytes());
+            bytes.extend_from_slice(&receive.to_be_bytes());
+            bytes.extend_from_slice(&transmit.to_be_bytes());
+        }
+        Message::SessionState {
+            track_index,
+            paused,
+            volume,
+            order,
+            sequence,
+            crossfade_ms,
+            gain_mode,
+            position_ms,
+            start_time,
+        } => {
+            bytes.extend_from_slice(&track_index.to_be_bytes());
+            bytes.push(u8::from(*paused));
+            bytes.push(*volume);
+            put_order(&mut bytes, *order);
+            put_sequence(&mut bytes, sequence)?;
+            bytes.extend_from_slice(&crossfade_ms.to_be_bytes());
+            bytes.push(gain_mode_to_byte(*gain_mode));
+            bytes.extend_from_slice(&position_ms.to_be_bytes());
+            bytes.extend_from_slice(&start_time.to_be_bytes());
+        }
+        Message::Position {
+            track_index,
+            position_ms,
+            leader_time,
+        } => {
+            bytes.extend_from_slice(&track_index.to_be_bytes());
+            bytes.extend_from_slice(&position_ms.to_be_bytes());
+            bytes.extend_from_slice(&leader_time.to_be_bytes());
+        }
+        Message::TrackStatus { hash, availability } => {
+            bytes.extend_from_slice(&hash.to_be_bytes());
+            bytes.push(availability_to_byte(*availability));
+        }
+    }
+
+    if bytes.len() > MAX_PACKET_SIZE {
+        return Err(ProtocolError::TooLarge);
+    }
+
+    Ok(bytes)
+}
+
+/// Parses a framed packet produced by `encode`.
+///
+/// The header is validated before the payload is looked at, so packets from other applications,
+/// other protocol versions or newer message types are rejected instead of being misparsed.
+pub fn decode(bytes: &[u8]) -> Result<Packet, ProtocolError> {
+    if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(ProtocolError::Truncated);
+    }
+    if bytes[4] != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(bytes[4]));
+    }
+
+    let message_type = bytes[5];
+    let epoch = u32::from_be_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let sequence = u32::from_be_bytes([bytes[10], bytes[11], bytes[12], bytes[13]]);
+    let mut reader = Reader {
+        bytes: &bytes[HEADER_LEN..],
+    };
+
+    let message = match message_type {
+        1 => Message::Ping {
+            broadcast_id: reader.u64()?,
+        },
+        2 => Message::Join,
+        4 => Message::Playlist {
+            stream_port: reader.u16()?,
+            tracks: reader.tracks()?,
+        },
+        5 => Message::Command {
+            action: Action::read(&mut reader)?,
+            start_time: reader.u64()?,
+        },
+        6 => Message::Request {
+            command: Command::read(&mut reader)?,
+        },
+        7 => Message::TimeRequest {
+            origin: reader.u64()?,
+        },
+        8 => Message::TimeResponse {
+            origin: reader.u64()?,
+            receive: reader.u64()?,
+            transmit: reader.u64()?,
+        },
+        9 => Message::Position {
+            track_index: reader.u32()?,
+            position_ms: reader.u64()?,
+            leader_time: reader.u64()?,
+        },
+        10 => Message::SessionState {
+            track_index: reader.u32()?,
+            paused: reader.bool()?,
+            volume: reader.u8()?,
+            order: reader.order()?,
+            sequence: reader.sequence()?,
+            crossfade_ms: reader.u32()?,
+            gain_mode: gain_mode_from_byte(reader.u8()?)?,
+            position_ms: reader.u64()?,
+            start_time: reader.u64()?,
+        },
+        11 => Message::KeepAlive,
+        12 => Message::Leave,
+        13 => Message::Ack {
+            sequence: reader.u32()?,
+        },
+        14 => Message::TrackStatus {
+            hash: reader.u64()?,
+            availability: availability_from_byte(reader.u8()?)?,
+        },
+        15 => Message::PlaylistEdit {
+            tracks: reader.tracks()?,
+            sequence: reader.sequence()?,
+            action: Action::read(&mut reader)?,
+            start_time: reader.u64()?,
+        },
+        16 => Message::EditRequest {
+            edit: Edit::read(&mut reader)?,
+        },
+        17 => Message::Latency {
+            latency_ms: reader.u32()?,
+        },
+        18 => Message::Calibrate {
+            start_time: reader.u64()?,
+        },
+        other => return Err(ProtocolError::UnknownMessageType(other)),
+    };
+
+    if !reader.bytes.is_empty() {
+        return Err(ProtocolError::TrailingBytes);
+    }
+
+    Ok(Packet {
+        epoch,
+        sequence,
+        message,
+    })
+}
+
+/// Returns the epoch of this process, a random number picked once at startup.
+///
+/// Sequence numbers start from 1 again when a peer restarts, so receivers tell the packets of a
+/// restarted peer from retransmissions of its earlier packets by the epoch.
+pub fn epoch() -> u32 {
+    *EPOCH.get_or_init(|| RandomState::new().build_hasher().finish() as u32)
+}
+
+/// Returns the next sequence number for an outgoing packet.
+pub fn next_sequence() -> u32 {
+    NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Encodes a message with a fresh sequence number and sends it to `addr`.
+pub fn send_message(
+    socket: &UdpSocket,
+    message: &Message,
+    addr: SocketAddr,
+) -> std::io::Result<()> {
+    let bytes = encode(next_sequence(), message)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    socket.send_to(&bytes, addr)?;
+    Ok(())
+}
+
+fn put_tracks(bytes: &mut Vec<u8>, tracks: &[TrackInfo]) -> Result<(), ProtocolError> {
+    let count = u16::try_from(tracks.len()).map_err(|_| ProtocolError::TooLarge)?;
+    bytes.extend_from_slice(&count.to_be_bytes());
+    for track in tracks {
+        put_string(bytes, &track.name)?;
+        bytes.push(format_to_byte(track.format));
+        bytes.extend_from_slice(&track.duration_ms.to_be_bytes());
+        bytes.extend_from_slice(&track.hash.to_be_bytes());
+        put_gain(bytes, track.replay_gain.track);
+        put_gain(bytes, track.replay_gain.album);
+    }
+    Ok(())
+}
+
+/// Writes a flag telling whether there is a gain, and the gain in hundredths of a decibel.
+fn put_gain(bytes: &mut Vec<u8>, gain: Option<Gain>) {
+    match gain {
+        Some(gain) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&gain.0.to_be_bytes());
+        }
+        None => bytes.push(0),
+    }
+}
+
+/// Writes a play order as a flag telling whether it is shuffled, the seed (only if shuffled) and
+/// the repeat mode.
+fn put_order(bytes: &mut Vec<u8>, order: PlayOrder) {
+    match order.shuffle_seed {
+        Some(seed) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&seed.to_be_bytes());
+        }
+        None => bytes.push(0),
+    }
+    bytes.push(repeat_to_byte(order.repeat));
+}
+
+/// Writes a play sequence as the number of playlist indices, followed by the indices.
+fn put_sequence(bytes: &mut Vec<u8>, sequence: &[u32]) -> Result<(), ProtocolError> {
+    let count = u16::try_from(sequence.len()).map_err(|_| ProtocolError::TooLarge)?;
+    bytes.extend_from_slice(&count.to_be_bytes());
+    for index in sequence {
+        bytes.extend_from_slice(&index.to_be_bytes());
+    }
+    Ok(())
+}
+
+fn put_string(bytes: &mut Vec<u8>, value: &str) -> Result<(), ProtocolError> {
+    let len = u16::try_from(value.len()).map_err(|_| ProtocolError::TooLarge)?;
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bytes.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+/// A cursor over a packet payload that fails with `Truncated` instead of panicking.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.bytes.len() < len {
+            return Err(ProtocolError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, ProtocolError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(ProtocolError::InvalidValue(other)),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, ProtocolError> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)
+    }
+
+    fn order(&mut self) -> Result<PlayOrder, ProtocolError> {
+        let shuffle_seed = match self.bool()? {
+            true => Some(self.u64()?),
+            false => None,
+        };
+        Ok(PlayOrder {
+            shuffle_seed,
+            repeat: repeat_from_byte(self.u8()?)?,
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Vec<u32>, ProtocolError> {
+        let count = self.u16()?;
+        (0..count).map(|_| self.u32()).collect()
+    }
+
+    fn gain(&mut self) -> Result<Option<Gain>, ProtocolError> {
+        Ok(match self.bool()? {
+            true => Some(Gain(self.u32()? as i32)),
+            false => None,
+        })
+    }
+
+    fn tracks(&mut self) -> Result<Vec<TrackInfo>, ProtocolError> {
+        let count = self.u16()?;
+        let mut tracks = Vec::new();
+        for _ in 0..count {
+            tracks.push(TrackInfo {
+                name: self.string()?,
+                format: format_from_byte(self.u8()?)?,
+                duration_ms: self.u64()?,
+                hash: self.u64()?,
+                replay_gain: ReplayGain {
+                    track: self.gain()?,
+                    album: self.gain()?,
+                },
+            });
+        }
+        Ok(tracks)
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_messages() -> Vec<Message> {
+        vec![
+            Message::Ping { broadcast_id: 42 },
+            Message::Join,
+            Message::KeepAlive,
+            Message::Leave,
+            Message::Ack { sequence: 7 },
+            Message::Playlist {
+                stream_port: 0,
+                tracks: vec![],
+            },
+            Message::Playlist {
+                stream_port: 40_000,
+                tracks: vec![
+                    TrackInfo {
+                        name: "Intro".to_string(),
+                        format: AudioFormat::Mp3,
+                        duration_ms: 61_500,
+                        hash: 0xaf63dc4c8601ec8c,
+                        replay_gain: ReplayGain {
+                            track: Some(Gain(-712)),
+                            album: Some(Gain(150)),
+                        },
+                    },
+                    TrackInfo {
+                        name: "Şarkı, with comma".to_string(),
+                        format: AudioFormat::Aac,
+                        duration_ms: 0,
+                        hash: u64::MAX,
+                        replay_gain: ReplayGain::default(),
+                    },
+                ],
+            },
+            Message::Command {
+                action: Action::Play {
+                    track_index: 0,
+                    position_ms: 0,
+                },
+                start_time: 1_700_000_000_123,
+            },
+            Message::Command {
+                action: Action::Pause {
+                    track_index: 2,
+                    position_ms: 93_250,
+                },
+                start_time: 1_700_000_000_123,
+            },
+            Message::Command {
+                action: Action::Seek {
+                    track_index: u32::MAX,
+                    position_ms: u64::MAX,
+                },
+                start_time: u64::MAX,
+            },
+            Message::Command {
+                action: Action::Stop,
+                start_time: 1,
+            },
+            Message::Request {
+                command: Command::Stop,
+            },
+            Message::Request {
+                command: Command::Restart,
+            },
+            Message::Request {
+                command: Command::SeekBy { offset_ms: -10_000 },
+            },
+            Message::Request {
+                command: Command::Previous,
+            },
+            Message::Request {
+                command: Command::VolumeDown,
+            },
+            Message::Request {
+                command: Command::SetVolume { percent: 35 },
+            },
+            Message::Command {
+                action: Action::Volume { percent: 60 },
+                start_time: 1_700_000_000_123,
+            },
+            Message::Request {
+                command: Command::JumpTo { track_index: 4 },
+            },
+            Message::Request {
+                command: Command::SeekTo {
+                    position_ms: 155_000,
+                },
+            },
+            Message::TimeRequest { origin: 12 },
+            Message::TimeResponse {
+                origin: 12,
+                receive: 1_700_000_000_000,
+                transmit: 1_700_000_000_001,
+            },
+            Message::Position {
+                track_index: 3,
+                position_ms: 93_250,
+                leader_time: 1_700_000_000_002,
+            },
+            Message::SessionState {
+                track_index: 1,
+                paused: true,
+                volume: 80,
+                order: PlayOrder {
+                    shuffle_seed: Some(u64::MAX),
+                    repeat: Repeat::One,
+                },
+                sequence: vec![2, 0, 1],
+                crossfade_ms: 4_000,
+                gain_mode: GainMode::Album,
+                position_ms: 42_000,
+                start_time: 1_700_000_000_003,
+            },
+            Message::TrackStatus {
+                hash: 0xaf63dc4c8601ec8c,
+                availability: Availability::Mismatch,
+            },
+            Message::TrackStatus {
+                hash: 0,
+                availability: Availability::Missing,
+            },
+            Message::Request {
+                command: Command::Shuffle,
+            },
+            Message::Request {
+                command: Command::Repeat { repeat: None },
+            },
+            Message::Request {
+                command: Command::Repeat {
+                    repeat: Some(Repeat::All),
+                },
+            },
+            Message::Command {
+                action: Action::Order {
+                    order: PlayOrder {
+                        shuffle_seed: Some(0x5eed),
+                        repeat: Repeat::Off,
+                    },
+                },
+                start_time: 1_700_000_000_005,
+            },
+            Message::Command {
+                action: Action::Order {
+                    order: PlayOrder::default(),
+                },
+                start_time: 1_700_000_000_006,
+            },
+            Message::EditRequest {
+                edit: Edit::InsertNext { library_index: 9 },
+            },
+            Message::EditRequest {
+                edit: Edit::Move { from: 3, to: 0 },
+            },
+            Message::EditRequest {
+                edit: Edit::Remove { track_index: 1 },
+            },
+            Message::EditRequest {
+                edit: Edit::Append { library_index: 0 },
+            },
+            Message::Latency { latency_ms: 0 },
+            Message::Latency { latency_ms: 180 },
+            Message::Calibrate {
+                start_time: 1_700_000_000_007,
+            },
+            Message::PlaylistEdit {
+                tracks: vec![TrackInfo {
+                    name: "Band – Intro".to_string(),
+                    format: AudioFormat::Flac,
+                    duration_ms: 90_000,
+                    hash: 7,
+                    replay_gain: ReplayGain {
+                        track: Some(Gain(i32::MIN)),
+                        album: None,
+                    },
+                }],
+                sequence: vec![0],
+                action: Action::Seek {
+                    track_index: 0,
+                    position_ms: 12_000,
+                },
+                start_time: 1_700_000_000_004,
+            },
+            Message::PlaylistEdit {
+                tracks: vec![],
+                sequence: vec![],
+                action: Action::Stop,
+                start_time: 1,
+            },
+        ]
+    }
+
+    /// Small deterministic xorshift generator so the fuzz tests are reproducible.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_round_trip_all_messages() {
+        for (sequence, message) in sample_messages().into_iter().enumerate() {
+            let bytes = encode(sequence as u32, &message).expect("Expected message to encode");
+            let packet = decode(&bytes).expect("Expected packet to decode");
+
+            assert_eq!(packet.epoch, epoch());
+            assert_eq!(packet.sequence, sequence as u32);
+            assert_eq!(packet.message, message);
+        }
+    }
+
+    #[test]
+    fn test_parse_console_commands() {
+        assert_eq!(Command::from_input("p\n"), Some(Command::PlayPause));
+        assert_eq!(
+            Command::from_input("+"),
+            Some(Command::SeekBy { offset_ms: 10_000 })
+        );
+        assert_eq!(
+            Command::from_input("-30s"),
+            Some(Command::SeekBy { offset_ms: -30_000 })
+        );
+        assert_eq!(
+            Command::from_input("goto 2:35"),
+            Some(Command::SeekTo {
+                position_ms: 155_000
+            })
+        );
+        assert_eq!(Command::from_input("b"), Some(Command::Previous));
+        assert_eq!(
+            Command::from_input("track 3"),
+            Some(Command::JumpTo { track_index: 2 })
+        );
+        assert_eq!(Command::from_input("track 0"), None);
+        assert_eq!(Command::from_input("vol +"), Some(Command::VolumeUp));
+        assert_eq!(
+            Command::from_input("vol 80"),
+            Some(Command::SetVolume { percent: 80 })
+        );
+        assert_eq!(Command::from_input("vol 120"), None);
+        assert_eq!(Command::from_input("+ten"), None);
+        assert_eq!(Command::from_input("goto"), None);
+        assert_eq!(Command::from_input("x"), None);
+        assert_eq!(Command::from_input("shuffle"), Some(Command::Shuffle));
+        assert_eq!(
+            Command::from_input("repeat one"),
+            Some(Command::Repeat {
+                repeat: Some(Repeat::One)
+            })
+        );
+        assert_eq!(Command::from_input("repeat twice"), None);
+    }
+
+    #[test]
+    fn test_parse_console_edits() {
+        assert_eq!(
+            Edit::from_input("add 4\n"),
+            Some(Edit::Append { library_index: 3 })
+        );
+        assert_eq!(
+            Edit::from_input("add next 4"),
+            Some(Edit::InsertNext { library_index: 3 })
+        );
+        assert_eq!(
+            Edit::from_input("remove 2"),
+            Some(Edit::Remove { track_index: 1 })
+        );
+        assert_eq!(
+            Edit::from_input("move 5 1"),
+            Some(Edit::Move { from: 4, to: 0 })
+        );
+        assert_eq!(Edit::from_input("move 5"), None);
+        assert_eq!(Edit::from_input("remove 0"), None);
+        assert_eq!(Edit::from_input("add"), None);
+        assert_eq!(Edit::from_input("p"), None);
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let bytes = encode(0x01020304, &Message::Join).unwrap();
+
+        assert_eq!(&bytes[..4], &MAGIC);
+        assert_eq!(bytes[4], PROTOCOL_VERSION);
+        assert_eq!(&bytes[6..10], &epoch().to_be_bytes());
+        assert_eq!(&bytes[10..14], &[1, 2, 3, 4]);
+        assert_eq!(bytes.len(), HEADER_LEN);
+    }
+
+    #[test]
+    fn test_rejects_legacy_string_packets() {
+        assert_eq!(decode(b"0 : 1700000000000"), Err(ProtocolError::BadMagic));
+        assert_eq!(decode(b"PING,1"), Err(ProtocolError::BadMagic));
+        assert_eq!(decode(b""), Err(ProtocolError::BadMagic));
+    }
+
+    #[test]
+    fn test_rejects_other_protocol_versions() {
+        let mut bytes = encode(
+            1,
+            &Message::Request {
+                command: Command::Next,
+            },
+        )
+        .unwrap();
+        bytes[4] = PROTOCOL_VERSION + 1;
+
+        assert_eq!(
+            decode(&bytes),
+            Err(ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_message_type() {
+        let mut bytes = encode(1, &Message::Join).unwrap();
+        bytes[5] = 200;
+
+        assert_eq!(decode(&bytes), Err(ProtocolError::UnknownMessageType(200)));
+    }
+
+    #[test]
+    fn test_rejects_trailing_bytes() {
+        let mut bytes = encode(1, &Message::Join).unwrap();
+        bytes.push(0);
+
+        assert_eq!(decode(&bytes), Err(ProtocolError::TrailingBytes));
+    }
+
+    #[test]
+    fn test_rejects_unknown_action() {
+        let message = Message::Command {
+            action: Action::Stop,
+            start_time: 1,
+        };
+        let mut bytes = encode(1, &message).unwrap();
+        bytes[HEADER_LEN] = 9;
+
+        assert_eq!(decode(&bytes), Err(ProtocolError::InvalidValue(9)));
+    }
+
+    #[test]
+    fn test_too_large_message() {
+        let track = TrackInfo {
+            name: "x".repeat(1000),
+            format: AudioFormat::Flac,
+            duration_ms: 1,
+            hash: 1,
+            replay_gain: ReplayGain::default(),
+        };
+        let message = Message::Playlist {
+            stream_port: 1,
+            tracks: vec![track; 100],
+        };
+
+        assert_eq!(encode(1, &message), Err(ProtocolError::TooLarge));
+    }
+
+    #[test]
+    fn test_fuzz_truncated_packets_are_rejected() {
+        for message in sample_messages() {
+            let bytes = encode(7, &message).unwrap();
+            for len in 0..bytes.len() {
+                assert!(
+                    decode(&bytes[..len]).is_err(),
+                    "Truncated {:?} at {} bytes decoded",
+                    message,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_random_bytes_do_not_panic() {
+        let mut rng = XorShift(0x9E3779B97F4A7C15);
+        for _ in 0..10_000 {
+            let len = (rng.next() % 64) as usize;
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            // Give half of the inputs a valid header so the payload parser gets exercised too
+            if rng.next() & 1 == 0 && bytes.len() >= HEADER_LEN {
+                bytes[..4].copy_from_slice(&MAGIC);
+                bytes[4] = PROTOCOL_VERSION;
+                bytes[5] = (rng.next() % 20) as u8;
+            }
+            let _ = decode(&bytes);
+        }
+    }
+
+    #[test]
+    fn test_fuzz_mutated_packets_never_misparse_silently() {
+        let mut rng = XorShift(0xDEADBEEF);
+        let messages = sample_messages();
+        for _ in 0..10_000 {
+            let message = &messages[(rng.next() % messages.len() as u64) as usize];
+            let mut bytes = encode(3, message).unwrap();
+            let index = (rng.next() % bytes.len() as u64) as usize;
+            bytes[index] ^= 1 << (rng.next() % 8);
+
+            // A flipped bit either breaks the frame or yields a well-formed packet that survives
+            // re-encoding unchanged; it must never panic.
+            if let Ok(packet) = decode(&bytes) {
+                let encoded = encode_frame(packet.epoch, packet.sequence, &packet.message);
+                assert_eq!(encoded.unwrap(), bytes);
+            }
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{self, Message};
+
+/// Number of sequence numbers remembered per sender for duplicate suppression.
+const DEDUP_WINDOW: usize = 256;
+
+/// Anything that can send datagrams. Implemented by `UdpSocket`; tests use lossy stand-ins.
+pub trait Transport: Send + Sync {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+}
+
+/// A packet that has been sent but not acknowledged yet.
+struct Pending {
+    bytes: Vec<u8>,
+    deadline: Instant,
+    next_retry: Instant,
+}
+
+/// Sends messages that must arrive, retransmitting them until they are acknowledged.
+///
+/// Every message gets its own sequence number. The receiver answers with an `Ack` carrying that
+/// number, and until it does the packet is retransmitted every `retry_interval`. Once `deadline`
+/// has passed, e.g. because the start time of a command is over, the packet is given up on.
+pub struct ReliableSender {
+    transport: Arc<dyn Transport>,
+    retry_interval: Duration,
+    pending: Mutex<HashMap<(SocketAddr, u32), Pending>>,
+}
+
+impl ReliableSender {
+    pub fn new(transport: Arc<dyn Transport>, retry_interval: Duration) -> Arc<Self> {
+        Arc::new(ReliableSender {
+            transport,
+            retry_interval,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Sends `message` to `addr` and keeps retransmitting it until it is acknowledged or
+    /// `deadline` has passed. Returns the sequence number of the packet.
+    pub fn send(&self, message: &Message, addr: SocketAddr, deadline: Instant) -> io::Result<u32> {
+        let sequence = protocol::next_sequence();
+        let bytes = protocol::encode(sequence, message)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        self.pending.lock().unwrap().insert(
+            (addr, sequence),
+            Pending {
+                bytes: bytes.clone(),
+                deadline,
+                next_retry: Instant::now() + self.retry_interval,
+            },
+        );
+        self.transport.send_to(&bytes, addr)?;
+
+        Ok(sequence)
+    }
+
+    /// Marks a packet as delivered. Returns `false` for unknown or already acknowledged packets.
+    pub fn acknowledge(&self, addr: SocketAddr, sequence: u32) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&(addr, sequence))
+            .is_some()
+    }
+
+    /// Retransmits every packet whose retry time has come, and drops the packets whose deadline
+    /// has passed. Returns the address and sequence number of the dropped packets.
+    pub fn retransmit_due(&self, now: Instant) -> Vec<(SocketAddr, u32)> {
+        let mut pending = self.pending.lock().unwrap();
+
+        let expired: Vec<(SocketAddr, u32)> = pending
+            .iter()
+            .filter(|(_, packet)| now >= packet.deadline)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &expired {
+            pending.remove(key);
+        }
+
+        for ((addr, _), packet) in pending.iter_mut() {
+            if now >= packet.next_retry {
+                packet.next_retry = now + self.retry_interval;
+                if let Err(e) = self.transport.send_to(&packet.bytes, *addr) {
+                    eprintln!("Failed to retransmit to {}: {}", addr, e);
+                }
+            }
+        }
+
+        expired
+    }
+
+    /// Starts a background thread that drives the retransmissions.
+    pub fn start_retransmit_thread(self: &Arc<Self>) {
+        let sender = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(sender.retry_interval / 2);
+            for (addr, sequence) in sender.retransmit_due(Instant::now()) {
+                eprintln!("\nPacket {} to {} was never acknowledged", sequence, addr);
+            }
+        });
+    }
+}
+
+/// Acknowledges a reliable packet back to its sender.
+pub fn send_ack(socket: &UdpSocket, sequence: u32, addr: SocketAddr) {
+    if let Err(e) = protocol::send_message(socket, &Message::Ack { sequence }, addr) {
+        eprintln!("Failed to acknowledge packet {}: {}", sequence, e);
+    }
+}
+
+/// Remembers the sequence numbers seen from each sender, so a retransmitted packet whose first
+/// copy did arrive (but whose ACK was lost) is not applied twice.
+///
+/// A sender that restarts on the same address numbers its packets from 1 again, under a new epoch
+/// (see `protocol::epoch`), so the sequence numbers of its earlier epoch are forgotten.
+#[derive(Debug, Default)]
+pub struct Deduplicator {
+    seen: HashMap<SocketAddr, Window>,
+}
+
+/// The sequence numbers seen last from one sender, in the order they arrived.
+#[derive(Debug, Default)]
+struct Window {
+    epoch: u32,
+    set: HashSet<u32>,
+    order: VecDeque<u32>,
+}
+
+impl Deduplicator {
+    /// Returns `true` the first time a sequence number from `addr` is seen in `epoch`.
+    pub fn is_new(&mut self, addr: SocketAddr, epoch: u32, sequence: u32) -> bool {
+        let window = self.seen.entry(addr).or_default();
+        if window.epoch != epoch {
+            *window = Window {
+                epoch,
+                ..Window::default()
+            };
+        }
+        if !window.set.insert(sequence) {
+            return false;
+        }
+
+        window.order.push_back(sequence);
+        if window.order.len() > DEDUP_WINDOW {
+            if let Some(oldest) = window.order.pop_front() {
+                window.set.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Action;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A socket stand-in that drops every packet for which `drop` returns `true`.
+    struct LossySocket {
+        socket: UdpSocket,
+        sent: AtomicUsize,
+        drop: fn(usize) -> bool,
+    }
+
+    impl LossySocket {
+        fn new(socket: UdpSocket, drop: fn(usize) -> bool) -> Self {
+            LossySocket {
+                socket,
+                sent: AtomicUsize::new(0),
+                drop,
+            }
+        }
+    }
+
+    impl Transport for LossySocket {
+        fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            let index = self.sent.fetch_add(1, Ordering::SeqCst);
+            if (self.drop)(index) {
+                Ok(buf.len())
+            } else {
+                self.socket.send_to(buf, addr)
+            }
+        }
+    }
+
+    /// Runs a receiver that acknowledges through `ack_transport` and counts applied commands.
+    fn spawn_receiver(
+        socket: UdpSocket,
+        ack_transport: Arc<dyn Transport>,
+        applied: Arc<AtomicUsize>,
+        duplicates: Arc<AtomicUsize>,
+    ) {
+        thread::spawn(move || {
+            let mut dedup = Deduplicator::default();
+            let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
+            while let Ok((size, src)) = socket.recv_from(&mut buf) {
+                let packet = protocol::decode(&buf[..size]).unwrap();
+                if let Message::Command { .. } = packet.message {
+                    if dedup.is_new(src, packet.epoch, packet.sequence) {
+                        applied.fetch_add(1, Ordering::SeqCst);
+                    } else {
+                        duplicates.fetch_add(1, Ordering::SeqCst);
+                    }
+
+                    let ack = Message::Ack {
+                        sequence: packet.sequence,
+                    };
+                    let bytes = protocol::encode(0, &ack).unwrap();
+                    ack_transport.send_to(&bytes, src).unwrap();
+                }
+            }
+        });
+    }
+
+    /// Feeds ACKs arriving on `socket` into `sender` until `expected` were received.
+    fn receive_acks(socket: &UdpSocket, sender: &ReliableSender, expected: usize) {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let mut buf = vec![0u8; protocol::MAX_PACKET_SIZE];
+        let started = Instant::now();
+        let mut acknowledged = 0;
+        while acknowledged < expected && started.elapsed() < Duration::from_secs(5) {
+            if let Ok((size, src)) = socket.recv_from(&mut buf) {
+                if let Message::Ack { sequence } = protocol::decode(&buf[..size]).unwrap().message {
+                    if sender.acknowledge(src, sequence) {
+                        acknowledged += 1;
+                    }
+                }
+            }
+            sender.retransmit_due(Instant::now());
+        }
+    }
+
+    fn command() -> Message {
+        Message::Command {
+            action: Action::Seek {
+                track_index: 1,
+                position_ms: 0,
+            },
+            start_time: 1_700_000_000_000,
+        }
+    }
+
+    #[test]
+    fn test_lost_commands_are_retransmitted() {
+        let leader = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let member = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let member_addr = member.local_addr().unwrap();
+        let applied = Arc::new(AtomicUsize::new(0));
+        let duplicates = Arc::new(AtomicUsize::new(0));
+        spawn_receiver(
+            member.try_clone().unwrap(),
+            Arc::new(member),
+            Arc::clone(&applied),
+            Arc::clone(&duplicates),
+        );
+
+        // The first three copies of every packet are lost
+        let lossy = LossySocket::new(leader.try_clone().unwrap(), |index| index % 4 != 3);
+        let sender = ReliableSender::new(Arc::new(lossy), Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        sender.send(&command(), member_addr, deadline).unwrap();
+        receive_acks(&leader, &sender, 1);
+
+        assert!(sender.pending.lock().unwrap().is_empty());
+        assert_eq!(applied.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_duplicates_are_suppressed_when_acks_are_lost() {
+        let leader = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let member = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let member_addr = member.local_addr().unwrap();
+        let applied = Arc::new(AtomicUsize::new(0));
+        let duplicates = Arc::new(AtomicUsize::new(0));
+        // Only every third ACK makes it back, so the leader retransmits packets that did arrive
+        let lossy_acks = LossySocket::new(member.try_clone().unwrap(), |index| index % 3 != 2);
+        spawn_receiver(
+            member,
+            Arc::new(lossy_acks),
+            Arc::clone(&applied),
+            Arc::clone(&duplicates),
+        );
+
+        let sender = ReliableSender::new(
+            Arc::new(leader.try_clone().unwrap()),
+            Duration::from_millis(10),
+        );
+        let deadline = Instant::now() + Duration::from_secs(5);
+        for _ in 0..3 {
+            sender.send(&command(), member_addr, deadline).unwrap();
+        }
+        receive_acks(&leader, &sender, 3);
+
+        assert!(sender.pending.lock().unwrap().is_empty());
+        assert_eq!(applied.load(Ordering::SeqCst), 3);
+        assert!(duplicates.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn test_packets_are_dropped_after_deadline() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let black_hole = LossySocket::new(socket, |_| true);
+        let sender = ReliableSender::new(Arc::new(black_hole), Duration::from_millis(10));
+        let addr: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let sequence = sender.send(&command(), addr, deadline).unwrap();
+
+        assert!(sender.retransmit_due(Instant::now()).is_empty());
+        assert_eq!(
+            sender.retransmit_due(deadline + Duration::from_millis(1)),
+            vec![(addr, sequence)]
+        );
+        assert!(sender.pending.lock().unwrap().is_empty());
+        assert!(!sender.acknowledge(addr, sequence));
+    }
+
+    #[test]
+    fn test_deduplicator_window() {
+        let mut dedup = Deduplicator::default();
+        let first: SocketAddr = "10.0.0.1:12345".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:12345".parse().unwrap();
+
+        assert!(dedup.is_new(first, 7, 1));
+        assert!(!dedup.is_new(first, 7, 1));
+        assert!(dedup.is_new(second, 7, 1));
+
+        for sequence in 2..(DEDUP_WINDOW as u32 + 2) {
+            assert!(dedup.is_new(first, 7, sequence));
+        }
+        // Sequence 1 has fallen out of the window
+        assert!(dedup.is_new(first, 7, 1));
+    }
+
+    #[test]
+    fn test_restarted_sender_is_not_deduplicated() {
+        let mut dedup = Deduplicator::default();
+        let member: SocketAddr = "10.0.0.1:12345".parse().unwrap();
+        for sequence in 1..=3 {
+            assert!(dedup.is_new(member, 7, sequence));
+        }
+
+        // The member restarts on the same port and numbers its packets from 1 again
+        for sequence in 1..=3 {
+            assert!(dedup.is_new(member, 8, sequence));
+            assert!(!dedup.is_new(member, 8, sequence));
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::clock::{self, ClockSync};
+use crate::protocol::Action;
+use crate::track::Track;
+
+/// How long before a job is due the scheduler stops sleeping and spins instead, since a sleep can
+/// overshoot by a millisecond or more.
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+
+/// The kind of state a scheduled job changes.
+///
+/// Actions describe absolute states, so a newer action supersedes a pending one of the same kind:
+/// after a second pause has been scheduled, the first one no longer matters. A stop ends the
+/// session whatever comes after it, so it has a slot of its own that no play or pause supersedes.
+/// Playlist edits build on each other, so they never supersede one another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Slot {
+    PlayPause,
+    Stop,
+    Seek,
+    Volume,
+    Order,
+    Playlist,
+    Clicks,
+}
+
+impl Slot {
+    pub fn of(action: &Action) -> Slot {
+        match action {
+            Action::Play { .. } | Action::Pause { .. } => Slot::PlayPause,
+            Action::Stop => Slot::Stop,
+            Action::Seek { .. } => Slot::Seek,
+            Action::Volume { .. } => Slot::Volume,
+            Action::Order { .. } => Slot::Order,
+        }
+    }
+
+    fn supersedes(self) -> bool {
+        self != Slot::Playlist
+    }
+}
+
+/// What a scheduled job changes.
+///
+/// The leader resolves a new command against the state the pending changes leave behind, so it
+/// does not have to wait for them to be carried out (see `Scheduler::with_pending`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// Moves the playback to the state the action describes.
+    Action(Action),
+    /// Replaces the playlist with `tracks`, played in the order of the playlist indices in
+    /// `sequence`, and moves the playback to the state `action` describes.
+    Playlist {
+        tracks: Vec<Track>,
+        sequence: Vec<u32>,
+        action: Action,
+    },
+    /// Plays the calibration clicks, which leave the playback alone.
+    Clicks,
+}
+
+impl Change {
+    pub fn slot(&self) -> Slot {
+        match self {
+            Change::Action(action) => Slot::of(action),
+            Change::Playlist { .. } => Slot::Playlist,
+            Change::Clicks => Slot::Clicks,
+        }
+    }
+}
+
+/// A scheduled job, which is passed how late it runs.
+type Job = Box<dyn FnOnce(Duration) + Send>;
+
+struct Pending {
+    change: Change,
+    job: Job,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Pending jobs, ordered by target time and then by the order they were scheduled in.
+    jobs: BTreeMap<(u64, u64), Pending>,
+    next_id: u64,
+    /// The latest target time scheduled for every superseding slot. A job for an earlier time
+    /// arrived out of order, e.g. as a retransmission, and is outdated.
+    latest: HashMap<Slot, u64>,
+    /// Whether a job is running at the moment.
+    running: bool,
+}
+
+/// Carries out jobs at their target time on a thread of its own.
+///
+/// The threads that receive commands only schedule them and go on, so they never miss a message
+/// while an action waits for its time. Target times are on the leader's clock, and every job runs
+/// this peer's output latency ahead of it, so it is heard at the target time (see
+/// `Config::output_latency`). The thread sleeps until shortly before the next job is due and spins
+/// for the rest, since sleeps are not precise enough. A job whose time has already passed runs
+/// right away and is told how late it is.
+#[derive(Clone)]
+pub struct Scheduler {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    clock: Arc<Mutex<ClockSync>>,
+    output_latency: Arc<Mutex<Duration>>,
+}
+
+impl Scheduler {
+    /// Starts the scheduler thread.
+    pub fn start(clock: Arc<Mutex<ClockSync>>, output_latency: Arc<Mutex<Duration>>) -> Self {
+        let scheduler = Scheduler {
+            queue: Arc::new((Mutex::new(Queue::default()), Condvar::new())),
+            clock,
+            output_latency,
+        };
+        let worker = scheduler.clone();
+        thread::spawn(move || worker.run());
+        scheduler
+    }
+
+    /// Schedules `job`, which carries out `change`, for `target_time_ms` on the leader's clock,
+    /// superseding the pending jobs of the same slot. Returns `false` if a job of the slot has
+    /// already been scheduled for a later time, which makes this one outdated.
+    pub fn schedule(
+        &self,
+        change: Change,
+        target_time_ms: u64,
+        job: impl FnOnce(Duration) + Send + 'static,
+    ) -> bool {
+        let slot = change.slot();
+        let (queue, wakeup) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        if slot.supersedes() {
+            if queue
+                .latest
+                .get(&slot)
+                .is_some_and(|latest| *latest > target_time_ms)
+            {
+                return false;
+            }
+            queue.latest.insert(slot, target_time_ms);
+            queue
+                .jobs
+                .retain(|_, pending| pending.change.slot() != slot);
+        }
+
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.jobs.insert(
+            (target_time_ms, id),
+            Pending {
+                change,
+                job: Box::new(job),
+            },
+        );
+        wakeup.notify_all();
+        true
+    }
+
+    /// Cancels every pending job, returning how many there were.
+    pub fn cancel_all(&self) -> usize {
+        let (queue, wakeup) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        let cancelled = queue.jobs.len();
+        queue.jobs.clear();
+        wakeup.notify_all();
+        cancelled
+    }
+
+    /// Calls `f` with the pending changes and their target times, in the order they are carried
+    /// out.
+    ///
+    /// No job runs while `f` does, so the playback state `f` reads reflects exactly the jobs that
+    /// are no longer pending. A job that is running is waited for. Must not be called from a job.
+    pub fn with_pending<R>(&self, f: impl FnOnce(&[(u64, &Change)]) -> R) -> R {
+        let (queue, wakeup) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        while queue.running {
+            queue = wakeup.wait(queue).unwrap();
+        }
+        let pending: Vec<(u64, &Change)> = queue
+            .jobs
+            .iter()
+            .map(|(&(target_time_ms, _), pending)| (target_time_ms, &pending.change))
+            .collect();
+        f(&pending)
+    }
+
+    /// Blocks until no job is running or pending.
+    #[cfg(test)]
+    pub fn wait_until_settled(&self) {
+        let (queue, wakeup) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        while queue.running || !queue.jobs.is_empty() {
+            queue = wakeup.wait(queue).unwrap();
+        }
+    }
+
+    /// Returns the time until a job for `target_time_ms` is due, in microseconds of the local
+    /// clock; negative once it is overdue.
+    fn until_due(&self, target_time_ms: u64) -> i64 {
+        let offset_us = self.clock.lock().unwrap().offset_ms() * 1000;
+        let output_latency_us = self.output_latency.lock().unwrap().as_micros() as i64;
+        let due_us = (target_time_ms * 1000) as i64 - offset_us - output_latency_us;
+        due_us - clock::system_time_us() as i64
+    }
+
+    fn run(self) {
+        let (queue, wakeup) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        loop {
+            let Some(&(target_time_ms, id)) = queue.jobs.keys().next() else {
+                queue = wakeup.wait(queue).unwrap();
+                continue;
+            };
+
+            // Sleep until shortly before the job is due; a new job may be due earlier
+            let until_due = self.until_due(target_time_ms);
+            let spin_threshold_us = SPIN_THRESHOLD.as_micros() as i64;
+            if until_due > spin_threshold_us {
+                let sleep = Duration::from_micros((until_due - spin_threshold_us) as u64);
+                queue = wakeup.wait_timeout(queue, sleep).unwrap().0;
+                continue;
+            }
+
+            let pending = queue.jobs.remove(&(target_time_ms, id)).unwrap();
+            queue.running = true;
+            drop(queue);
+
+            while self.until_due(target_time_ms) > 0 {
+                std::hint::spin_loop();
+            }
+            let lateness = Duration::from_millis((-until_due).max(0) as u64 / 1000);
+            (pending.job)(lateness);
+
+            queue = self.queue.0.lock().unwrap();
+            queue.running = false;
+            wakeup.notify_all();
+        }
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::PlayOrder;
+    use std::sync::mpsc;
+
+    fn scheduler() -> Scheduler {
+        Scheduler::start(
+            Arc::new(Mutex::new(ClockSync::default())),
+            Arc::new(Mutex::new(Duration::ZERO)),
+        )
+    }
+
+    #[test]
+    fn test_jobs_run_in_target_order() {
+        let scheduler = scheduler();
+        let (ran, received) = mpsc::channel();
+        let now = clock::system_time_ms();
+
+        let seek = Action::Seek {
+            track_index: 0,
+            position_ms: 0,
+        };
+        let volume = Action::Volume { percent: 50 };
+        let order = Action::Order {
+            order: PlayOrder::default(),
+        };
+        for (action, delay) in [(seek, 60), (volume, 20), (order, 40)] {
+            let ran = ran.clone();
+            let target_time = now + delay;
+            let slot = Slot::of(&action);
+            let change = Change::Action(action);
+            assert!(scheduler.schedule(change, target_time, move |lateness| {
+                let early_us = (target_time * 1000) as i64 - clock::system_time_us() as i64;
+                ran.send((slot, lateness, early_us)).unwrap();
+            }));
+        }
+        scheduler.wait_until_settled();
+
+        let runs: Vec<(Slot, Duration, i64)> = received.try_iter().collect();
+        let slots: Vec<Slot> = runs.iter().map(|(slot, _, _)| *slot).collect();
+        assert_eq!(slots, vec![Slot::Volume, Slot::Order, Slot::Seek]);
+        for (_, lateness, early_us) in runs {
+            assert_eq!(lateness, Duration::ZERO);
+            assert!(early_us <= 0, "ran {} us early", early_us);
+        }
+    }
+
+    #[test]
+    fn test_newer_job_supersedes_pending_one() {
+        let scheduler = scheduler();
+        let (ran, received) = mpsc::channel();
+        let now = clock::system_time_ms();
+        let job = |name: &'static str| {
+            let ran = ran.clone();
+            move |lateness: Duration| ran.send((name, lateness)).unwrap()
+        };
+        let pause = || {
+            Change::Action(Action::Pause {
+                track_index: 0,
+                position_ms: 0,
+            })
+        };
+        let edit = || Change::Playlist {
+            tracks: Vec::new(),
+            sequence: Vec::new(),
+            action: Action::Stop,
+        };
+
+        // The second pause supersedes the first, and the first one's retransmission is outdated
+        assert!(scheduler.schedule(pause(), now + 50, job("first pause")));
+        assert!(scheduler.schedule(pause(), now + 80, job("second pause")));
+        assert!(!scheduler.schedule(pause(), now + 50, job("first pause")));
+
+        // Playlist edits build on each other, so both run
+        assert!(scheduler.schedule(edit(), now + 30, job("first edit")));
+        assert!(scheduler.schedule(edit(), now + 40, job("second edit")));
+
+        // The pending changes are known in the order they are carried out
+        let pending = scheduler.with_pending(|pending| {
+            pending
+                .iter()
+                .map(|(time, _)| *time - now)
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(pending, [30, 40, 80]);
+
+        // A job whose time has passed runs right away and knows how late it is
+        let volume = Change::Action(Action::Volume { percent: 50 });
+        assert!(scheduler.schedule(volume, now - 200, job("volume")));
+        scheduler.wait_until_settled();
+
+        let runs: Vec<(&str, Duration)> = received.try_iter().collect();
+        let names: Vec<&str> = runs.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            vec!["volume", "first edit", "second edit", "second pause"]
+        );
+        assert!(runs[0].1 >= Duration::from_millis(200));
+        assert_eq!(runs[3].1, Duration::ZERO);
+
+        // Cancelled jobs never run
+        let seek = Change::Action(Action::Seek {
+            track_index: 0,
+            position_ms: 0,
+        });
+        assert!(scheduler.schedule(seek, now + 60_000, job("seek")));
+        assert_eq!(scheduler.cancel_all(), 1);
+        scheduler.wait_until_settled();
+        assert_eq!(received.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_play_does_not_supersede_stop() {
+        let scheduler = scheduler();
+        let (ran, received) = mpsc::channel();
+        let now = clock::system_time_ms();
+        let job = |name: &'static str| {
+            let ran = ran.clone();
+            move |_: Duration| ran.send(name).unwrap()
+        };
+
+        let play = Change::Action(Action::Play {
+            track_index: 0,
+            position_ms: 0,
+        });
+        assert!(scheduler.schedule(Change::Action(Action::Stop), now + 30, job("stop")));
+        assert!(scheduler.schedule(play, now + 50, job("play")));
+        scheduler.wait_until_settled();
+
+        assert_eq!(received.try_iter().collect::<Vec<_>>(), ["stop", "play"]);
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::clock;
+
+/// How late this peer carried out the actions of a session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LatenessStats {
+    /// How many actions were carried out, and how many of them after they were due.
+    pub actions: u32,
+    pub late: u32,
+    /// Total and longest lateness of the late actions.
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl LatenessStats {
+    pub fn record(&mut self, lateness: Duration) {
+        self.actions += 1;
+        if !lateness.is_zero() {
+            self.late += 1;
+            self.total += lateness;
+            self.max = self.max.max(lateness);
+        }
+    }
+
+    /// Returns the average lateness of the late actions.
+    pub fn mean(&self) -> Duration {
+        if self.late == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.late
+        }
+    }
+}
+
+impl fmt::Display for LatenessStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} actions, {} late (mean {} ms, max {} ms)",
+            self.actions,
+            self.late,
+            self.mean().as_millis(),
+            self.max.as_millis()
+        )
+    }
+}
+
+/// A log of how well this peer kept up with the session, to look into synchronization problems
+/// after the session.
+///
+/// Every line starts with the local time in milliseconds since the UNIX epoch. The log is appended
+/// to, so it covers several sessions. Without a file, the statistics are only kept in memory.
+#[derive(Debug, Default)]
+pub struct SessionLog {
+    file: Option<File>,
+    lateness: LatenessStats,
+}
+
+impl SessionLog {
+    /// Opens the log file at `path` and notes the start of a session as `role`.
+    pub fn open(path: Option<&Path>, role: &str) -> SessionLog {
+        let file = path.and_then(|path| {
+            let opened = OpenOptions::new().create(true).append(true).open(path);
+            opened
+                .inspect_err(|e| eprintln!("Failed to open session log {}: {}", path.display(), e))
+                .ok()
+        });
+
+        let mut log = SessionLog {
+            file,
+            lateness: LatenessStats::default(),
+        };
+        log.write(&format!("session started as {}", role));
+        log
+    }
+
+    /// Records how late an action was carried out, and logs the action if it was late.
+    pub fn record_lateness(&mut self, action: &str, lateness: Duration) {
+        self.lateness.record(lateness);
+        if !lateness.is_zero() {
+            self.write(&format!(
+                "{} carried out {} ms late",
+                action,
+                lateness.as_millis()
+            ));
+        }
+    }
+
+    pub fn lateness(&self) -> LatenessStats {
+        self.lateness
+    }
+
+    /// Logs the lateness statistics of the session, when the session ends.
+    pub fn write_summary(&mut self) {
+        let summary = format!("session ended: {}", self.lateness);
+        self.write(&summary);
+    }
+
+    fn write(&mut self, message: &str) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        if let Err(e) = writeln!(file, "{} {}", clock::system_time_ms(), message) {
+            eprintln!("Failed to write the session log: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_session_log_records_late_actions() {
+        let path =
+            std::env::temp_dir().join(format!("syncstream-session-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut log = SessionLog::open(Some(&path), "member");
+        log.record_lateness("Play", Duration::ZERO);
+        log.record_lateness("Seek", Duration::from_millis(30));
+        log.record_lateness("Play", Duration::from_millis(10));
+        log.write_summary();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let stats = log.lateness();
+        assert_eq!((stats.actions, stats.late), (3, 2));
+        assert_eq!(stats.mean(), Duration::from_millis(20));
+        assert_eq!(stats.max, Duration::from_millis(30));
+
+        // Only the late actions are logged, between the start and the end of the session
+        let messages: Vec<&str> = contents
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "session started as member",
+                "Seek carried out 30 ms late",
+                "Play carried out 10 ms late",
+                "session ended: 3 actions, 2 late (mean 20 ms, max 30 ms)",
+            ]
+        );
+    }
+}
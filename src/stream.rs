@@ -0,0 +1,628 @@
+use rodio::cpal::traits::HostTrait;
+use rodio::source::{SeekError, UniformSourceIterator};
+use rodio::{cpal, Decoder, DeviceTrait, Source};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::track::{Availability, ContentHasher, Track};
+
+/// Size of the chunks the leader writes to the TCP side channel.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Frames a `StreamedSource` is handed over from its decoding thread at a time, and how many such
+/// chunks the thread decodes ahead.
+const CHUNK_FRAMES: usize = 2048;
+const DECODED_CHUNKS: usize = 16;
+
+/// Starts the leader's TCP side channel that streams the encoded audio of the media library to
+/// members.
+///
+/// A member opens one connection per track and sends the track's content hash as a big-endian
+/// `u64`. The leader answers with the file length as a big-endian `u64` followed by the raw file
+/// bytes in chunks of `CHUNK_SIZE`. Tracks are requested by hash rather than by playlist index,
+/// since the playlist can be edited while a download is on its way. Only tracks of the given
+/// library can be requested.
+///
+/// Returns the port the server is listening on, which is announced to members with the playlist.
+pub fn start_stream_server(library: &[Track]) -> io::Result<u16> {
+    let listener = TcpListener::bind("0.0.0.0:0")?;
+    let port = listener.local_addr()?.port();
+    let paths: Arc<HashMap<u64, PathBuf>> = Arc::new(
+        library
+            .iter()
+            .map(|track| (track.hash, track.path.clone()))
+            .collect(),
+    );
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let paths = Arc::clone(&paths);
+                    thread::spawn(move || {
+                        if let Err(e) = serve_track(stream, &paths) {
+                            eprintln!("\nFailed to stream track: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("\nFailed to accept stream connection: {}", e),
+            }
+        }
+    });
+
+    Ok(port)
+}
+
+/// Answers a single track request on the side channel.
+fn serve_track(mut stream: TcpStream, paths: &HashMap<u64, PathBuf>) -> io::Result<()> {
+    let mut hash = [0u8; 8];
+    stream.read_exact(&mut hash)?;
+    let hash = u64::from_be_bytes(hash);
+    let path = paths.get(&hash).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("track {:016x} is not in the media library", hash),
+        )
+    })?;
+
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    stream.write_all(&len.to_be_bytes())?;
+
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&chunk[..read])?;
+    }
+
+    stream.flush()
+}
+
+/// Bytes of a track received so far, shared between the download thread and the decoder.
+#[derive(Default)]
+struct StreamState {
+    data: Vec<u8>,
+    total_len: Option<u64>,
+    finished: bool,
+    error: Option<String>,
+}
+
+/// A buffer that is filled by a download thread and drained by a `StreamReader`.
+#[derive(Default)]
+pub struct StreamBuffer {
+    state: Mutex<StreamState>,
+    data_available: Condvar,
+}
+
+impl StreamBuffer {
+    fn set_total_len(&self, len: u64) {
+        self.state.lock().unwrap().total_len = Some(len);
+        self.data_available.notify_all();
+    }
+
+    fn push(&self, chunk: &[u8]) {
+        self.state.lock().unwrap().data.extend_from_slice(chunk);
+        self.data_available.notify_all();
+    }
+
+    fn finish(&self, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        state.error = error;
+        self.data_available.notify_all();
+    }
+}
+
+/// A `Read + Seek` view over a `StreamBuffer`.
+///
+/// Reads block until the requested bytes have been downloaded, so the decoder can start working
+/// on a track before the whole file has arrived. Only the decoding thread of a `StreamedSource`
+/// reads, so the audio thread is never blocked by a slow download.
+pub struct StreamReader {
+    buffer: Arc<StreamBuffer>,
+    position: u64,
+}
+
+impl StreamReader {
+    pub fn new(buffer: Arc<StreamBuffer>) -> Self {
+        StreamReader {
+            buffer,
+            position: 0,
+        }
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.buffer.state.lock().unwrap();
+        while (state.data.len() as u64) <= self.position && !state.finished {
+            state = self.buffer.data_available.wait(state).unwrap();
+        }
+
+        if let Some(error) = &state.error {
+            if (state.data.len() as u64) <= self.position {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, error.clone()));
+            }
+        }
+
+        let start = (self.position as usize).min(state.data.len());
+        let end = (start + buf.len()).min(state.data.len());
+        buf[..end - start].copy_from_slice(&state.data[start..end]);
+        self.position += (end - start) as u64;
+        Ok(end - start)
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                let mut state = self.buffer.state.lock().unwrap();
+                while state.total_len.is_none() && !state.finished {
+                    state = self.buffer.data_available.wait(state).unwrap();
+                }
+                let total_len = state.total_len.unwrap_or(state.data.len() as u64);
+                total_len.checked_add_signed(offset)
+            }
+        };
+
+        match target {
+            Some(target) => {
+                self.position = target;
+                Ok(target)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+/// Starts downloading a track from the leader's side channel into a new `StreamBuffer`.
+///
+/// The track is identified by its content hash. The download runs on its own thread; the returned
+/// buffer can be read immediately. Once the download is over, the received bytes are checked
+/// against the hash and the outcome is passed to `on_verified`.
+pub fn fetch_track(
+    stream_addr: SocketAddr,
+    hash: u64,
+    on_verified: impl FnOnce(Availability) + Send + 'static,
+) -> Arc<StreamBuffer> {
+    let buffer = Arc::new(StreamBuffer::default());
+    let download_buffer = Arc::clone(&buffer);
+
+    thread::spawn(move || {
+        let result = download_track(stream_addr, hash, &download_buffer);
+        let availability = match &result {
+            Ok(received_hash) if *received_hash == hash => Availability::Playable,
+            Ok(_) => Availability::Mismatch,
+            Err(e) => {
+                eprintln!("\nFailed to download track {:016x}: {}", hash, e);
+                Availability::Missing
+            }
+        };
+        download_buffer.finish(result.err().map(|e| e.to_string()));
+        on_verified(availability);
+    });
+
+    buffer
+}
+
+/// Downloads a track into `buffer`, returning the `ContentHasher` hash of the received bytes.
+fn download_track(stream_addr: SocketAddr, hash: u64, buffer: &StreamBuffer) -> io::Result<u64> {
+    let mut stream = TcpStream::connect(stream_addr)?;
+    stream.write_all(&hash.to_be_bytes())?;
+
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_be_bytes(len);
+    buffer.set_total_len(len);
+
+    let mut hasher = ContentHasher::default();
+    let mut received = 0u64;
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    while received < len {
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "leader closed the stream early",
+            ));
+        }
+        buffer.push(&chunk[..read]);
+        hasher.update(&chunk[..read]);
+        received += read as u64;
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Channels and sample rate streamed tracks are decoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl StreamFormat {
+    /// Returns the format of the default output device, which the sink plays at, or CD quality if
+    /// the device cannot tell.
+    pub fn of_default_output() -> StreamFormat {
+        let config = cpal::default_host()
+            .default_output_device()
+            .and_then(|device| device.default_output_config().ok());
+        match config {
+            Some(config) => StreamFormat {
+                channels: config.channels(),
+                sample_rate: config.sample_rate().0,
+            },
+            None => StreamFormat {
+                channels: 2,
+                sample_rate: 44_100,
+            },
+        }
+    }
+}
+
+/// Decoded samples passed from the decoding thread to a `StreamedSource`.
+struct Chunk {
+    /// The seek the samples were decoded after (see `StreamedSource::seeks`).
+    generation: u64,
+    samples: Vec<i16>,
+    /// Whether the track ends with these samples.
+    end: bool,
+}
+
+/// An audio `Source` that decodes a track while it is being streamed from the leader.
+///
+/// The encoded bytes are decoded on a thread of its own, which waits for the download whenever it
+/// catches up with it, and hands the samples over in chunks. The audio thread never waits: while
+/// no samples are ready, e.g. right after a seek or while the download falls behind, the source
+/// plays silence instead, and skips the audio it missed once the samples arrive, so the track keeps
+/// to its timeline. The duration announced by the leader is reported as the total duration, so
+/// seeking works even before the whole file has arrived.
+///
+/// Tracks are decoded to the given format, so it is known before the first bytes have arrived.
+pub struct StreamedSource {
+    format: StreamFormat,
+    duration: Duration,
+    chunks: mpsc::Receiver<Chunk>,
+    /// Seeks for the decoding thread, numbered so the chunks decoded before the latest one are
+    /// dropped.
+    seeks: mpsc::Sender<(u64, Duration)>,
+    generation: u64,
+    chunk: Vec<i16>,
+    offset: usize,
+    /// Samples played as silence since the last chunk, which are skipped in the next ones.
+    missed: usize,
+    ended: bool,
+}
+
+impl StreamedSource {
+    /// Creates a source for a streamed track, and starts decoding it.
+    pub fn new(buffer: Arc<StreamBuffer>, duration: Duration, format: StreamFormat) -> Self {
+        let (chunk_sender, chunks) = mpsc::sync_channel(DECODED_CHUNKS);
+        let (seeks, seek_receiver) = mpsc::channel();
+        thread::spawn(move || decode(buffer, format, duration, chunk_sender, seek_receiver));
+
+        StreamedSource {
+            format,
+            duration,
+            chunks,
+            seeks,
+            generation: 0,
+            chunk: Vec::new(),
+            offset: 0,
+            missed: 0,
+            ended: false,
+        }
+    }
+}
+
+/// Decodes a streamed track into chunks for a `StreamedSource`, until the source is dropped.
+fn decode(
+    buffer: Arc<StreamBuffer>,
+    format: StreamFormat,
+    duration: Duration,
+    chunks: mpsc::SyncSender<Chunk>,
+    seeks: mpsc::Receiver<(u64, Duration)>,
+) {
+    let reader = BufReader::new(StreamReader::new(buffer));
+    let decoder = match Decoder::new(reader) {
+        Ok(decoder) => decoder,
+        Err(e) => {
+            eprintln!("\nFailed to decode streamed track: {}", e);
+            let _ = chunks.send(Chunk {
+                generation: 0,
+                samples: Vec::new(),
+                end: true,
+            });
+            return;
+        }
+    };
+    let mut audio: UniformSourceIterator<_, i16> =
+        UniformSourceIterator::new(decoder, format.channels, format.sample_rate);
+    let chunk_len = CHUNK_FRAMES * format.channels as usize;
+
+    let mut generation = 0;
+    let mut pending_seek = None;
+    loop {
+        // Only the latest seek matters
+        pending_seek = seeks.try_iter().last().or(pending_seek);
+        if let Some((seek_generation, position)) = pending_seek.take() {
+            if let Err(e) = audio.try_seek(position.min(duration)) {
+                eprintln!("\nFailed to seek in streamed track: {}", e);
+            }
+            generation = seek_generation;
+        }
+
+        let samples: Vec<i16> = audio.by_ref().take(chunk_len).collect();
+        let end = samples.len() < chunk_len;
+        let chunk = Chunk {
+            generation,
+            samples,
+            end,
+        };
+        if chunks.send(chunk).is_err() {
+            return;
+        }
+
+        // After the end, only a seek back into the track brings up more samples
+        if end {
+            match seeks.recv() {
+                Ok(seek) => pending_seek = Some(seek),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Iterator for StreamedSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        loop {
+            if let Some(sample) = self.chunk.get(self.offset) {
+                self.offset += 1;
+                return Some(*sample);
+            }
+            if self.ended {
+                return None;
+            }
+
+            match self.chunks.try_recv() {
+                Ok(chunk) if chunk.generation != self.generation => {}
+                Ok(chunk) => {
+                    let skipped = self.missed.min(chunk.samples.len());
+                    self.missed -= skipped;
+                    self.offset = skipped;
+                    self.chunk = chunk.samples;
+                    self.ended = chunk.end;
+                }
+                Err(mpsc::TryRecvError::Empty) => {
+                    self.missed += 1;
+                    return Some(0);
+                }
+                Err(mpsc::TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+impl Source for StreamedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.format.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(self.duration)
+    }
+
+    /// Seeks on the decoding thread, so the seek returns right away. Silence is played until the
+    /// samples at the new position have been decoded.
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.generation += 1;
+        self.chunk.clear();
+        self.offset = 0;
+        self.missed = 0;
+        self.ended = false;
+        // The decoding thread is only gone if decoding failed, and then there is nothing to seek
+        let _ = self.seeks.send((self.generation, pos));
+        Ok(())
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Tags;
+    use crate::track::{self, AudioFormat};
+    use std::sync::mpsc;
+
+    /// Builds a mono 16-bit PCM WAV file holding `samples`.
+    fn wav_bytes(sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+        let data_len = (samples.len() * 2) as u32;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        bytes.extend_from_slice(&1u16.to_le_bytes()); // mono
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&16u16.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_reader_blocks_until_data_arrives() {
+        let buffer = Arc::new(StreamBuffer::default());
+        let writer = Arc::clone(&buffer);
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            writer.set_total_len(6);
+            writer.push(b"abc");
+            thread::sleep(Duration::from_millis(50));
+            writer.push(b"def");
+            writer.finish(None);
+        });
+
+        let mut reader = StreamReader::new(buffer);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(content, b"abcdef");
+    }
+
+    #[test]
+    fn test_reader_seek_from_end_uses_announced_length() {
+        let buffer = Arc::new(StreamBuffer::default());
+        buffer.set_total_len(10);
+        buffer.push(b"0123456789");
+        buffer.finish(None);
+
+        let mut reader = StreamReader::new(buffer);
+        assert_eq!(reader.seek(SeekFrom::End(-3)).unwrap(), 7);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+
+        assert_eq!(rest, "789");
+    }
+
+    #[test]
+    fn test_reader_reports_failed_download() {
+        let buffer = Arc::new(StreamBuffer::default());
+        buffer.push(b"ab");
+        buffer.finish(Some("connection reset".to_string()));
+
+        let mut reader = StreamReader::new(buffer);
+        let mut chunk = [0u8; 8];
+        assert_eq!(reader.read(&mut chunk).unwrap(), 2);
+        assert!(reader.read(&mut chunk).is_err());
+    }
+
+    #[test]
+    fn test_stream_track_over_loopback() {
+        let media_dir =
+            std::env::temp_dir().join(format!("syncstream-stream-{}", std::process::id()));
+        fs::create_dir_all(&media_dir).unwrap();
+        let samples: Vec<i16> = (0..8000).map(|i| (i % 100) as i16 + 1).collect();
+        let path = media_dir.join("tone.wav");
+        fs::write(&path, wav_bytes(8000, &samples)).unwrap();
+        let hash = track::hash_file(&path).unwrap();
+
+        let tracks = vec![Track {
+            name: "tone".to_string(),
+            path,
+            format: AudioFormat::Wav,
+            duration: Duration::from_secs(1),
+            tags: Tags::default(),
+            hash,
+        }];
+        let port = start_stream_server(&tracks).unwrap();
+        let stream_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+
+        let (verified, availability) = mpsc::channel();
+        let buffer = fetch_track(stream_addr, hash, move |availability| {
+            verified.send(availability).unwrap()
+        });
+        let format = StreamFormat {
+            channels: 1,
+            sample_rate: 8000,
+        };
+        let source = StreamedSource::new(buffer, Duration::from_secs(1), format);
+        assert_eq!(source.sample_rate(), 8000);
+        assert_eq!(source.channels(), 1);
+        assert_eq!(availability.recv().unwrap(), Availability::Playable);
+        thread::sleep(Duration::from_millis(200));
+
+        // Silence is played until the first samples are decoded, in place of the samples missed
+        let played: Vec<i16> = source.collect();
+        let silence = played.iter().take_while(|sample| **sample == 0).count();
+        assert_eq!(played.len(), samples.len());
+        assert_eq!(played[silence..], samples[silence..]);
+
+        // A file that changed on the leader since it was scanned is reported as a different recording
+        fs::write(&tracks[0].path, wav_bytes(8000, &samples[..100])).unwrap();
+        let (verified, availability) = mpsc::channel();
+        fetch_track(stream_addr, hash, move |availability| {
+            verified.send(availability).unwrap()
+        });
+        assert_eq!(availability.recv().unwrap(), Availability::Mismatch);
+
+        fs::remove_dir_all(&media_dir).unwrap();
+    }
+
+    #[test]
+    fn test_source_plays_silence_while_download_is_behind() {
+        let samples: Vec<i16> = (0..8000).map(|i| (i % 100) as i16 + 1).collect();
+        let wav = wav_bytes(8000, &samples);
+        let buffer = Arc::new(StreamBuffer::default());
+        let format = StreamFormat {
+            channels: 1,
+            sample_rate: 8000,
+        };
+        let mut source = StreamedSource::new(Arc::clone(&buffer), Duration::from_secs(1), format);
+
+        // Nothing has arrived yet, and the audio thread does not wait for it
+        let waiting: Vec<i16> = source.by_ref().take(800).collect();
+        assert_eq!(waiting, vec![0; 800]);
+
+        buffer.set_total_len(wav.len() as u64);
+        buffer.push(&wav);
+        buffer.finish(None);
+        // The audio thread pulls in real time, so give the decoding thread a moment to catch up
+        thread::sleep(Duration::from_millis(200));
+
+        // The tenth of a second played as silence is skipped, so the track keeps to its timeline
+        let rest: Vec<i16> = source.collect();
+        let silence = 800 + rest.iter().take_while(|sample| **sample == 0).count();
+        assert_eq!(800 + rest.len(), samples.len());
+        assert_eq!(rest[silence - 800..], samples[silence..]);
+    }
+
+    #[test]
+    fn test_stream_rejects_track_outside_library() {
+        let port = start_stream_server(&[]).unwrap();
+        let stream_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+
+        let (verified, availability) = mpsc::channel();
+        let buffer = fetch_track(stream_addr, 3, move |availability| {
+            verified.send(availability).unwrap()
+        });
+        let mut reader = StreamReader::new(buffer);
+        let mut content = Vec::new();
+
+        assert!(reader.read_to_end(&mut content).is_err());
+        assert_eq!(availability.recv().unwrap(), Availability::Missing);
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::path::Path;
+
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+use crate::loudness::{Gain, ReplayGain};
+
+/// Descriptive tags of a track, as far as the file provides them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    pub year: Option<u32>,
+    /// The file's ReplayGain tags; a missing track gain is measured during the library scan.
+    pub replay_gain: ReplayGain,
+}
+
+impl Tags {
+    /// Takes over the tags symphonia knows a standard meaning for. Later tags win, so the
+    /// container's own tags override the ones found in front of it (e.g. ID3v2).
+    fn apply(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            // RIFF INFO values keep their NUL terminator and padding
+            let value = tag.value.to_string();
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            if value.is_empty() {
+                continue;
+            }
+
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => self.title = Some(value.to_string()),
+                Some(StandardTagKey::Artist) => self.artist = Some(value.to_string()),
+                Some(StandardTagKey::AlbumArtist) if self.artist.is_none() => {
+                    self.artist = Some(value.to_string())
+                }
+                Some(StandardTagKey::Album) => self.album = Some(value.to_string()),
+                Some(StandardTagKey::TrackNumber) => self.track_number = parse_track_number(value),
+                Some(StandardTagKey::Date | StandardTagKey::ReleaseDate) => {
+                    self.year = parse_year(value)
+                }
+                Some(StandardTagKey::ReplayGainTrackGain) => {
+                    self.replay_gain.track = Gain::parse(value)
+                }
+                Some(StandardTagKey::ReplayGainAlbumGain) => {
+                    self.replay_gain.album = Gain::parse(value)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Reads the ID3, Vorbis comment, MP4 or RIFF INFO tags of an audio file, including ReplayGain.
+///
+/// Tags are optional: files without tags, or whose tags cannot be read, get empty `Tags`.
+pub fn read_tags(path: &Path) -> Tags {
+    let mut tags = Tags::default();
+    let Ok(file) = File::open(path) else {
+        return tags;
+    };
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let source = MediaSourceStream::new(Box::new(file), Default::default());
+    let Ok(mut probed) = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) else {
+        return tags;
+    };
+
+    if let Some(metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.current() {
+            tags.apply(revision.tags());
+        }
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.apply(revision.tags());
+    }
+
+    tags
+}
+
+/// Parses a track number such as `3` or `3/12`.
+fn parse_track_number(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Parses the year of a date such as `2019` or `2019-05-01`.
+fn parse_year(value: &str) -> Option<u32> {
+    value.get(..4)?.parse().ok()
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_track_number() {
+        assert_eq!(parse_track_number("3"), Some(3));
+        assert_eq!(parse_track_number("03/12"), Some(3));
+        assert_eq!(parse_track_number("A1"), None);
+    }
+
+    #[test]
+    fn test_parse_year() {
+        assert_eq!(parse_year("2019"), Some(2019));
+        assert_eq!(parse_year("2019-05-01"), Some(2019));
+        assert_eq!(parse_year("May 2019"), None);
+        assert_eq!(parse_year("19"), None);
+    }
+}
@@ -1,34 +1,267 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+
+use crate::tags::Tags;
+
+/// Container/codec families SyncStream can play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AudioFormat {
+    Mp3,
+    Flac,
+    Wav,
+    /// Ogg Vorbis.
+    Ogg,
+    /// AAC, either raw or in an MP4/M4A container.
+    Aac,
+}
+
+impl AudioFormat {
+    /// Detects the format from a file's extension, ignoring case.
+    pub fn from_path(path: &Path) -> Option<AudioFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "mp3" => Some(AudioFormat::Mp3),
+            "flac" => Some(AudioFormat::Flac),
+            "wav" => Some(AudioFormat::Wav),
+            "ogg" | "oga" => Some(AudioFormat::Ogg),
+            "m4a" | "mp4" | "aac" => Some(AudioFormat::Aac),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AudioFormat::Mp3 => "MP3",
+            AudioFormat::Flac => "FLAC",
+            AudioFormat::Wav => "WAV",
+            AudioFormat::Ogg => "OGG",
+            AudioFormat::Aac => "AAC",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Incremental 64-bit FNV-1a hash of a file's bytes.
+///
+/// Leader and members compare it to make sure they play the same recording. It only needs to tell
+/// different files apart, not to withstand deliberate collisions, and unlike `DefaultHasher` its
+/// result is the same for every build.
+#[derive(Debug, Clone, Copy)]
+pub struct ContentHasher(u64);
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        ContentHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl ContentHasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hashes the whole content of the file at `path` with `ContentHasher`.
+pub fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = ContentHasher::default();
+    let mut chunk = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&chunk[..read]);
+    }
+}
+
+/// Whether a member can play a track of the playlist, as checked against the track's content hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    /// The member's copy has the announced content.
+    Playable,
+    /// The member received a file whose content differs from the announced one.
+    Mismatch,
+    /// The member could not get the file at all.
+    Missing,
+}
+
+impl fmt::Display for Availability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Availability::Playable => "playable",
+            Availability::Mismatch => "content differs",
+            Availability::Missing => "missing",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Track {
     pub name: String,
+    /// Where the file lives on the leader. Streamed tracks on a member have no local file.
+    pub path: PathBuf,
+    pub format: AudioFormat,
     pub duration: Duration,
+    pub tags: Tags,
+    /// `ContentHasher` hash of the file, which identifies the recording across peers.
+    pub hash: u64,
 }
 
-impl Clone for Track {
-    fn clone(&self) -> Self {
-        Track {
-            name: self.name.clone(),
-            duration: self.duration,
+impl Track {
+    /// The name shown to the user: "Artist – Title" if the file is tagged, the file name otherwise.
+    pub fn display_name(&self) -> String {
+        match (&self.tags.artist, &self.tags.title) {
+            (Some(artist), Some(title)) => format!("{} – {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => self.name.clone(),
         }
     }
 }
 
+/// Tracks are equal if they are the same file with the same content, which is what `Ord` compares
+/// last. The format, duration and tags follow from the file and its content.
 impl PartialEq for Track {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name && self.duration == other.duration
+        self.cmp(other) == std::cmp::Ordering::Equal
     }
 }
 
 impl PartialOrd for Track {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.name.partial_cmp(&other.name)
+        Some(self.cmp(other))
     }
 }
 
 impl Eq for Track {}
 
+/// Tracks are ordered like on their albums: by album, then track number. Untagged tracks come first,
+/// ordered by file name. Different files, or different contents of a file, never compare equal.
 impl Ord for Track {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.name.cmp(&other.name)
+        self.tags
+            .album
+            .cmp(&other.tags.album)
+            .then_with(|| self.tags.track_number.cmp(&other.tags.track_number))
+            .then_with(|| self.name.cmp(&other.name))
+            .then_with(|| self.path.cmp(&other.path))
+            .then_with(|| self.hash.cmp(&other.hash))
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            AudioFormat::from_path(Path::new("media/song.FLAC")),
+            Some(AudioFormat::Flac)
+        );
+        assert_eq!(
+            AudioFormat::from_path(Path::new("song.m4a")),
+            Some(AudioFormat::Aac)
+        );
+        assert_eq!(AudioFormat::from_path(Path::new("cover.jpg")), None);
+        assert_eq!(AudioFormat::from_path(Path::new("README")), None);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        // Reference values of 64-bit FNV-1a
+        assert_eq!(ContentHasher::default().finish(), 0xcbf29ce484222325);
+        let mut hasher = ContentHasher::default();
+        hasher.update(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+
+        // Feeding the bytes in chunks does not change the result
+        let mut whole = ContentHasher::default();
+        whole.update(b"foobar");
+        let mut chunked = ContentHasher::default();
+        chunked.update(b"foo");
+        chunked.update(b"bar");
+        assert_eq!(whole.finish(), chunked.finish());
+        assert_eq!(whole.finish(), 0x85944171f73967e8);
+    }
+
+    fn track(name: &str, tags: Tags) -> Track {
+        Track {
+            name: name.to_string(),
+            path: PathBuf::from(format!("media/{}.flac", name)),
+            format: AudioFormat::Flac,
+            duration: Duration::from_secs(180),
+            tags,
+            hash: 0,
+        }
+    }
+
+    #[test]
+    fn test_display_name() {
+        let untagged = track("01 intro", Tags::default());
+        let title_only = Tags {
+            title: Some("Intro".to_string()),
+            ..Tags::default()
+        };
+        let tagged = Tags {
+            artist: Some("Band".to_string()),
+            ..title_only.clone()
+        };
+
+        assert_eq!(untagged.display_name(), "01 intro");
+        assert_eq!(track("01 intro", title_only).display_name(), "Intro");
+        assert_eq!(track("01 intro", tagged).display_name(), "Band – Intro");
+    }
+
+    #[test]
+    fn test_sorted_by_album_order() {
+        let on_album = |album: &str, number: u32| Tags {
+            album: Some(album.to_string()),
+            track_number: Some(number),
+            ..Tags::default()
+        };
+        let mut tracks = [
+            track("a", on_album("Second", 1)),
+            track("b", on_album("First", 10)),
+            track("c", on_album("First", 2)),
+            track("z", Tags::default()),
+        ];
+        tracks.sort();
+
+        let names: Vec<&str> = tracks.iter().map(|track| track.name.as_str()).collect();
+        assert_eq!(names, vec!["z", "c", "b", "a"]);
+    }
+
+    #[test]
+    fn test_ordering_agrees_with_equality() {
+        let original = track("a", Tags::default());
+        let edited = Track {
+            hash: 1,
+            ..original.clone()
+        };
+        let moved = Track {
+            path: PathBuf::from("media/other/a.flac"),
+            ..original.clone()
+        };
+
+        assert_eq!(original, original.clone());
+        for other in [&edited, &moved] {
+            assert_ne!(&original, other);
+            assert_ne!(original.cmp(other), std::cmp::Ordering::Equal);
+        }
     }
 }
@@ -0,0 +1,311 @@
+use rodio::source::{SeekError, UniformSourceIterator};
+use rodio::{Sample, Source};
+use std::f32::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::track::Track;
+
+/// The decoded audio of a track, as opened by `AudioSource`.
+pub type Audio = Box<dyn Source<Item = i16> + Send>;
+
+/// How many samples a `TrackSource` plays between two updates of the playhead.
+const PLAYHEAD_INTERVAL: u64 = 1024;
+
+/// The position in the current track, published by the source that plays it.
+///
+/// `Sink::get_pos` counts from the moment the sink started a source, so it is off whenever a track
+/// does not start at its beginning, e.g. after joining a session or after a crossfade. The playhead
+/// is written from the audio thread, so it is a lock-free atomic instead of a mutex.
+#[derive(Debug, Default)]
+pub struct Playhead {
+    position_us: AtomicU64,
+}
+
+impl Playhead {
+    pub fn get(&self) -> Duration {
+        Duration::from_micros(self.position_us.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, position: Duration) {
+        self.position_us
+            .store(position.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Returns how long the transition from `outgoing` to `incoming` is crossfaded.
+///
+/// A crossfade longer than half of either track would overlap with the previous or the next
+/// transition, so such tracks follow each other gaplessly instead. Every peer derives the length
+/// from the same playlist and setting, so the crossfades line up.
+pub fn crossfade_length(crossfade: Duration, outgoing: &Track, incoming: &Track) -> Duration {
+    if outgoing.duration > crossfade * 2 && incoming.duration > crossfade * 2 {
+        crossfade
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// The start of the next track, mixed into the end of the current one.
+pub struct Crossfade {
+    /// Position of the current track at which the next track starts fading in.
+    pub starts_at: Duration,
+    pub length: Duration,
+    /// The next track, opened at its start.
+    pub incoming: Audio,
+}
+
+/// Mixing state of a crossfade.
+struct Fade {
+    starts_at: Duration,
+    incoming: UniformSourceIterator<Audio, f32>,
+    channels: u64,
+    /// Length of the crossfade, and how much of it has been played, in samples.
+    length: u64,
+    mixed: u64,
+    started: bool,
+}
+
+impl Fade {
+    /// Mixes the next sample of both tracks with equal-power gains. Returns `None` once the
+    /// crossfade is over.
+    fn mix(&mut self, outgoing: &mut Audio) -> Option<f32> {
+        if self.mixed >= self.length {
+            return None;
+        }
+        let incoming = self.incoming.next()?;
+        // The outgoing track may end early if its duration was overestimated
+        let outgoing = outgoing.next().map_or(0.0, Sample::to_f32);
+
+        let frame = self.mixed - self.mixed % self.channels;
+        let progress = frame as f32 / self.length as f32 * FRAC_PI_2;
+        self.mixed += 1;
+        Some(outgoing * progress.cos() + incoming * progress.sin())
+    }
+}
+
+/// A track as it is queued in the sink.
+///
+/// The source keeps the playhead up to date while it plays, and calls `on_end` once it has run out
+/// of audio, which is the moment the sink moves on to the next queued source. With a crossfade, the
+/// start of the next track is mixed into the end of this one, and the source ends once that start
+/// has been played. The next track is then queued to start right after it (see
+/// `crossfade_length`), so the transition is seamless either way.
+pub struct TrackSource {
+    inner: Audio,
+    playhead: Arc<Playhead>,
+    /// Where the track was started or last seeked to, and the samples played since then.
+    start: Duration,
+    played: u64,
+    fade: Option<Fade>,
+    on_end: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl TrackSource {
+    /// Wraps the audio of a track that has already been moved to `start`.
+    pub fn new(
+        inner: Audio,
+        start: Duration,
+        playhead: Arc<Playhead>,
+        crossfade: Option<Crossfade>,
+        on_end: impl FnOnce() + Send + 'static,
+    ) -> Self {
+        let channels = inner.channels();
+        let sample_rate = inner.sample_rate();
+        let fade = crossfade.map(|crossfade| Fade {
+            starts_at: crossfade.starts_at,
+            incoming: UniformSourceIterator::new(crossfade.incoming, channels, sample_rate),
+            channels: channels as u64,
+            length: (crossfade.length.as_secs_f64() * sample_rate as f64).round() as u64
+                * channels as u64,
+            mixed: 0,
+            started: false,
+        });
+
+        TrackSource {
+            inner,
+            playhead,
+            start,
+            played: 0,
+            fade,
+            on_end: Some(Box::new(on_end)),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        let samples_per_second = self.inner.sample_rate() as f64 * self.inner.channels() as f64;
+        self.start + Duration::from_secs_f64(self.played as f64 / samples_per_second)
+    }
+}
+
+impl Iterator for TrackSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let frame_start = self.played.is_multiple_of(self.inner.channels() as u64);
+        let position = self.elapsed();
+        let sample = match &mut self.fade {
+            Some(fade) => {
+                if !fade.started && frame_start && position >= fade.starts_at {
+                    fade.started = true;
+                }
+                if fade.started {
+                    fade.mix(&mut self.inner)
+                } else {
+                    match self.inner.next() {
+                        Some(sample) => Some(sample.to_f32()),
+                        None => {
+                            // The track ended before the crossfade, so the next one fades in
+                            // over silence
+                            fade.started = true;
+                            fade.mix(&mut self.inner)
+                        }
+                    }
+                }
+            }
+            None => self.inner.next().map(Sample::to_f32),
+        };
+
+        match sample {
+            Some(sample) => {
+                if self.played.is_multiple_of(PLAYHEAD_INTERVAL) {
+                    self.playhead.set(position);
+                }
+                self.played += 1;
+                Some(sample)
+            }
+            None => {
+                if let Some(on_end) = self.on_end.take() {
+                    self.playhead.set(position);
+                    on_end();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Source for TrackSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        match &self.fade {
+            // The outgoing track may run out during the crossfade, which has a constant format
+            Some(fade) if fade.started => None,
+            _ => self.inner.current_frame_len(),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, position: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(position)?;
+        self.start = position;
+        self.played = 0;
+        self.playhead.set(position);
+
+        // Seeking back from within a crossfade plays it again from its start
+        if let Some(fade) = &mut self.fade {
+            if fade.started {
+                fade.started = false;
+                fade.mixed = 0;
+                if fade.incoming.try_seek(Duration::ZERO).is_err() {
+                    self.fade = None;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Unit testing
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::Tags;
+    use crate::track::AudioFormat;
+    use rodio::buffer::SamplesBuffer;
+    use std::sync::mpsc;
+
+    /// One second of a constant stereo signal at 1000 Hz.
+    fn constant(value: i16) -> Audio {
+        Box::new(SamplesBuffer::new(2, 1000, vec![value; 2000]))
+    }
+
+    #[test]
+    fn test_track_source_reports_position_and_end() {
+        let playhead = Arc::new(Playhead::default());
+        let (ended, ends) = mpsc::channel();
+        let source = TrackSource::new(
+            constant(i16::MAX),
+            Duration::from_secs(3),
+            Arc::clone(&playhead),
+            None,
+            move || ended.send(()).unwrap(),
+        );
+
+        let samples: Vec<f32> = source.collect();
+
+        // Gapless: every sample of the track is played as is, and the end is reported once
+        assert_eq!(samples.len(), 2000);
+        assert!(samples.iter().all(|sample| (*sample - 1.0).abs() < 0.001));
+        assert_eq!(ends.try_iter().count(), 1);
+        assert_eq!(playhead.get(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_crossfade_mixes_in_next_track() {
+        let playhead = Arc::new(Playhead::default());
+        let crossfade = Crossfade {
+            starts_at: Duration::from_millis(750),
+            length: Duration::from_millis(250),
+            incoming: constant(i16::MIN),
+        };
+        let source = TrackSource::new(
+            constant(i16::MAX),
+            Duration::ZERO,
+            Arc::clone(&playhead),
+            Some(crossfade),
+            || {},
+        );
+
+        let samples: Vec<f32> = source.collect();
+
+        // The outgoing track fades out while the incoming one fades in, and the source ends when
+        // the crossfade is over
+        assert_eq!(samples.len(), 2000);
+        assert!((samples[1499] - 1.0).abs() < 0.001);
+        assert!(samples[1500] > 0.99);
+        let midpoint = samples[1750];
+        assert!(midpoint.abs() < 0.01, "midpoint {}", midpoint);
+        assert!(samples[1999] < -0.99);
+
+        // With a crossfade longer than the tracks can hold, they follow each other gaplessly
+        let track = |secs| Track {
+            name: "Track".to_string(),
+            path: "/nonexistent/track.flac".into(),
+            format: AudioFormat::Flac,
+            duration: Duration::from_secs(secs),
+            tags: Tags::default(),
+            hash: 0,
+        };
+        let crossfade = Duration::from_secs(5);
+        assert_eq!(
+            crossfade_length(crossfade, &track(60), &track(11)),
+            crossfade
+        );
+        assert_eq!(
+            crossfade_length(crossfade, &track(60), &track(10)),
+            Duration::ZERO
+        );
+    }
+}
@@ -1,34 +1,64 @@
+use crate::clock;
+use crate::config;
+use crate::order::{self, PlayOrder, Sequence};
+use crate::player::{self, Playback};
+use crate::protocol::{Action, Command, Edit};
+use crate::scheduler::Change;
 use crate::track::Track;
+use crate::transition;
 use rodio::Sink;
-use std::net::UdpSocket;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::UNIX_EPOCH;
-use std::time::{Duration, SystemTime};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
 
-/// Starts a thread to monitor the current track's position and handle track transitions.
+/// Starts a thread that moves the playback on whenever the current track ends.
 ///
-/// This function spawns a thread that continuously checks the playback position of the current track
-/// using `Sink::get_pos()`. When the playback position exceeds the duration of the current track, the
-/// thread advances to the next track by incrementing `current_track_index`, and signals a reset for
-/// synchronization (setting `should_reset` to `true`). It checks if the `current_track_index` exceeds
-/// the total number of tracks, and if there are no more tracks, the program prints a farewell message and exits.
-pub fn start_track_position_thread(
-    sink: Arc<Mutex<Sink>>,
-    current_track_index: Arc<Mutex<usize>>,
-    should_reset: Arc<Mutex<bool>>,
-    tracks: Vec<Track>,
-) {
-    std::thread::spawn(move || loop {
-        let track_index = *current_track_index.lock().unwrap();
-        if sink.lock().unwrap().get_pos() >= tracks[track_index].duration {
-            *current_track_index.lock().unwrap() += 1;
-            *should_reset.lock().unwrap() = true;
-            if *current_track_index.lock().unwrap() >= tracks.len() {
-                println!("\nNo more tracks!");
-                println!("Thanks for using the SyncStream!");
-                std::process::exit(0);
+/// Every queued track reports when it has run out of audio (see `transition::TrackSource`), which
+/// is the moment the sink starts the next queued track, so track boundaries do not depend on the
+/// duration in the track's metadata. The thread then advances `current_track_index` to the next
+/// track of the play order, queues the track after that one so the next transition is gapless
+/// (or crossfaded) as well, and signals a reset for synchronization (setting `should_reset` to
+/// `true`). At the end of the playlist, the playback stops at the first track of the order, from
+/// where it can be played again.
+///
+/// Tracks that end after the queue was rebuilt were cleared from it, so their ends are ignored.
+/// Playlist edits that leave the queued tracks alone keep the queue, even if the tracks' playlist
+/// indices change. The sink, the track index and the playlist are locked together, in the same
+/// order as everywhere else, so a command or playlist edit never lands in the middle of a
+/// transition.
+pub fn start_track_transition_thread(playback: Playback, track_ends: mpsc::Receiver<u64>) {
+    std::thread::spawn(move || {
+        for generation in track_ends {
+            let sink = playback.sink.lock().unwrap();
+            let mut track_index = playback.current_track_index.lock().unwrap();
+            let tracks = playback.tracks.lock().unwrap();
+            let order = playback.order.lock().unwrap();
+            if generation != *playback.queue_generation.lock().unwrap() {
+                continue;
+            }
+
+            match order.next(*track_index) {
+                Some(next) => {
+                    // The next track is already playing, so only the one after it is queued
+                    if let Some(following) = order.next(next) {
+                        let fade_in = transition::crossfade_length(
+                            playback.crossfade,
+                            &tracks[next],
+                            &tracks[following],
+                        );
+                        player::queue_track(&playback, &sink, &tracks, &order, following, fade_in);
+                    }
+                    *track_index = next;
+                }
+                None => {
+                    let first = order.first();
+                    sink.pause();
+                    rebuild_queue(&playback, &sink, &tracks, &order, first, Duration::ZERO);
+                    *track_index = first;
+                    println!("\nEnd of the playlist! Press 'p' to play it again.");
+                }
             }
+            *playback.should_reset.lock().unwrap() = true;
         }
     });
 }
@@ -42,160 +72,762 @@ pub fn duration_to_minutes_seconds(seconds: u64) -> String {
     format!("{:02}:{:02}", minutes, seconds)
 }
 
-/// Calculates a start time 1 second in the future and returns it in milliseconds since the UNIX epoch.
-///
-/// The function uses a global clock for synchronization, ensuring aligned playback across devices
-/// by obtaining the current time from an NTP server (e.g., Google's NTP service). This provides
-/// accurate and consistent timing between devices.
+/// Parses a timestamp formatted as `MM:SS` (or just a number of seconds) into a duration.
 ///
-/// In case the NTP request fails due to a network error, the function falls back to the system clock.
-/// Using the system clock may introduce synchronization errors if the clocks on the devices
-/// are not perfectly aligned.
-pub fn broadcast_start_time() -> Option<u64> {
-    let current_time_ms = match get_time_ms_ntp() {
-        Ok(time) => time,
-        Err(_) => {
-            println!("Network Error! Using system time instead.");
-            let current_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Failed to get current time");
-            current_time.as_secs() * 1000 + current_time.subsec_millis() as u64
+/// This is the inverse of `duration_to_minutes_seconds`, used for `goto` commands.
+pub fn minutes_seconds_to_duration(timestamp: &str) -> Option<Duration> {
+    let seconds = match timestamp.split_once(':') {
+        Some((minutes, seconds)) => {
+            let minutes = minutes.parse::<u64>().ok()?;
+            let seconds = seconds
+                .parse::<u64>()
+                .ok()
+                .filter(|seconds| *seconds < 60)?;
+            minutes * 60 + seconds
         }
+        None => timestamp.parse::<u64>().ok()?,
     };
 
-    let start_time_ms = current_time_ms + 1000;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Calculates a start time `lead` in the future and returns it in milliseconds since the UNIX epoch.
+///
+/// Start times are always expressed on the leader's clock. Members estimate their offset to that
+/// clock over the SyncStream socket (see `clock::ClockSync`), so no internet access or NTP server
+/// is required for the devices to agree on when an action happens. The leader picks the lead from
+/// the round trips to its members (see `Members::start_lead`).
+pub fn broadcast_start_time(lead: Duration) -> Option<u64> {
+    let current_time_ms = clock::system_time_ms();
+
+    let start_time_ms = current_time_ms + lead.as_millis() as u64;
 
     Some(start_time_ms)
 }
 
-/// Calculates the time offset until a given target time, returning the offset as a `Duration`.
+/// Works out the action that carries out a console command at the target time.
 ///
-/// The function determines the current time in milliseconds since the UNIX epoch using an NTP server
-/// (e.g., Google's NTP service) to ensure accurate synchronization. The offset is calculated by
-/// comparing the current time with the provided `target_time_ms`.
+/// Only the leader resolves commands, based on its own playback state, so every peer receives
+/// the same absolute state instead of a toggle it would apply relative to its own state. The state
+/// includes the actions and edits that are still pending (see `Scheduled`), so commands given in
+/// quick succession build on each other:
+///   - `PlayPause`: Plays from the current position if paused, or pauses at the position the
+///     playback will have reached at `target_time_ms`.
+///   - `Next`: Seeks to the start of the next track of the play order. After the last one, the
+///     playback stops at the start of the playlist, unless the playlist is repeated.
+///   - `Previous`: Seeks to the start of the previous track of the play order, or restarts the
+///     first one.
+///   - `JumpTo`: Seeks to the start of a track of the playlist.
+///   - `Stop`: Stops the session.
+///   - `Restart`: Seeks to the start of the current track.
+///   - `SeekBy`: Seeks relative to the position the playback will have reached at `target_time_ms`.
+///   - `SeekTo`: Seeks to a position of the current track.
+///   - `VolumeUp`, `VolumeDown`, `SetVolume`: Sets the session volume.
+///   - `Shuffle`: Turns shuffling off, or on with a fresh seed.
+///   - `Repeat`: Sets the given repeat mode, or the one after the current mode.
 ///
-/// If the current time is already past the target time, the function returns a `Duration` of zero.
-/// In case of a failure to obtain the current time via NTP, the function falls back to the system clock, 
-/// but this might lead to desynchronization between devices if the system clocks are not aligned.
-fn get_offset(target_time_ms: u64) -> Option<Duration> {
-    let current_time_ms = match get_time_ms_ntp() {
-        Ok(time) => time,
-        Err(_) => {
-            println!("Network Error! Using system time instead.");
-            let current_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Failed to get current time");
-            current_time.as_secs() * 1000 + current_time.subsec_millis() as u64
-        }
+/// Seek targets are clamped to the current track. Jumps to a track that does not exist are
+/// rejected with a message for the console, as is every command but `Stop` while the playlist is
+/// empty.
+pub fn resolve_command(
+    command: Command,
+    target_time_ms: u64,
+    playback: &Playback,
+) -> Result<Action, String> {
+    let Scheduled {
+        tracks,
+        sequence,
+        track_index,
+        paused,
+        position_ms,
+        volume,
+    } = Scheduled::at(target_time_ms, playback);
+    let order = sequence.order();
+    let Some(track) = tracks.get(track_index) else {
+        return match command {
+            Command::Stop => Ok(Action::Stop),
+            _ => Err("The playlist is empty!".to_string()),
+        };
     };
+    let (track_count, duration_ms) = (tracks.len(), track.duration.as_millis() as u64);
 
-    let current_time = Duration::from_millis(current_time_ms);
-    let target_time = Duration::from_millis(target_time_ms);
+    Ok(match command {
+        Command::PlayPause if paused => Action::Play {
+            track_index: track_index as u32,
+            position_ms,
+        },
+        Command::PlayPause => Action::Pause {
+            track_index: track_index as u32,
+            position_ms,
+        },
+        Command::Next => match sequence.skip(track_index) {
+            Some(next) => Action::Seek {
+                track_index: next as u32,
+                position_ms: 0,
+            },
+            None => Action::Pause {
+                track_index: sequence.first() as u32,
+                position_ms: 0,
+            },
+        },
+        Command::Previous => Action::Seek {
+            track_index: sequence.previous(track_index) as u32,
+            position_ms: 0,
+        },
+        Command::JumpTo { track_index } if track_index as usize >= track_count => {
+            return Err(format!(
+                "There is no track {} in the playlist!",
+                track_index + 1
+            ));
+        }
+        Command::JumpTo { track_index } => Action::Seek {
+            track_index,
+            position_ms: 0,
+        },
+        Command::Stop => Action::Stop,
+        Command::Restart => Action::Seek {
+            track_index: track_index as u32,
+            position_ms: 0,
+        },
+        Command::SeekBy { offset_ms } => Action::Seek {
+            track_index: track_index as u32,
+            position_ms: position_ms
+                .saturating_add_signed(offset_ms)
+                .min(duration_ms),
+        },
+        Command::SeekTo { position_ms } => Action::Seek {
+            track_index: track_index as u32,
+            position_ms: position_ms.min(duration_ms),
+        },
+        Command::VolumeUp => Action::Volume {
+            percent: volume.saturating_add(player::VOLUME_STEP).min(100),
+        },
+        Command::VolumeDown => Action::Volume {
+            percent: volume.saturating_sub(player::VOLUME_STEP),
+        },
+        Command::SetVolume { percent } => Action::Volume {
+            percent: percent.min(100),
+        },
+        Command::Shuffle => Action::Order {
+            order: PlayOrder {
+                shuffle_seed: match order.shuffle_seed {
+                    Some(_) => None,
+                    None => Some(order::new_shuffle_seed()),
+                },
+                ..order
+            },
+        },
+        Command::Repeat { repeat } => Action::Order {
+            order: PlayOrder {
+                repeat: repeat.unwrap_or(order.repeat.cycle()),
+                ..order
+            },
+        },
+    })
+}
 
-    if current_time < target_time {
-        Some(target_time - current_time)
+/// Returns whether the playback is paused, and the position (in milliseconds) that will be heard
+/// at `target_time_ms`.
+fn projected_position(target_time_ms: u64, playback: &Playback) -> (bool, u64) {
+    let output_latency_ms = playback.output_latency.lock().unwrap().as_millis() as u64;
+    let sink = playback.sink.lock().unwrap();
+    let paused = sink.is_paused();
+    let lead = if paused {
+        0
     } else {
-        println!("Not enough time to synchronize!");
-        Some(Duration::from_secs(0)) // Time already passed
+        target_time_ms
+            .saturating_sub(output_latency_ms)
+            .saturating_sub(clock::system_time_ms())
+    };
+
+    (paused, playback.playhead.get().as_millis() as u64 + lead)
+}
+
+/// The playback state at the target time of a command the leader resolves: the state of its own
+/// playback, with the actions and edits it has scheduled but not yet carried out applied to it.
+struct Scheduled {
+    tracks: Vec<Track>,
+    sequence: Sequence,
+    track_index: usize,
+    paused: bool,
+    /// The position (in milliseconds) that will be heard at the target time.
+    position_ms: u64,
+    volume: u8,
+}
+
+impl Scheduled {
+    fn at(target_time_ms: u64, playback: &Playback) -> Scheduled {
+        playback.scheduler.with_pending(|pending| {
+            let (paused, position_ms) = projected_position(target_time_ms, playback);
+            let mut state = Scheduled {
+                tracks: playback.tracks.lock().unwrap().clone(),
+                sequence: playback.order.lock().unwrap().clone(),
+                track_index: *playback.current_track_index.lock().unwrap(),
+                paused,
+                position_ms,
+                volume: playback.volume.lock().unwrap().level,
+            };
+            for (time_ms, change) in pending {
+                let since_ms = target_time_ms.saturating_sub(*time_ms);
+                match change {
+                    Change::Action(action) => state.apply(*action, since_ms),
+                    Change::Playlist {
+                        tracks,
+                        sequence,
+                        action,
+                    } => {
+                        let order = state.sequence.order();
+                        state.sequence = Sequence::from_indices(order, sequence, tracks.len());
+                        state.tracks = tracks.clone();
+                        state.apply(*action, since_ms);
+                    }
+                    Change::Clicks => {}
+                }
+            }
+            state
+        })
+    }
+
+    /// Carries out an action that is due `since_ms` before the target time.
+    fn apply(&mut self, action: Action, since_ms: u64) {
+        match action {
+            Action::Play {
+                track_index,
+                position_ms,
+            } => {
+                self.track_index = track_index as usize;
+                self.paused = false;
+                self.position_ms = position_ms + since_ms;
+            }
+            Action::Pause {
+                track_index,
+                position_ms,
+            } => {
+                self.track_index = track_index as usize;
+                self.paused = true;
+                self.position_ms = position_ms;
+            }
+            Action::Seek {
+                track_index,
+                position_ms,
+            } => {
+                self.track_index = track_index as usize;
+                self.position_ms = position_ms + if self.paused { 0 } else { since_ms };
+            }
+            Action::Volume { percent } => self.volume = percent,
+            Action::Order { order } => self.sequence.set_order(order),
+            Action::Stop => {}
+        }
     }
 }
 
-/// Executes a synchronized action at the specified target time based on the provided role.
+/// Works out the playlist a console edit leads to, the sequence it is played in, and the action
+/// that keeps the playback going.
 ///
-/// The function waits until the offset duration (calculated as the difference between the current time
-/// and the target time) has elapsed.
+/// Like commands, edits are resolved by the leader alone and broadcast as the complete new
+/// playlist and sequence, so a peer that missed an earlier edit still ends up with the same ones
+/// (see `order::Sequence` for how a shuffled sequence follows the edit). Like a command, an edit
+/// builds on the pending actions and edits (see `Scheduled`). The
+/// action keeps playing (or keeps paused) the current track at the position reached at
+/// `target_time_ms`. If the current track is removed, the playback moves on to the start of the
+/// track that took its place, or stops at the start of the playlist if it was the last one.
 ///
-/// The specific action performed depends on the given `role` parameter:
-///   - "p": Toggles playback (play/pause) of the audio sink.
-///   - "n": Skips to the next track in the audio sink.
-///   - "s": Stops the application with a goodbye message.
-///   - "r": Restarts the currently playing track from the beginning.
-pub fn synchronized_action(role: &str, target_time_ms: u64, sink_clone: &Arc<Mutex<Sink>>) {
-    let offset = get_offset(target_time_ms).expect("Cannot obtain offset");
-
-    thread::sleep(offset);
-
-    // Execute the action at the target time
-    match role.trim() {
-        "p" => {
-            let sink = sink_clone.lock().unwrap();
-            if sink.is_paused() {
-                sink.play();
-            } else {
-                sink.pause();
-            }
+/// `add` and `add next` pick tracks from `library`, the leader's media library. Edits that refer to
+/// a track that does not exist, or that would leave the playlist empty, are rejected with a
+/// message for the console.
+pub fn resolve_edit(
+    edit: Edit,
+    target_time_ms: u64,
+    playback: &Playback,
+    library: &[Track],
+) -> Result<(Vec<Track>, Sequence, Action), String> {
+    let Scheduled {
+        mut tracks,
+        mut sequence,
+        track_index: current_index,
+        paused,
+        position_ms,
+        ..
+    } = Scheduled::at(target_time_ms, playback);
+
+    let edited = edit_playlist(edit, &mut tracks, &mut sequence, current_index, library)?;
+    let (track_index, position_ms) = match edited {
+        Some(track_index) => (track_index, position_ms),
+        None if current_index < tracks.len() => (current_index, 0),
+        None => {
+            let first = sequence.first() as u32;
+            return Ok((
+                tracks,
+                sequence,
+                Action::Pause {
+                    track_index: first,
+                    position_ms: 0,
+                },
+            ));
         }
-        "n" => {
-            sink_clone.lock().unwrap().skip_one();
+    };
+    let track_index = track_index as u32;
+    let action = if paused {
+        Action::Pause {
+            track_index,
+            position_ms,
         }
-        "s" => {
-            println!("\nThanks for using the SyncStream!");
-            std::process::exit(0);
+    } else {
+        Action::Play {
+            track_index,
+            position_ms,
         }
-        "r" => {
-            sink_clone
-                .lock()
-                .unwrap()
-                .try_seek(Duration::from_secs(0))
-                .expect("Cannot restart the track");
+    };
+
+    Ok((tracks, sequence, action))
+}
+
+/// Applies `edit` to `tracks`, and to the `sequence` they are played in. Returns where the track at
+/// `current_index` ended up, or `None` if it was removed.
+fn edit_playlist(
+    edit: Edit,
+    tracks: &mut Vec<Track>,
+    sequence: &mut Sequence,
+    current_index: usize,
+    library: &[Track],
+) -> Result<Option<usize>, String> {
+    let library_track = |library_index: u32| {
+        library
+            .get(library_index as usize)
+            .cloned()
+            .ok_or_else(|| format!("There is no track {} in the library!", library_index + 1))
+    };
+    let playlist_index = |track_index: u32, track_count: usize| {
+        Some(track_index as usize)
+            .filter(|index| *index < track_count)
+            .ok_or_else(|| format!("There is no track {} in the playlist!", track_index + 1))
+    };
+
+    match edit {
+        Edit::Append { library_index } => {
+            tracks.push(library_track(library_index)?);
+            sequence.insert(tracks.len() - 1, current_index);
+            Ok(Some(current_index))
+        }
+        Edit::InsertNext { library_index } => {
+            let index = (current_index + 1).min(tracks.len());
+            tracks.insert(index, library_track(library_index)?);
+            sequence.insert(index, current_index);
+            Ok(Some(current_index))
+        }
+        Edit::Remove { track_index } => {
+            let index = playlist_index(track_index, tracks.len())?;
+            if tracks.len() == 1 {
+                return Err("Cannot remove the only track of the playlist!".to_string());
+            }
+            tracks.remove(index);
+            sequence.remove(index);
+            Ok(match index.cmp(&current_index) {
+                std::cmp::Ordering::Less => Some(current_index - 1),
+                std::cmp::Ordering::Equal => None,
+                std::cmp::Ordering::Greater => Some(current_index),
+            })
+        }
+        Edit::Move { from, to } => {
+            let from = playlist_index(from, tracks.len())?;
+            let to = (to as usize).min(tracks.len() - 1);
+            let track = tracks.remove(from);
+            tracks.insert(to, track);
+            sequence.move_track(from, to);
+
+            if from == current_index {
+                return Ok(Some(to));
+            }
+            let mut index = current_index;
+            if from < index {
+                index -= 1;
+            }
+            if to <= index {
+                index += 1;
+            }
+            Ok(Some(index))
         }
-        _ => {}
     }
 }
 
-/// Retrieves the current time in milliseconds since the UNIX epoch using an NTP server.
+/// Schedules an action for the specified target time, and returns right away.
 ///
-/// This function establishes a UDP connection to an NTP server (e.g., Google's NTP service) and
-/// fetches the current time. The time is returned with millisecond precision by combining the whole
-/// seconds and fractional seconds obtained from the NTP response.
+/// The scheduler thread moves the local playback to the state described by the action once the
+/// target time, less this peer's output latency, has come (see `scheduler::Scheduler`). Both roles
+/// go through this function, so the leader and the members update the track index and the progress
+/// display in the same way. A pending action is superseded by a newer one of the same kind, and a
+/// stop cancels every pending action.
 ///
-/// The NTP server used is `time.google.com:123`. This can be replaced with any other valid NTP server.
-/// The function sets a 2-second timeout for the UDP socket. If no response is received within this time,
-/// the operation fails with a timeout error.
-pub fn get_time_ms_ntp() -> Result<u64, sntpc::Error> {
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Unable to crate UDP socket");
-    socket
-        .set_read_timeout(Some(Duration::from_secs(2)))
-        .expect("Unable to set UDP socket read timeout");
+/// An action that arrives after its target time has passed is still carried out, but the other
+/// peers have been playing since then, so a playing state is entered that much further into the
+/// track (see `catch_up`). How late the action was is recorded in the session log.
+pub fn schedule_action(action: Action, target_time_ms: u64, playback: &Playback) {
+    if action == Action::Stop {
+        playback.scheduler.cancel_all();
+    }
 
-    let result = sntpc::simple_get_time("time.google.com:123", &socket)?;
+    let scheduled = playback.clone();
+    let job = move |lateness| {
+        scheduled
+            .log
+            .lock()
+            .unwrap()
+            .record_lateness(&format!("{:?}", action), lateness);
+        apply_action(action, lateness, &scheduled);
+    };
+    if !playback
+        .scheduler
+        .schedule(Change::Action(action), target_time_ms, job)
+    {
+        println!("\nIgnoring an outdated command: {:?}", action);
+    }
+}
 
-    let seconds = result.sec() as u64; // Whole seconds
-    let millis = sntpc::fraction_to_milliseconds(result.sec_fraction()); // Fractional part in milliseconds
+/// Writes the lateness statistics of this peer to the session log and shows them, as the session
+/// ends for this peer.
+pub fn end_session(playback: &Playback) {
+    let mut log = playback.log.lock().unwrap();
+    log.write_summary();
+    println!("\nLateness of this session: {}", log.lateness());
+}
 
-    // Combine seconds and milliseconds into total milliseconds
-    let current_time_ms = seconds * 1000 + millis as u64;
+/// Returns the position a late action moves the playback to.
+///
+/// If the action leaves the playback playing, the other peers have been playing for `lateness`
+/// since the action was due, so this peer skips ahead by that much to land where they are. A
+/// paused playback stays at the action's position.
+fn catch_up(action: Action, position: Duration, lateness: Duration, paused: bool) -> Duration {
+    let playing = match action {
+        Action::Play { .. } => true,
+        Action::Seek { .. } => !paused,
+        _ => false,
+    };
+    if !playing || lateness.is_zero() {
+        return position;
+    }
 
-    Ok(current_time_ms)
+    println!(
+        "\nCommand arrived {} ms late, skipping ahead to catch up",
+        lateness.as_millis()
+    );
+    position + lateness
 }
 
-/// Extracts a timestamp from a colon-delimited input string.
+/// Moves the local playback to the state described by `action`, `lateness` after the action was
+/// due.
 ///
-/// This function splits the input string at the first colon (`:`) and parses the
-/// second part (after the colon) as an unsigned 64-bit integer (`u64`). The parsed value
-/// represents the extracted timestamp.
-pub fn extract_timestamp(input: &str) -> Option<u64> {
-    // Split the string to find the timestamp
-    if let Some(number_str) = input.split(':').nth(1) {
-        // Trim whitespace and parse the number
-        number_str.trim().parse::<u64>().ok()
+/// Applying an action that was already applied does not change anything, so duplicated or
+/// repeated commands are harmless.
+fn apply_action(action: Action, lateness: Duration, playback: &Playback) {
+    let (track_index, position_ms) = match action {
+        Action::Play {
+            track_index,
+            position_ms,
+        }
+        | Action::Pause {
+            track_index,
+            position_ms,
+        }
+        | Action::Seek {
+            track_index,
+            position_ms,
+        } => (track_index as usize, position_ms),
+        Action::Stop => {
+            end_session(playback);
+            println!("\nThanks for using the SyncStream!");
+            std::process::exit(0);
+        }
+        Action::Volume { percent } => {
+            player::set_volume(playback, Some(percent), None);
+            return;
+        }
+        Action::Order { order } => {
+            set_order(order, playback);
+            return;
+        }
+    };
+
+    let sink = playback.sink.lock().unwrap();
+    let mut current_track_index = playback.current_track_index.lock().unwrap();
+    let tracks = playback.tracks.lock().unwrap();
+    let order = playback.order.lock().unwrap();
+    if track_index >= tracks.len() {
+        eprintln!("\nCannot move to track {}", track_index + 1);
+        return;
+    }
+
+    let position = catch_up(
+        action,
+        Duration::from_millis(position_ms),
+        lateness,
+        sink.is_paused(),
+    );
+    if track_index == *current_track_index {
+        if let Err(e) = sink.try_seek(position) {
+            eprintln!("\nFailed to seek: {}", e);
+        }
     } else {
-        None
+        // Rebuild the queue from the new track on, which works in both directions
+        rebuild_queue(playback, &sink, &tracks, &order, track_index, position);
+        *current_track_index = track_index;
+        *playback.should_reset.lock().unwrap() = true;
+    }
+
+    match action {
+        Action::Play { .. } => sink.play(),
+        Action::Pause { .. } => sink.pause(),
+        _ => {}
+    }
+}
+
+/// Switches to a new play order. If the next tracks change, the current track is requeued at the
+/// position it has reached.
+fn set_order(order: PlayOrder, playback: &Playback) {
+    let sink = playback.sink.lock().unwrap();
+    let track_index = *playback.current_track_index.lock().unwrap();
+    let tracks = playback.tracks.lock().unwrap();
+    let mut current_order = playback.order.lock().unwrap();
+    if current_order.order() == order {
+        return;
+    }
+
+    let mut sequence = current_order.clone();
+    sequence.set_order(order);
+    if queued_tracks(&tracks, &current_order, track_index)
+        != queued_tracks(&tracks, &sequence, track_index)
+    {
+        let position = playback.playhead.get();
+        rebuild_queue(playback, &sink, &tracks, &sequence, track_index, position);
+    }
+    *current_order = sequence;
+    println!(
+        "\nShuffle: {}, repeat: {}",
+        if order.shuffle_seed.is_some() {
+            "on"
+        } else {
+            "off"
+        },
+        order.repeat
+    );
+}
+
+/// Replaces the queued tracks with the track at `track_index`, starting at `position`, and the one
+/// after it in `order`.
+fn rebuild_queue(
+    playback: &Playback,
+    sink: &Sink,
+    tracks: &[Track],
+    order: &Sequence,
+    track_index: usize,
+    position: Duration,
+) {
+    let paused = sink.is_paused();
+    *playback.queue_generation.lock().unwrap() += 1;
+    sink.clear();
+    playback.playhead.set(position);
+    player::queue_tracks(playback, sink, tracks, order, track_index, position);
+    if !paused {
+        sink.play();
+    }
+}
+
+/// Returns the tracks whose audio is in the sink while the track at `track_index` plays: the track
+/// itself, the next one, and the one after that, whose start is mixed into the next one by a
+/// crossfade.
+fn queued_tracks<'a>(tracks: &'a [Track], order: &Sequence, track_index: usize) -> Vec<&'a Track> {
+    let mut queued = vec![&tracks[track_index]];
+    let mut index = track_index;
+    while queued.len() < 3 {
+        match order.next(index) {
+            Some(next) => {
+                queued.push(&tracks[next]);
+                index = next;
+            }
+            None => break,
+        }
     }
+    queued
 }
 
-/// Extracts a mode value from a colon-delimited input string.
+/// Schedules the replacement of the playlist at the specified target time, which also switches to
+/// the playlist indices in `sequence` as the play sequence and moves the playback to the state
+/// described by the action. Edits build on each other, so they are all carried out, in the order
+/// of their target times.
+pub fn schedule_edit(
+    tracks: Vec<Track>,
+    sequence: Vec<u32>,
+    action: Action,
+    target_time_ms: u64,
+    playback: &Playback,
+) {
+    let change = Change::Playlist {
+        tracks: tracks.clone(),
+        sequence: sequence.clone(),
+        action,
+    };
+    let scheduled = playback.clone();
+    playback
+        .scheduler
+        .schedule(change, target_time_ms, move |lateness| {
+            apply_edit(tracks, &sequence, action, lateness, &scheduled)
+        });
+}
+
+/// Replaces the playlist and its play sequence, and moves the playback to the state described by
+/// the action, `lateness` after the edit was due.
 ///
-/// This function splits the input string at the first colon (`:`) and parsea the
-/// first part (before the colon) as an unsigned 64-bit integer (`u64`). The parsed value
-/// represents the extracted mode.
-pub fn extract_mode(input: &str) -> Option<u64> {
-    // Split the string to find the mode (0/2/3)
-    if let Some(number_str) = input.split(':').nth(0) {
-        // Trim whitespace and parse the number
-        number_str.trim().parse::<u64>().ok()
-    } else {
-        None
+/// The queue is rebuilt from the new playlist, since the sink cannot drop or reorder queued tracks.
+/// If the edit leaves the current track and the ones queued after it alone, the queue is kept, so
+/// the current track plays on without a hitch.
+fn apply_edit(
+    tracks: Vec<Track>,
+    sequence: &[u32],
+    action: Action,
+    lateness: Duration,
+    playback: &Playback,
+) {
+    playback
+        .log
+        .lock()
+        .unwrap()
+        .record_lateness(&format!("Playlist edit, {:?}", action), lateness);
+
+    let (track_index, position_ms) = match action {
+        Action::Play {
+            track_index,
+            position_ms,
+        }
+        | Action::Pause {
+            track_index,
+            position_ms,
+        }
+        | Action::Seek {
+            track_index,
+            position_ms,
+        } => (track_index as usize, position_ms),
+        Action::Stop | Action::Volume { .. } | Action::Order { .. } => {
+            let order = playback.order.lock().unwrap().order();
+            let sequence = Sequence::from_indices(order, sequence, tracks.len());
+            *playback.tracks.lock().unwrap() = tracks;
+            *playback.order.lock().unwrap() = sequence;
+            apply_action(action, lateness, playback);
+            return;
+        }
+    };
+    if track_index >= tracks.len() {
+        eprintln!("\nCannot move to track {}", track_index + 1);
+        return;
+    }
+
+    {
+        let sink = playback.sink.lock().unwrap();
+        let mut current_track_index = playback.current_track_index.lock().unwrap();
+        let mut playlist = playback.tracks.lock().unwrap();
+        let mut order = playback.order.lock().unwrap();
+        let sequence = Sequence::from_indices(order.order(), sequence, tracks.len());
+        if queued_tracks(&playlist, &order, *current_track_index)
+            != queued_tracks(&tracks, &sequence, track_index)
+        {
+            let position = catch_up(
+                action,
+                Duration::from_millis(position_ms),
+                lateness,
+                sink.is_paused(),
+            );
+            rebuild_queue(playback, &sink, &tracks, &sequence, track_index, position);
+            *playback.should_reset.lock().unwrap() = true;
+        }
+        *current_track_index = track_index;
+        *order = sequence;
+
+        match action {
+            Action::Play { .. } => sink.play(),
+            Action::Pause { .. } => sink.pause(),
+            _ => {}
+        }
+        *playlist = tracks;
+    }
+
+    player::print_playlist(&playback.tracks.lock().unwrap());
+}
+
+/// Applies a `trim +`, `trim -` or `trim N` console command, which sets the volume trim of this
+/// peer only. Returns `false` if the input is not a trim command.
+pub fn handle_trim_input(input: &str, playback: &Playback) -> bool {
+    let Some(argument) = input.trim().strip_prefix("trim ") else {
+        return false;
+    };
+
+    let trim = playback.volume.lock().unwrap().trim;
+    let trim = match argument.trim() {
+        "+" => trim.saturating_add(player::VOLUME_STEP).min(100),
+        "-" => trim.saturating_sub(player::VOLUME_STEP),
+        percent => match percent.parse::<u8>() {
+            Ok(percent) if percent <= 100 => percent,
+            _ => {
+                println!("Invalid trim! Use 'trim +', 'trim -', or 'trim N' (0-100).");
+                return true;
+            }
+        },
+    };
+    player::set_volume(playback, None, Some(trim));
+
+    true
+}
+
+/// Longest output latency a `latency N` command or a calibration accepts.
+pub const MAX_OUTPUT_LATENCY: Duration = Duration::from_secs(2);
+
+/// Applies a `latency` or `latency N` console command, which shows or sets the output latency of
+/// this peer in milliseconds. A new latency is set with `set_output_latency` and passed to
+/// `on_change`. Returns `false` if the input is not a latency command.
+pub fn handle_latency_input(
+    input: &str,
+    playback: &Playback,
+    on_change: impl FnOnce(Duration),
+) -> bool {
+    let input = input.trim();
+    if input == "latency" {
+        println!(
+            "\nOutput latency: {} ms",
+            playback.output_latency.lock().unwrap().as_millis()
+        );
+        return true;
+    }
+    let Some(argument) = input.strip_prefix("latency ") else {
+        return false;
+    };
+
+    let latency = match argument.trim().parse::<u64>().map(Duration::from_millis) {
+        Ok(latency) if latency <= MAX_OUTPUT_LATENCY => latency,
+        _ => {
+            println!(
+                "Invalid latency! Use 'latency N' with N in milliseconds (0-{}).",
+                MAX_OUTPUT_LATENCY.as_millis()
+            );
+            return true;
+        }
+    };
+    set_output_latency(latency, playback);
+    on_change(latency);
+
+    true
+}
+
+/// Compensates a new output latency on this peer and saves it to the configuration file, so it is
+/// kept for the next session.
+pub fn set_output_latency(latency: Duration, playback: &Playback) {
+    *playback.output_latency.lock().unwrap() = latency;
+
+    let path = Path::new(config::CONFIG_FILE);
+    let value = latency.as_millis().to_string();
+    match config::save_setting(path, "output_latency_ms", &value) {
+        Ok(()) => println!(
+            "\nOutput latency: {} ms (saved to {})",
+            value,
+            path.display()
+        ),
+        Err(e) => eprintln!("\nOutput latency: {} ms (failed to save: {})", value, e),
     }
 }
 
@@ -203,21 +835,57 @@ pub fn extract_mode(input: &str) -> Option<u64> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ClockSync;
+    use crate::loudness::GainMode;
+    use crate::order::Repeat;
+    use crate::scheduler::Scheduler;
+    use crate::session_log::SessionLog;
+    use crate::tags::Tags;
+    use crate::track::AudioFormat;
+    use crate::transition::Playhead;
+    use std::sync::{Arc, Mutex};
+
+    fn idle_playback(track_count: usize) -> Playback {
+        let (sink, _queue) = Sink::new_idle();
+        let tracks = (0..track_count)
+            .map(|i| Track {
+                name: format!("Track {}", i + 1),
+                path: format!("/nonexistent/track{}.flac", i + 1).into(),
+                format: AudioFormat::Flac,
+                duration: Duration::from_secs(180),
+                tags: Tags::default(),
+                hash: i as u64,
+            })
+            .collect();
+        let clock = Arc::new(Mutex::new(ClockSync::default()));
+        let output_latency = Arc::new(Mutex::new(Duration::ZERO));
+
+        Playback {
+            sink: Arc::new(Mutex::new(sink)),
+            tracks: Arc::new(Mutex::new(tracks)),
+            current_track_index: Arc::new(Mutex::new(0)),
+            should_reset: Arc::new(Mutex::new(false)),
+            clock: Arc::clone(&clock),
+            audio: player::AudioSource::Local,
+            volume: Arc::new(Mutex::new(player::Volume::default())),
+            order: Arc::new(Mutex::new(Sequence::new(PlayOrder::default(), track_count))),
+            playhead: Arc::new(Playhead::default()),
+            crossfade: Duration::ZERO,
+            output_latency: Arc::clone(&output_latency),
+            gain_mode: GainMode::Off,
+            track_ends: mpsc::channel().0,
+            queue_generation: Arc::new(Mutex::new(0)),
+            log: Arc::new(Mutex::new(SessionLog::default())),
+            scheduler: Scheduler::start(clock, output_latency),
+        }
+    }
 
     #[test]
     fn test_broadcast_start_time() {
-        let start_time = broadcast_start_time().expect("Expected valid start time");
-
-        let current_time_ms = match get_time_ms_ntp() {
-            Ok(time) => time,
-            Err(_) => {
-                println!("Network Error! Using system time instead.");
-                let current_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Failed to get current time");
-                current_time.as_secs() * 1000 + current_time.subsec_millis() as u64
-            }
-        };
+        let start_time =
+            broadcast_start_time(Duration::from_secs(1)).expect("Expected valid start time");
+
+        let current_time_ms = clock::system_time_ms();
 
         // Ensure the start time is at least 500ms in the future
         assert!(
@@ -227,74 +895,418 @@ mod tests {
     }
 
     #[test]
-    fn test_get_offset_future_time() {
-        let current_time_ms = match get_time_ms_ntp() {
-            Ok(time) => time,
-            Err(_) => {
-                println!("Network Error! Using system time instead.");
-                let current_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Failed to get current time");
-                current_time.as_secs() * 1000 + current_time.subsec_millis() as u64
+    fn test_resolve_play_pause_uses_leader_state() {
+        let playback = idle_playback(2);
+        let start_time = clock::system_time_ms() + 1000;
+
+        // A playing leader pauses everybody at the position reached at the start time
+        match resolve_command(Command::PlayPause, start_time, &playback).unwrap() {
+            Action::Pause {
+                track_index: 0,
+                position_ms,
+            } => assert!((900..=1000).contains(&position_ms)),
+            other => panic!("expected a pause, got {:?}", other),
+        }
+
+        playback.sink.lock().unwrap().pause();
+        assert_eq!(
+            resolve_command(Command::PlayPause, start_time, &playback).unwrap(),
+            Action::Play {
+                track_index: 0,
+                position_ms: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_output_latency_shortens_projection() {
+        let playback = idle_playback(2);
+        *playback.output_latency.lock().unwrap() = Duration::from_millis(300);
+        let start_time = clock::system_time_ms() + 1000;
+
+        // The leader acts 300 ms before the start time, so it only plays 700 ms until then
+        match resolve_command(Command::PlayPause, start_time, &playback).unwrap() {
+            Action::Pause {
+                track_index: 0,
+                position_ms,
+            } => assert!((600..=700).contains(&position_ms)),
+            other => panic!("expected a pause, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_resolve_seeks_are_clamped_to_track() {
+        let playback = idle_playback(1);
+        playback.sink.lock().unwrap().pause();
+
+        assert_eq!(
+            resolve_command(Command::SeekBy { offset_ms: -10_000 }, 0, &playback).unwrap(),
+            Action::Seek {
+                track_index: 0,
+                position_ms: 0
+            }
+        );
+        assert_eq!(
+            resolve_command(Command::SeekBy { offset_ms: 10_000 }, 0, &playback).unwrap(),
+            Action::Seek {
+                track_index: 0,
+                position_ms: 10_000
+            }
+        );
+        assert_eq!(
+            resolve_command(
+                Command::SeekTo {
+                    position_ms: 999_000
+                },
+                0,
+                &playback
+            )
+            .unwrap(),
+            Action::Seek {
+                track_index: 0,
+                position_ms: 180_000
+            }
+        );
+    }
+
+    #[test]
+    fn test_minutes_seconds_to_duration() {
+        assert_eq!(
+            minutes_seconds_to_duration("2:35"),
+            Some(Duration::from_secs(155))
+        );
+        assert_eq!(
+            minutes_seconds_to_duration("95"),
+            Some(Duration::from_secs(95))
+        );
+        assert_eq!(minutes_seconds_to_duration("1:75"), None);
+        assert_eq!(minutes_seconds_to_duration("soon"), None);
+    }
+
+    #[test]
+    fn test_resolve_next_after_last_track() {
+        let playback = idle_playback(2);
+
+        assert_eq!(
+            resolve_command(Command::Next, 0, &playback).unwrap(),
+            Action::Seek {
+                track_index: 1,
+                position_ms: 0
+            }
+        );
+
+        // After the last track, the playback stops at the start unless the playlist is repeated
+        *playback.current_track_index.lock().unwrap() = 1;
+        assert_eq!(
+            resolve_command(Command::Next, 0, &playback).unwrap(),
+            Action::Pause {
+                track_index: 0,
+                position_ms: 0
+            }
+        );
+        playback.order.lock().unwrap().set_order(PlayOrder {
+            shuffle_seed: None,
+            repeat: Repeat::All,
+        });
+        assert_eq!(
+            resolve_command(Command::Next, 0, &playback).unwrap(),
+            Action::Seek {
+                track_index: 0,
+                position_ms: 0
             }
+        );
+    }
+
+    #[test]
+    fn test_repeated_actions_are_idempotent() {
+        let playback = idle_playback(3);
+        let next = Action::Seek {
+            track_index: 1,
+            position_ms: 0,
+        };
+        let pause = Action::Pause {
+            track_index: 1,
+            position_ms: 0,
         };
 
-        let target_time_ms = current_time_ms + 1000; // 1 second into the future
-        let offset = get_offset(target_time_ms).expect("Expected valid offset");
+        // A retransmitted or repeated command must not skip twice or toggle back
+        for _ in 0..2 {
+            apply_action(next, Duration::ZERO, &playback);
+            apply_action(pause, Duration::ZERO, &playback);
+        }
+
+        assert_eq!(*playback.current_track_index.lock().unwrap(), 1);
+        assert!(playback.sink.lock().unwrap().is_paused());
+        assert!(*playback.should_reset.lock().unwrap());
+    }
+
+    #[test]
+    fn test_resolve_previous_and_jump() {
+        let playback = idle_playback(3);
 
-        // Offset should be close to 1 second
-        assert!(offset >= Duration::from_millis(900) && offset <= Duration::from_millis(1100));
+        // On the first track, going back restarts it
+        assert_eq!(
+            resolve_command(Command::Previous, 0, &playback).unwrap(),
+            Action::Seek {
+                track_index: 0,
+                position_ms: 0
+            }
+        );
+        assert_eq!(
+            resolve_command(Command::JumpTo { track_index: 7 }, 0, &playback),
+            Err("There is no track 8 in the playlist!".to_string())
+        );
     }
 
     #[test]
-    fn test_get_offset_past_time() {
-        let current_time_ms = match get_time_ms_ntp() {
-        Ok(time) => time,
-        Err(_) => {
-            println!("Network Error! Using system time instead.");
-            let current_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Failed to get current time");
-            current_time.as_secs() * 1000 + current_time.subsec_millis() as u64
+    fn test_moving_back_rebuilds_queue() {
+        let playback = idle_playback(3);
+        *playback.current_track_index.lock().unwrap() = 2;
+
+        apply_action(
+            Action::Play {
+                track_index: 0,
+                position_ms: 0,
+            },
+            Duration::ZERO,
+            &playback,
+        );
+
+        assert_eq!(*playback.current_track_index.lock().unwrap(), 0);
+        assert!(!playback.sink.lock().unwrap().is_paused());
+        assert!(*playback.should_reset.lock().unwrap());
+    }
+
+    #[test]
+    fn test_late_play_skips_ahead() {
+        let playback = idle_playback(3);
+        let start_time = clock::system_time_ms() - 500;
+
+        // Everybody else started playing half a second ago
+        schedule_action(
+            Action::Play {
+                track_index: 1,
+                position_ms: 2000,
+            },
+            start_time,
+            &playback,
+        );
+        playback.scheduler.wait_until_settled();
+        let position = playback.playhead.get();
+        assert!(
+            (Duration::from_millis(2500)..Duration::from_millis(2600)).contains(&position),
+            "position {:?}",
+            position
+        );
+
+        // A late pause stays where it was meant to
+        schedule_action(
+            Action::Pause {
+                track_index: 2,
+                position_ms: 1000,
+            },
+            start_time,
+            &playback,
+        );
+        playback.scheduler.wait_until_settled();
+        assert_eq!(playback.playhead.get(), Duration::from_secs(1));
+
+        let lateness = playback.log.lock().unwrap().lateness();
+        assert_eq!((lateness.actions, lateness.late), (2, 2));
+        assert!(lateness.max >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_commands_are_rejected_on_an_empty_playlist() {
+        let playback = idle_playback(0);
+
+        for command in [
+            Command::PlayPause,
+            Command::Next,
+            Command::JumpTo { track_index: 0 },
+        ] {
+            assert!(resolve_command(command, 0, &playback).is_err());
         }
-    };
+        assert_eq!(
+            resolve_command(Command::Stop, 0, &playback),
+            Ok(Action::Stop)
+        );
+    }
+
+    #[test]
+    fn test_commands_build_on_pending_actions() {
+        let playback = idle_playback(3);
+        let now = clock::system_time_ms();
 
-        let target_time_ms = current_time_ms - 1000; // 1 second in the past
-        let offset = get_offset(target_time_ms).expect("Expected valid offset");
+        // A pause is pending, so play/pause resumes from where it pauses
+        let pause = Action::Pause {
+            track_index: 1,
+            position_ms: 5_000,
+        };
+        schedule_action(pause, now + 60_000, &playback);
+        assert_eq!(
+            resolve_command(Command::PlayPause, now + 60_500, &playback).unwrap(),
+            Action::Play {
+                track_index: 1,
+                position_ms: 5_000
+            }
+        );
 
-        // Offset should be 0 as the time has already passed
-        assert_eq!(offset, Duration::from_secs(0));
+        // A pending play has been playing for a second by the time the seek is due
+        let play = Action::Play {
+            track_index: 1,
+            position_ms: 5_000,
+        };
+        schedule_action(play, now + 61_000, &playback);
+        assert_eq!(
+            resolve_command(
+                Command::SeekBy { offset_ms: 10_000 },
+                now + 62_000,
+                &playback
+            )
+            .unwrap(),
+            Action::Seek {
+                track_index: 1,
+                position_ms: 16_000
+            }
+        );
+        playback.scheduler.cancel_all();
     }
 
     #[test]
-    fn test_extract_timestamp_valid() {
-        let input = "timestamp:1234567890";
-        let timestamp = extract_timestamp(input).expect("Expected valid timestamp");
+    fn test_member_that_missed_commands_converges() {
+        let playback = idle_playback(3);
+
+        // The member missed the skip to track 2 and the pause; the next command catches it up
+        apply_action(
+            Action::Pause {
+                track_index: 2,
+                position_ms: 0,
+            },
+            Duration::ZERO,
+            &playback,
+        );
 
-        assert_eq!(timestamp, 1234567890, "Timestamp extraction failed");
+        assert_eq!(*playback.current_track_index.lock().unwrap(), 2);
+        assert!(playback.sink.lock().unwrap().is_paused());
     }
 
     #[test]
-    fn test_extract_timestamp_invalid() {
-        let input = "timestamp:";
-        let timestamp = extract_timestamp(input);
+    fn test_edit_playlist_follows_current_track() {
+        let library: Vec<Track> = idle_playback(5).tracks.lock().unwrap().clone();
+        let hashes = |tracks: &[Track]| tracks.iter().map(|t| t.hash).collect::<Vec<_>>();
+        let mut tracks = library[..3].to_vec();
+        let mut sequence = Sequence::new(PlayOrder::default(), 3);
+        let mut edit = |edit: Edit, tracks: &mut Vec<Track>, current_index: usize| {
+            edit_playlist(edit, tracks, &mut sequence, current_index, &library)
+        };
+
+        let append = Edit::Append { library_index: 4 };
+        assert_eq!(edit(append, &mut tracks, 1), Ok(Some(1)));
+        let insert = Edit::InsertNext { library_index: 3 };
+        assert_eq!(edit(insert, &mut tracks, 1), Ok(Some(1)));
+        assert_eq!(hashes(&tracks), [0, 1, 3, 2, 4]);
+
+        // Moving tracks around the current one shifts its index
+        let move_first = Edit::Move { from: 0, to: 3 };
+        assert_eq!(edit(move_first, &mut tracks, 1), Ok(Some(0)));
+        let move_current = Edit::Move { from: 0, to: 9 };
+        assert_eq!(edit(move_current, &mut tracks, 0), Ok(Some(4)));
+        assert_eq!(hashes(&tracks), [3, 2, 0, 4, 1]);
+
+        let remove_current = Edit::Remove { track_index: 4 };
+        assert_eq!(edit(remove_current, &mut tracks, 4), Ok(None));
+        assert!(edit(Edit::Remove { track_index: 4 }, &mut tracks, 0).is_err());
+        assert!(edit(Edit::Append { library_index: 5 }, &mut tracks, 0).is_err());
 
-        assert!(timestamp.is_none(), "Expected None for invalid timestamp");
+        // Without shuffle, the playlist plays from top to bottom whatever the edits
+        assert_eq!(sequence.indices(), [0, 1, 2, 3]);
     }
 
     #[test]
-    fn test_extract_mode_valid() {
-        let input = "1:timestamp";
-        let mode = extract_mode(input).expect("Expected valid mode");
+    fn test_removing_current_track_moves_on_paused() {
+        let playback = idle_playback(3);
+        let library: Vec<Track> = playback.tracks.lock().unwrap().clone();
+        *playback.current_track_index.lock().unwrap() = 1;
+        playback.sink.lock().unwrap().pause();
+
+        let (tracks, sequence, action) =
+            resolve_edit(Edit::Remove { track_index: 1 }, 0, &playback, &library).unwrap();
+        assert_eq!(
+            action,
+            Action::Pause {
+                track_index: 1,
+                position_ms: 0
+            }
+        );
+        schedule_edit(tracks, sequence.indices(), action, 0, &playback);
+        playback.scheduler.wait_until_settled();
 
-        assert_eq!(mode, 1, "Mode extraction failed");
+        let tracks = playback.tracks.lock().unwrap();
+        assert_eq!(tracks.iter().map(|t| t.hash).collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(*playback.current_track_index.lock().unwrap(), 1);
+        assert!(playback.sink.lock().unwrap().is_paused());
+        assert!(*playback.should_reset.lock().unwrap());
     }
 
     #[test]
-    fn test_extract_mode_invalid() {
-        let input = ":timestamp";
-        let mode = extract_mode(input);
+    fn test_shuffle_is_toggled_with_a_shared_seed() {
+        let playback = idle_playback(4);
 
-        assert!(mode.is_none(), "Expected None for invalid mode");
+        let shuffle = resolve_command(Command::Shuffle, 0, &playback).unwrap();
+        let Action::Order { order } = shuffle else {
+            panic!("expected a play order, got {:?}", shuffle);
+        };
+        assert!(order.shuffle_seed.is_some());
+        apply_action(shuffle, Duration::ZERO, &playback);
+        assert_eq!(playback.order.lock().unwrap().order(), order);
+
+        // Turning shuffle off keeps the repeat mode, and repeat keeps the shuffled order
+        let repeat = resolve_command(Command::Repeat { repeat: None }, 0, &playback).unwrap();
+        assert_eq!(
+            repeat,
+            Action::Order {
+                order: PlayOrder {
+                    repeat: Repeat::All,
+                    ..order
+                }
+            }
+        );
+        apply_action(repeat, Duration::ZERO, &playback);
+        assert_eq!(
+            resolve_command(Command::Shuffle, 0, &playback).unwrap(),
+            Action::Order {
+                order: PlayOrder {
+                    shuffle_seed: None,
+                    repeat: Repeat::All
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_volume_is_shared_and_trim_is_local() {
+        let playback = idle_playback(1);
+
+        apply_action(
+            resolve_command(Command::VolumeDown, 0, &playback).unwrap(),
+            Duration::ZERO,
+            &playback,
+        );
+        assert!(handle_trim_input("trim 50\n", &playback));
+        assert!(!handle_trim_input("vol 50", &playback));
+        // Out of range trims are rejected rather than clamped
+        assert!(handle_trim_input("trim 150", &playback));
+
+        let volume = *playback.volume.lock().unwrap();
+        assert_eq!(volume.level, 90);
+        assert_eq!(volume.trim, 50);
+        assert!((playback.sink.lock().unwrap().volume() - 0.45).abs() < 1e-6);
+
+        // Volume commands carry the absolute level, so the trim never leaks into them
+        assert_eq!(
+            resolve_command(Command::VolumeUp, 0, &playback).unwrap(),
+            Action::Volume { percent: 100 }
+        );
     }
 }